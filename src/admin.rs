@@ -0,0 +1,178 @@
+use crate::{crank::MarketsHandle, markets, stats::CrankStats};
+use itertools::Itertools;
+use log::{debug, error, info};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    fs::Permissions,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+
+const HELP: &str = "commands: add-market <market_pk> [event_heap], remove-market <market_pk>, list-markets, pause, resume, stats";
+
+#[derive(Clone)]
+struct AdminContext {
+    rpc_client: Arc<RpcClient>,
+    markets_handle: MarketsHandle,
+    stats: CrankStats,
+}
+
+async fn execute(command: &str, context: &AdminContext) -> anyhow::Result<String> {
+    let mut args = command.split_whitespace();
+    match args.next() {
+        Some("add-market") => {
+            let market_pk = Pubkey::from_str(args.next().unwrap_or_default())?;
+            let event_heap = args.next().map(Pubkey::from_str).transpose()?;
+            let mut markets = context.markets_handle.markets();
+            if markets.iter().any(|x| x.market_pk == market_pk) {
+                anyhow::bail!("market {market_pk} is already cranked");
+            }
+            let market = markets::fetch_markets(&context.rpc_client, &[market_pk])
+                .await?
+                .pop()
+                .ok_or_else(|| anyhow::anyhow!("market {market_pk} not found"))?;
+            if event_heap.is_some_and(|x| x != market.event_heap) {
+                anyhow::bail!("event heap of market {market_pk} is {}", market.event_heap);
+            }
+            let event_heap = market.event_heap;
+            markets.push(market);
+            context.markets_handle.update(&markets)?;
+            Ok(format!("added market {market_pk} event heap {event_heap}"))
+        }
+        Some("remove-market") => {
+            let market_pk = Pubkey::from_str(args.next().unwrap_or_default())?;
+            let mut markets = context.markets_handle.markets();
+            let len = markets.len();
+            markets.retain(|x| x.market_pk != market_pk);
+            if markets.len() == len {
+                anyhow::bail!("market {market_pk} is not cranked");
+            }
+            context.markets_handle.update(&markets)?;
+            Ok(format!("removed market {market_pk}"))
+        }
+        Some("list-markets") => Ok(context
+            .markets_handle
+            .markets()
+            .iter()
+            .map(|x| format!("{} {}", x.market_pk, x.event_heap))
+            .join("\n")),
+        Some("pause") => {
+            context.markets_handle.set_paused(true);
+            Ok("cranking paused".to_string())
+        }
+        Some("resume") => {
+            context.markets_handle.set_paused(false);
+            Ok("cranking resumed".to_string())
+        }
+        Some("stats") => Ok(context.stats.summary().await),
+        _ => Ok(HELP.to_string()),
+    }
+}
+
+async fn handle_connection(stream: UnixStream, context: AdminContext) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match execute(&line, &context).await {
+            Ok(response) => response,
+            Err(e) => format!("error: {e}"),
+        };
+        info!("admin command {:?}: {}", line, response);
+        writer.write_all(response.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+/// Accepts line commands on a unix socket to change the cranked markets at runtime, changes
+/// are lost on the next market reload
+/// Binds the admin socket readable and writable by the owner only, replacing a socket left by
+/// a previous run. Any other file at the path is left untouched and fails the bind.
+fn bind_admin_socket(path: &str) -> anyhow::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{path} exists and is not a socket");
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // anyone able to connect can pause the crank or change its markets
+    std::fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+pub fn start_admin_socket(
+    path: String,
+    rpc_client: Arc<RpcClient>,
+    markets_handle: MarketsHandle,
+    stats: CrankStats,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let listener = match bind_admin_socket(&path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("cannot bind admin socket {} : {}", path, e);
+                return;
+            }
+        };
+        info!("listening for admin commands on {}", path);
+        let context = AdminContext {
+            rpc_client,
+            markets_handle,
+            stats,
+        };
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!("admin socket accept failed {}", e);
+                    continue;
+                }
+            };
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, context).await {
+                    debug!("admin connection failed {}", e);
+                }
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{name}-{}.sock", std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_socket_owner_only() {
+        let path = socket_path("stale-admin");
+        drop(bind_admin_socket(&path).unwrap());
+        let _listener = bind_admin_socket(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn keeps_file_which_is_not_socket() {
+        let path = socket_path("file-admin");
+        std::fs::write(&path, "data").unwrap();
+        let error = bind_admin_socket(&path).err().unwrap();
+        let data = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().ends_with("is not a socket"));
+        assert_eq!(data, "data");
+    }
+}
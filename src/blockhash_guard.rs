@@ -0,0 +1,46 @@
+use crate::{health::HealthStatus, states::TransactionSendRecord, stats::CrankStats};
+use log::{debug, info, warn};
+use solana_sdk::transaction::VersionedTransaction;
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+/// Forwards transactions to the send bridge, dropping them while the blockhash they were
+/// signed with was not refreshed for more than `max_blockhash_age` as they would only fail
+pub fn start_blockhash_guard(
+    tx_rx: UnboundedReceiver<(VersionedTransaction, TransactionSendRecord)>,
+    tx_sx: UnboundedSender<(VersionedTransaction, TransactionSendRecord)>,
+    health: HealthStatus,
+    max_blockhash_age: Duration,
+    stats: CrankStats,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tx_rx = tx_rx;
+        let mut stale = false;
+        while let Some((transaction, record)) = tx_rx.recv().await {
+            let blockhash_age = health.blockhash_age().unwrap_or_default();
+            if blockhash_age > max_blockhash_age {
+                if !stale {
+                    warn!(
+                        "blockhash not updated for {}s, not sending transactions",
+                        blockhash_age.as_secs()
+                    );
+                    stale = true;
+                }
+                debug!("stale blockhash, dropping {}", record.signature);
+                stats.inc_stale_blockhash_drops();
+                continue;
+            }
+            if stale {
+                info!("blockhash updated, sending transactions again");
+                stale = false;
+            }
+            if tx_sx.send((transaction, record)).is_err() {
+                warn!("transaction bridge channel closed");
+                break;
+            }
+        }
+    })
+}
@@ -0,0 +1,313 @@
+use crate::{
+    crank::MarketsHandle,
+    states::{TransactionConfirmRecord, TransactionSendRecord},
+    stats::CrankStats,
+};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use solana_sdk::{pubkey::Pubkey, transaction::VersionedTransaction};
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{UnboundedReceiver, UnboundedSender},
+    },
+    task::JoinHandle,
+    time::Instant,
+};
+
+// while probing, a single transaction is let through per interval
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+// number of probe outcomes needed to decide whether to resume
+const PROBE_TXS: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// pause sending when the success rate over the window drops below this ratio
+    pub min_success_rate: f64,
+    /// sliding window over which confirmation outcomes are considered
+    pub window: Duration,
+    /// the breaker only opens when the window holds at least this many outcomes
+    pub min_txs: usize,
+    /// duration of a pause before probing
+    pub cooldown: Duration,
+}
+
+enum BreakerState {
+    Closed,
+    Open {
+        until: Instant,
+    },
+    // `since` is compared with the send time of the transactions
+    Probing {
+        since: DateTime<Utc>,
+        last_probe: Option<Instant>,
+    },
+}
+
+struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: BreakerState,
+    // confirmation outcomes, true when successful
+    outcomes: VecDeque<(Instant, bool)>,
+}
+
+impl CircuitBreaker {
+    fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: BreakerState::Closed,
+            outcomes: VecDeque::new(),
+        }
+    }
+
+    fn open(&mut self, success_rate: f64) {
+        warn!(
+            "success rate {:.2} below {:.2}, pausing sending for {}s",
+            success_rate,
+            self.config.min_success_rate,
+            self.config.cooldown.as_secs()
+        );
+        self.state = BreakerState::Open {
+            until: Instant::now() + self.config.cooldown,
+        };
+        self.outcomes.clear();
+    }
+
+    fn record(&mut self, success: bool, sent_at: Option<DateTime<Utc>>) {
+        // transactions sent before probing time out long after, they tell nothing of the
+        // recovery
+        if let BreakerState::Probing { since, .. } = self.state {
+            match sent_at {
+                Some(sent_at) if sent_at >= since => {}
+                _ => return,
+            }
+        }
+        let now = Instant::now();
+        self.outcomes.push_back((now, success));
+        while let Some((at, _)) = self.outcomes.front() {
+            if now.duration_since(*at) <= self.config.window {
+                break;
+            }
+            self.outcomes.pop_front();
+        }
+
+        let nb_successful = self.outcomes.iter().filter(|(_, success)| *success).count();
+        let success_rate = nb_successful as f64 / self.outcomes.len() as f64;
+        match self.state {
+            BreakerState::Closed => {
+                if self.outcomes.len() >= self.config.min_txs
+                    && success_rate < self.config.min_success_rate
+                {
+                    self.open(success_rate);
+                }
+            }
+            BreakerState::Probing { .. } => {
+                if self.outcomes.len() < PROBE_TXS {
+                    return;
+                }
+                if success_rate < self.config.min_success_rate {
+                    self.open(success_rate);
+                } else {
+                    info!(
+                        "success rate {:.2} recovered, resuming sending",
+                        success_rate
+                    );
+                    self.state = BreakerState::Closed;
+                }
+            }
+            // outcomes of transactions sent before the pause
+            BreakerState::Open { .. } => self.outcomes.clear(),
+        }
+    }
+
+    fn allow(&mut self) -> bool {
+        if let BreakerState::Open { until } = self.state {
+            if Instant::now() < until {
+                return false;
+            }
+            info!("cooldown over, probing with a transaction every {PROBE_INTERVAL:?}");
+            self.state = BreakerState::Probing {
+                since: Utc::now(),
+                last_probe: None,
+            };
+        }
+        match &mut self.state {
+            BreakerState::Closed => true,
+            BreakerState::Probing { last_probe, .. } => {
+                if last_probe.is_some_and(|x| x.elapsed() < PROBE_INTERVAL) {
+                    return false;
+                }
+                *last_probe = Some(Instant::now());
+                true
+            }
+            BreakerState::Open { .. } => false,
+        }
+    }
+}
+
+/// Forwards transactions from the crank to the send bridge, dropping them while the
+/// confirmed success rate is too low
+pub fn start_circuit_breaker(
+    tx_rx: UnboundedReceiver<(VersionedTransaction, TransactionSendRecord)>,
+    tx_sx: UnboundedSender<(VersionedTransaction, TransactionSendRecord)>,
+    tx_confirm_records: broadcast::Receiver<TransactionConfirmRecord>,
+    config: CircuitBreakerConfig,
+) -> Vec<JoinHandle<()>> {
+    let breaker = Arc::new(Mutex::new(CircuitBreaker::new(config)));
+
+    let outcomes_jh = {
+        let breaker = breaker.clone();
+        tokio::spawn(async move {
+            let mut tx_confirm_records = tx_confirm_records;
+            loop {
+                match tx_confirm_records.recv().await {
+                    Ok(record) => breaker.lock().unwrap().record(
+                        record.confirmed_at.is_some() && record.error.is_none(),
+                        record.sent_at.parse().ok(),
+                    ),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    };
+
+    let forward_jh = tokio::spawn(async move {
+        let mut tx_rx = tx_rx;
+        while let Some((transaction, record)) = tx_rx.recv().await {
+            if !breaker.lock().unwrap().allow() {
+                debug!("circuit breaker open, dropping {}", record.signature);
+                continue;
+            }
+            if tx_sx.send((transaction, record)).is_err() {
+                warn!("transaction bridge channel closed");
+                break;
+            }
+        }
+    });
+
+    vec![outcomes_jh, forward_jh]
+}
+
+/// Stops cranking a market once this many of its transactions in a row failed, as long as
+/// `reprobe_after` is unset or until it elapsed. Only the transactions of a single market
+/// count, the failure of a batch cannot be blamed on one of its markets.
+pub fn start_market_breaker(
+    tx_confirm_records: broadcast::Receiver<TransactionConfirmRecord>,
+    markets_handle: MarketsHandle,
+    max_consecutive_failures: usize,
+    reprobe_after: Option<Duration>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tx_confirm_records = tx_confirm_records;
+        let mut consecutive_failures: HashMap<Pubkey, usize> = HashMap::new();
+        loop {
+            let record = match tx_confirm_records.recv().await {
+                Ok(record) => record,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            // timed out transactions may have failed to land for reasons of their own
+            if record.confirmed_at.is_none() || record.reorged {
+                continue;
+            }
+            let Some(Ok(market)) = record.market.as_deref().map(Pubkey::from_str) else {
+                continue;
+            };
+            if record.error.is_none() {
+                consecutive_failures.remove(&market);
+                continue;
+            }
+            let failures = consecutive_failures.entry(market).or_default();
+            *failures += 1;
+            if *failures < max_consecutive_failures {
+                continue;
+            }
+            match reprobe_after {
+                Some(reprobe_after) => warn!(
+                    "{failures} transactions of market {market} failed in a row, last error: {}, disabling it for {}s",
+                    record.error.unwrap_or_default(),
+                    reprobe_after.as_secs()
+                ),
+                None => warn!(
+                    "{failures} transactions of market {market} failed in a row, last error: {}, disabling it",
+                    record.error.unwrap_or_default()
+                ),
+            }
+            markets_handle
+                .disable_market(market, reprobe_after.map(|x| std::time::Instant::now() + x));
+        }
+    })
+}
+
+// how often the unresolved transactions are checked against the water marks
+const BACKPRESSURE_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Holds back cranking once `high_water_mark` sent transactions are neither confirmed nor
+/// timed out, and resumes it when they drained to `low_water_mark`, so that a lagging
+/// confirmation side does not pile up ever staler transactions
+pub fn start_backpressure(
+    stats: CrankStats,
+    markets_handle: MarketsHandle,
+    high_water_mark: u64,
+    low_water_mark: u64,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(BACKPRESSURE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let unresolved = stats.nb_unresolved_txs();
+            let backpressure = markets_handle.has_backpressure();
+            if !backpressure && unresolved >= high_water_mark {
+                warn!("{unresolved} transactions unconfirmed, holding back cranking until {low_water_mark} are left");
+                markets_handle.set_backpressure(true);
+            } else if backpressure && unresolved <= low_water_mark {
+                info!("{unresolved} transactions unconfirmed, cranking again");
+                markets_handle.set_backpressure(false);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            min_success_rate: 0.5,
+            window: Duration::from_secs(60),
+            min_txs: PROBE_TXS,
+            cooldown: Duration::ZERO,
+        })
+    }
+
+    #[test]
+    fn ignores_outcomes_sent_before_probing() {
+        let mut breaker = breaker();
+        let sent_before = Utc::now();
+        for _ in 0..PROBE_TXS {
+            breaker.record(false, Some(sent_before));
+        }
+        assert!(matches!(breaker.state, BreakerState::Open { .. }));
+        assert!(breaker.allow());
+        assert!(matches!(breaker.state, BreakerState::Probing { .. }));
+
+        // timeouts of transactions sent before the pause
+        for _ in 0..PROBE_TXS {
+            breaker.record(false, Some(sent_before));
+        }
+        assert!(matches!(breaker.state, BreakerState::Probing { .. }));
+
+        for _ in 0..PROBE_TXS {
+            breaker.record(true, Some(Utc::now()));
+        }
+        assert!(matches!(breaker.state, BreakerState::Closed));
+    }
+}
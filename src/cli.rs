@@ -1,5 +1,13 @@
+use crate::logging::LogFormat;
 use clap::Parser;
-use solana_sdk::pubkey::Pubkey;
+use openbook_v2_crank::{
+    helpers::SendMode,
+    markets,
+    openbook_v2_sink::EventTypeFilter,
+    priority_fees::{FeeCurvePoint, DEFAULT_ADAPTIVE_FEE_CURVE},
+    CrankConfig,
+};
+use solana_sdk::{commitment_config::CommitmentLevel, pubkey::Pubkey};
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
@@ -10,29 +18,602 @@ pub struct Args {
     #[arg(short, long, default_value_t = String::from("ws://127.0.0.1:8900"))]
     pub ws_url: String,
 
+    /// fetch the blocks confirming transactions from this RPC instead of --rpc-url, e.g. a
+    /// node suited to getBlock throughput
+    #[arg(long)]
+    pub confirmation_rpc_url: Option<String>,
+
+    /// commitment of the markets, blockhash and slot fetches: processed, confirmed or
+    /// finalized
+    #[arg(long, default_value_t = CommitmentLevel::Finalized)]
+    pub commitment: CommitmentLevel,
+
+    /// commitment of the blocks confirming transactions: confirmed, or finalized for
+    /// results holding only finalized data at the cost of latency
+    #[arg(long, default_value_t = CommitmentLevel::Confirmed)]
+    pub confirmation_commitment: CommitmentLevel,
+
+    /// check that transactions confirmed at confirmed commitment get finalized, the ones
+    /// dropped by a reorg are saved again with reorged set and counted apart
+    #[arg(long, default_value_t = false)]
+    pub recheck_finalized: bool,
+
+    /// number of blocks fetched at the same time when confirming transactions, keeps
+    /// catching up below the rpc rate limits
+    #[arg(long, default_value_t = 8)]
+    pub max_concurrent_block_fetches: usize,
+
+    /// evict the oldest transactions waiting for a confirmation when more are pending, they
+    /// are saved with the evicted error and counted apart from timeouts
+    #[arg(long)]
+    pub max_pending_transactions: Option<usize>,
+
+    /// stop cranking while this many sent transactions are neither confirmed nor timed out,
+    /// so that a lagging confirmation side does not pile up stale transactions
+    #[arg(long)]
+    pub unresolved_high_water_mark: Option<u64>,
+
+    /// crank again once the unresolved transactions drained to this many, half of
+    /// --unresolved-high-water-mark by default
+    #[arg(long, requires = "unresolved_high_water_mark")]
+    pub unresolved_low_water_mark: Option<u64>,
+
+    /// warn when the next block to confirm transactions with is more slots behind the
+    /// current slot, a growing lag means block fetching cannot keep up
+    #[arg(long, default_value_t = 150)]
+    pub confirmation_lag_warning_slots: u64,
+
+    /// wait for confirmations up to twice the 99th percentile of the recent confirmation
+    /// latencies when it is above --confirmation-timeout-secs, at most 4 times longer, so
+    /// that congestion does not show up as timeouts
+    #[arg(long, default_value_t = false)]
+    pub adaptive_timeout: bool,
+
+    /// sign a transaction which timed out after its blockhash expired or was not found again
+    /// with the latest blockhash and resend it once while its events are still in the heap,
+    /// not available with --nonce-account
+    #[arg(long, default_value_t = false, conflicts_with = "nonce_account")]
+    pub retry_expired_blockhash: bool,
+
+    /// do not request block rewards, which shrinks block downloads, the slot leader of
+    /// confirmed transactions is then left empty
+    #[arg(long, default_value_t = false)]
+    pub no_leader_tracking: bool,
+
+    /// only confirm transactions with blocks at least this many slots behind the current
+    /// slot, so that a reorg cannot change the recorded outcome. Transactions stay pending
+    /// meanwhile so keep --confirmation-timeout-secs well above it, disabled when 0
+    #[arg(long, default_value_t = 0)]
+    pub confirmation_depth_slots: u64,
+
     /// tpu fanout
     #[arg(short = 'f', long, default_value_t = 16)]
     pub fanout_size: u64,
 
+    /// immediately resend a failed TPU batch this many times before counting an error
+    #[arg(long, default_value_t = 0)]
+    pub tpu_send_retries: usize,
+
+    /// QUIC connections kept to each leader by a TPU client, more helps a high fanout and
+    /// throughput, fewer suits constrained networks
+    #[arg(long, default_value_t = 4)]
+    pub tpu_connection_pool_size: usize,
+
+    /// rebuild the TPU clients this often, 0 disables the periodic reset
+    #[arg(long, default_value_t = 600)]
+    pub tpu_reset_interval_secs: u64,
+
+    /// transactions sent together over TPU. Larger batches amortize the QUIC overhead but
+    /// a failed send fails, and retries, the whole batch. Not used when sending over RPC
+    #[arg(long, default_value_t = 16)]
+    pub tpu_batch_size: usize,
+
+    /// keypair file, json array, base58 secret or env:<VAR>, repeat it to rotate TPU
+    /// batches among several identities
     #[arg(short = 'k', long)]
-    pub identity: Option<String>,
+    pub identity: Vec<String>,
+
+    /// send over TPU with the crank authority as identity when no --identity is given,
+    /// --identity keeps precedence when it is
+    #[arg(long, default_value_t = false)]
+    pub use_tpu: bool,
+
+    /// send over TPU, RPC or both, defaults to tpu when --identity or --use-tpu is given and
+    /// to rpc otherwise
+    #[arg(long, value_enum)]
+    pub send_mode: Option<SendMode>,
 
+    /// save transaction confirmations to this csv file, compressed when it ends with .gz,
+    /// `-` writes them to stdout which requires --report-interval-secs 0 so that the stats
+    /// reports do not end up in the csv
     #[arg(short = 't', long)]
     pub transaction_save_file: Option<String>,
 
+    /// save block data to this csv file, same formats as --transaction-save-file, only one
+    /// file can be written to stdout
     #[arg(short = 'b', long)]
     pub block_data_save_file: Option<String>,
 
-    #[arg(short = 'a', long, required = true)]
+    /// interval in seconds at which result files are flushed to disk
+    #[arg(long, default_value_t = 5)]
+    pub result_flush_interval_secs: u64,
+
+    /// close result files larger than this many bytes, rename them with a timestamp
+    /// suffix and start new ones, checked on every flush
+    #[arg(long)]
+    pub result_rotate_max_bytes: Option<u64>,
+
+    /// rotate result files opened for this many seconds, checked on every flush
+    #[arg(long)]
+    pub result_rotate_max_age_secs: Option<u64>,
+
+    /// write the transaction, block and heap depth files without the csv header row
+    #[arg(long, default_value_t = false)]
+    pub no_csv_header: bool,
+
+    /// written in the run_tag column of the transaction and block files to tell runs apart,
+    /// defaults to the start time and process id
+    #[arg(long)]
+    pub run_tag: Option<String>,
+
+    /// append a json line with the stats to this file on every report
+    #[arg(long)]
+    pub stats_json_file: Option<String>,
+
+    /// push the counters and rates to this StatsD or DogStatsD agent over UDP on every
+    /// report, as host:port
+    #[arg(long)]
+    pub statsd_addr: Option<String>,
+
+    /// csv file with the count of every error, not only the top 5 of the reports, rewritten
+    /// on every report and at the end of the run
+    #[arg(long)]
+    pub error_histogram_file: Option<String>,
+
+    /// seconds between two stats reports, stdout reports are disabled when 0
+    #[arg(long, default_value_t = 60)]
+    pub report_interval_secs: u64,
+
+    /// seconds after start during which timed out transactions do not count against the
+    /// success rates and reports are marked as warming up
+    #[arg(long, default_value_t = 0)]
+    pub stats_warmup_secs: u64,
+
+    /// serve /healthz, /readyz and the effective configuration at /config on this port
+    #[arg(long)]
+    pub health_port: Option<u16>,
+
+    /// also serve the last this many transaction confirmations as json at
+    /// /recent-transactions on the health port, 0 disables it
+    #[arg(long, default_value_t = 100)]
+    pub recent_transactions_size: usize,
+
+    /// accept line commands on this unix socket: add-market <market_pk> [event_heap],
+    /// remove-market <market_pk>, list-markets and stats, changes are lost on SIGHUP
+    #[arg(long)]
+    pub admin_socket: Option<String>,
+
+    /// /healthz fails and transactions are not sent when the blockhash was not updated for
+    /// this many seconds
+    #[arg(long, default_value_t = 60)]
+    pub health_max_blockhash_age_secs: u64,
+
+    /// send transactions as bundles to this jito block engine instead of over TPU or RPC
+    #[arg(long)]
+    pub jito_block_engine_url: Option<String>,
+
+    /// lamports tipped to jito in every transaction sent as a bundle
+    #[arg(long, requires = "jito_block_engine_url")]
+    pub jito_tip_lamports: Option<u64>,
+
+    /// save event heap depth of every market on each heap update
+    #[arg(long)]
+    pub heap_depth_save_file: Option<String>,
+
+    /// also save transaction confirmations and block data to postgres
+    #[arg(long)]
+    pub postgres_url: Option<String>,
+
+    /// keypair file, json array, base58 secret or env:<VAR>, the pubkey of the crank
+    /// authority with --remote-signer-url
+    #[arg(short = 'a', long, env = "CRANK_AUTHORITY_KEY", hide_env_values = true)]
     pub crank_authority: String,
 
+    /// sign for the crank authority with this http sign service instead of a local keypair,
+    /// requires --fee-payer
+    #[arg(long, requires = "fee_payer")]
+    pub remote_signer_url: Option<String>,
+
+    /// keypair paying the transaction fees, defaults to the crank authority, same formats as
+    /// --crank-authority
+    #[arg(long)]
+    pub fee_payer: Option<String>,
+
+    /// interval in milliseconds at which the send bridge drains pending transactions
     #[arg(long, default_value_t = 10)]
     pub transaction_retry_in_ms: u64,
 
+    /// fetch blocks which failed to download once more in the next confirmation pass
+    #[arg(long, default_value_t = false)]
+    pub retry_failed_blocks: bool,
+
+    /// start fetching blocks for confirmations this many slots before the current slot
+    #[arg(long, default_value_t = 0)]
+    pub confirmation_lookback_slots: u64,
+
+    /// seconds after which an unconfirmed transaction is marked as timed out
+    #[arg(long, default_value_t = 120)]
+    pub confirmation_timeout_secs: u64,
+
+    /// seconds between two passes looking for timed out transactions
+    #[arg(long, default_value_t = 10)]
+    pub cleaner_interval_secs: u64,
+
+    /// seconds between two fetches of the new blocks confirming transactions
+    #[arg(long, default_value_t = 10)]
+    pub confirmation_refresh_secs: u64,
+
+    /// move every confirmation refresh randomly by up to this percentage of
+    /// --confirmation-refresh-secs, so that cranks sharing an RPC do not fetch their blocks
+    /// at the same time
+    #[arg(long, default_value_t = 0)]
+    pub confirmation_refresh_jitter_percent: u64,
+
+    /// look up transactions still unconfirmed after half the confirmation timeout with
+    /// getSignatureStatuses, in case block polling missed them
+    #[arg(long, default_value_t = false)]
+    pub signature_status_fallback: bool,
+
+    /// save block data only for blocks containing at least one crank transaction
+    #[arg(long, default_value_t = false)]
+    pub block_data_only_ours: bool,
+
+    /// do not write block data anywhere, including postgres, only transactions. Block data
+    /// is still gathered for the stats
+    #[arg(long, default_value_t = false, conflicts_with = "block_data_save_file")]
+    pub no_block_data_output: bool,
+
+    /// save unconfirmed transactions to this file on every cleaner pass and confirm them
+    /// again after a restart, use with --confirmation-lookback-slots to cover the downtime
+    #[arg(long)]
+    pub pending_transactions_file: Option<String>,
+
+    /// batch consume events of markets updated within this many milliseconds into one
+    /// transaction, disabled when 0
+    #[arg(long, default_value_t = 0)]
+    pub market_batch_window_ms: u64,
+
+    /// resend transactions still unconfirmed after this many seconds while their blockhash
+    /// is valid, disabled when 0
+    #[arg(long, default_value_t = 0)]
+    pub rebroadcast_after_secs: u64,
+
+    /// send at most this many transactions per second, excess transactions are queued
+    #[arg(long)]
+    pub max_tps: Option<u32>,
+
+    /// transactions waiting for --max-tps, the oldest are dropped above it
+    #[arg(long, default_value_t = 1000)]
+    pub max_tps_queue: usize,
+
+    /// send the transactions of at most this many distinct markets per
+    /// --transaction-retry-in-ms interval, the other markets wait their turn
+    #[arg(long)]
+    pub max_concurrent_markets: Option<usize>,
+
+    /// pause sending when the ratio of successful transactions drops below this value,
+    /// disabled when not set
+    #[arg(long)]
+    pub circuit_breaker_min_success_rate: Option<f64>,
+
+    /// window in seconds over which the circuit breaker success rate is computed
+    #[arg(long, default_value_t = 60)]
+    pub circuit_breaker_window_secs: u64,
+
+    /// minimum number of confirmation outcomes in the window to pause sending
+    #[arg(long, default_value_t = 20)]
+    pub circuit_breaker_min_txs: usize,
+
+    /// seconds to pause sending before probing again
+    #[arg(long, default_value_t = 30)]
+    pub circuit_breaker_cooldown_secs: u64,
+
+    /// stop cranking a market once this many of its confirmed transactions failed in a row,
+    /// transactions batching several markets do not count
+    #[arg(long)]
+    pub market_max_consecutive_failures: Option<usize>,
+
+    /// crank a market stopped by --market-max-consecutive-failures again after this many
+    /// seconds, a new failure stops it again. Stopped until restart when unset
+    #[arg(long, requires = "market_max_consecutive_failures")]
+    pub market_reprobe_secs: Option<u64>,
+
+    /// warn when a market event heap holds at least this many events
+    #[arg(long)]
+    pub backlog_alert_threshold: Option<usize>,
+
+    /// log an error when the seq_num of a market with a backlog did not move for this many
+    /// event heap updates, disabled when 0
+    #[arg(long, default_value_t = 20)]
+    pub stuck_heap_updates: usize,
+
+    /// only consume events of these types, markets whose oldest event is of another type
+    /// are not cranked
+    #[arg(long, value_enum, default_value_t = EventTypeFilter::All)]
+    pub event_types: EventTypeFilter,
+
+    /// consume at most this many events per instruction. Accounts are still gathered for
+    /// every event fitting in the transaction (up to 50), the limit only lowers the count
+    /// passed to consume events, so it has no effect above the number of events gathered
+    #[arg(long)]
+    pub consume_limit: Option<usize>,
+
+    /// simulate the first transaction of every market after start and drop it, logging the
+    /// simulation error, when it would fail. Markets are sent to without simulation once
+    /// one simulation succeeded
+    #[arg(long, default_value_t = false)]
+    pub validate_first_send: bool,
+
+    /// let the rpc simulate the transactions sent over rpc before forwarding them, failing
+    /// transactions are then reported with their simulation error and never land
+    #[arg(long, default_value_t = false)]
+    pub rpc_preflight: bool,
+
+    /// read the event heaps of the markets of every successful transaction again once it
+    /// is confirmed and count the transactions which consumed no event, e.g. because of a
+    /// race with another crank. Costs an RPC request per confirmed transaction
+    #[arg(long, default_value_t = false)]
+    pub verify_consumption: bool,
+
+    /// experimental: hold consume events transactions until a leader which already included
+    /// some of our transactions is scheduled within the next slots, following the leader
+    /// schedule. Wastes fewer transactions but can increase settlement latency by up to 10s.
+    /// Requires --identity or --use-tpu
+    #[arg(long, default_value_t = false)]
+    pub leader_aware_send: bool,
+
+    /// crank on fills only once the fills of a heap are worth this much, in price lots
+    /// times base lots, markets with a backlog are cranked regardless. Overridden by the
+    /// min_fill_value of a market in --config
+    #[arg(long)]
+    pub min_fill_value: Option<u64>,
+
+    /// do not crank a market again within this many milliseconds of its last crank, while
+    /// the previous transaction is likely still confirming, its last update is processed again
+    /// once the wait is over. Fills of markets cranking on any fill, unless crank_on_any_fill
+    /// is set to false in --config, are cranked regardless, disabled when 0
+    #[arg(long, default_value_t = 0)]
+    pub min_crank_interval_ms: u64,
+
+    /// consume events instructions waiting for the transaction sender, when it is full the
+    /// oldest instruction of the same market is dropped since a newer heap state supersedes it,
+    /// the new instruction is dropped when its market has none queued
+    #[arg(long, default_value_t = 1000)]
+    pub instruction_queue_size: usize,
+
+    /// owners whose fill or out events get cranked right away, ignoring the backlog rule,
+    /// --min-fill-value and --min-crank-interval-ms, for example our own market maker
+    #[arg(long, num_args = 1..)]
+    pub priority_owners: Vec<Pubkey>,
+
+    /// consume the events buffered in every market once, wait for confirmations, print the
+    /// report and exit
+    #[arg(long, default_value_t = false)]
+    pub once: bool,
+
+    /// follow only the event heap of this market and print its decoded events and whether
+    /// it would be cranked on every update, without sending any transaction
+    #[arg(long, conflicts_with = "once")]
+    pub debug_market: Option<Pubkey>,
+
+    /// compute unit price in micro lamports, used as a floor with --dynamic-priority-fee
+    /// unless --priority-fee-min is given
+    #[arg(long, default_value_t = 1000)]
+    pub priority_fee: u64,
+
+    /// set the compute unit price from recent prioritization fees of the cranked accounts
+    #[arg(long, default_value_t = false)]
+    pub dynamic_priority_fee: bool,
+
+    /// lowest compute unit price set by --dynamic-priority-fee, defaults to --priority-fee
+    #[arg(long)]
+    pub priority_fee_min: Option<u64>,
+
+    /// highest compute unit price ever paid, caps --dynamic-priority-fee so that a spike of
+    /// the recent prioritization fees cannot drain the fee payer
+    #[arg(long)]
+    pub priority_fee_max: Option<u64>,
+
+    /// raise the compute unit price of markets whose event heap grows quickly, following
+    /// --adaptive-fee-curve within --priority-fee-min and --priority-fee-max
+    #[arg(long, default_value_t = false)]
+    pub adaptive_fee_by_backlog: bool,
+
+    /// comma separated <events per second>:<micro lamports> points mapping the growth rate
+    /// of an event heap over the last 30s to a compute unit price, interpolated between
+    /// the points
+    #[arg(long, value_delimiter = ',', default_value = DEFAULT_ADAPTIVE_FEE_CURVE)]
+    pub adaptive_fee_curve: Vec<FeeCurvePoint>,
+
+    /// stream event heaps from this Yellowstone gRPC endpoint instead of the websocket,
+    /// requires building with the grpc feature
+    #[arg(long)]
+    pub grpc_url: Option<String>,
+
+    /// x-token of the gRPC endpoint
+    #[arg(long, env = "GRPC_X_TOKEN", hide_env_values = true)]
+    pub grpc_x_token: Option<String>,
+
+    /// log as human readable text or as one json object per line
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// number of tokio worker threads, defaults to the number of CPUs
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub worker_threads: Option<usize>,
+
     #[arg(long, default_value_t = openbook_v2::ID)]
     pub program_id: Pubkey,
 
-    /// List of markets to crank
-    #[arg(long, required = true, num_args = 1..)]
-    pub markets: Vec<Pubkey>,
+    /// List of markets to crank, acts as an allowlist when discovering markets. Invalid
+    /// entries are logged with their index and skipped, the crank does not start when none
+    /// is valid
+    #[arg(long, required_unless_present_any = ["discover_markets", "markets_file"], num_args = 1..)]
+    pub markets: Vec<String>,
+
+    /// text file with one market pubkey per line, `#` starts a comment, merged with
+    /// --markets and re-read on SIGHUP
+    #[arg(long)]
+    pub markets_file: Option<String>,
+
+    /// address lookup table holding frequently used event accounts, transactions are sent as v0
+    /// and may reference more event accounts when it is set
+    #[arg(long)]
+    pub lookup_table: Option<Pubkey>,
+
+    /// sign transactions over this durable nonce account instead of a recent blockhash, each
+    /// transaction advances it so transactions are sent one nonce at a time
+    #[arg(long)]
+    pub nonce_account: Option<Pubkey>,
+
+    /// authority of --nonce-account, defaults to the fee payer, same formats as
+    /// --crank-authority
+    #[arg(long, requires = "nonce_account")]
+    pub nonce_authority: Option<String>,
+
+    /// json file with per market configuration, re-read on SIGHUP
+    #[arg(long)]
+    pub config: Option<String>,
+
+    /// discover all markets of the program using getProgramAccounts
+    #[arg(long, default_value_t = false)]
+    pub discover_markets: bool,
+
+    /// markets to skip when discovering markets
+    #[arg(long, num_args = 1..)]
+    pub exclude_markets: Vec<Pubkey>,
+}
+
+impl Args {
+    /// the crank config of the arguments, fails on invalid --markets entries
+    pub fn crank_config(&self) -> anyhow::Result<CrankConfig> {
+        Ok(CrankConfig {
+            rpc_url: self.rpc_url.clone(),
+            confirmation_rpc_url: self.confirmation_rpc_url.clone(),
+            ws_url: self.ws_url.clone(),
+            commitment: self.commitment,
+            confirmation_commitment: self.confirmation_commitment,
+            recheck_finalized: self.recheck_finalized,
+            max_concurrent_block_fetches: self.max_concurrent_block_fetches,
+            max_pending_transactions: self.max_pending_transactions,
+            unresolved_high_water_mark: self.unresolved_high_water_mark,
+            unresolved_low_water_mark: self.unresolved_low_water_mark,
+            confirmation_lag_warning_slots: self.confirmation_lag_warning_slots,
+            confirmation_depth_slots: self.confirmation_depth_slots,
+            no_leader_tracking: self.no_leader_tracking,
+            adaptive_timeout: self.adaptive_timeout,
+            retry_expired_blockhash: self.retry_expired_blockhash,
+            fanout_size: self.fanout_size,
+            tpu_send_retries: self.tpu_send_retries,
+            tpu_connection_pool_size: self.tpu_connection_pool_size,
+            tpu_reset_interval_secs: self.tpu_reset_interval_secs,
+            tpu_batch_size: self.tpu_batch_size,
+            transaction_save_file: self.transaction_save_file.clone(),
+            block_data_save_file: self.block_data_save_file.clone(),
+            result_flush_interval_secs: self.result_flush_interval_secs,
+            result_rotate_max_bytes: self.result_rotate_max_bytes,
+            result_rotate_max_age_secs: self.result_rotate_max_age_secs,
+            no_csv_header: self.no_csv_header,
+            run_tag: self.run_tag.clone(),
+            stats_json_file: self.stats_json_file.clone(),
+            statsd_addr: self.statsd_addr.clone(),
+            error_histogram_file: self.error_histogram_file.clone(),
+            report_interval_secs: self.report_interval_secs,
+            stats_warmup_secs: self.stats_warmup_secs,
+            health_port: self.health_port,
+            recent_transactions_size: self.recent_transactions_size,
+            admin_socket: self.admin_socket.clone(),
+            health_max_blockhash_age_secs: self.health_max_blockhash_age_secs,
+            heap_depth_save_file: self.heap_depth_save_file.clone(),
+            postgres_url: self.postgres_url.clone(),
+            transaction_retry_in_ms: self.transaction_retry_in_ms,
+            max_concurrent_markets: self.max_concurrent_markets,
+            retry_failed_blocks: self.retry_failed_blocks,
+            confirmation_lookback_slots: self.confirmation_lookback_slots,
+            confirmation_timeout_secs: self.confirmation_timeout_secs,
+            cleaner_interval_secs: self.cleaner_interval_secs,
+            confirmation_refresh_secs: self.confirmation_refresh_secs,
+            confirmation_refresh_jitter_percent: self.confirmation_refresh_jitter_percent,
+            signature_status_fallback: self.signature_status_fallback,
+            block_data_only_ours: self.block_data_only_ours,
+            no_block_data_output: self.no_block_data_output,
+            pending_transactions_file: self.pending_transactions_file.clone(),
+            market_batch_window_ms: self.market_batch_window_ms,
+            rebroadcast_after_secs: self.rebroadcast_after_secs,
+            max_tps: self.max_tps,
+            max_tps_queue: self.max_tps_queue,
+            circuit_breaker_min_success_rate: self.circuit_breaker_min_success_rate,
+            circuit_breaker_window_secs: self.circuit_breaker_window_secs,
+            circuit_breaker_min_txs: self.circuit_breaker_min_txs,
+            circuit_breaker_cooldown_secs: self.circuit_breaker_cooldown_secs,
+            market_max_consecutive_failures: self.market_max_consecutive_failures,
+            market_reprobe_secs: self.market_reprobe_secs,
+            backlog_alert_threshold: self.backlog_alert_threshold,
+            stuck_heap_updates: self.stuck_heap_updates,
+            priority_fee: self.priority_fee,
+            dynamic_priority_fee: self.dynamic_priority_fee,
+            priority_fee_min: self.priority_fee_min,
+            priority_fee_max: self.priority_fee_max,
+            adaptive_fee_by_backlog: self.adaptive_fee_by_backlog,
+            adaptive_fee_curve: self.adaptive_fee_curve.clone(),
+            grpc_url: self.grpc_url.clone(),
+            grpc_x_token: self.grpc_x_token.clone(),
+            program_id: self.program_id,
+            markets: markets::parse_markets(&self.markets)?,
+            lookup_table: self.lookup_table,
+            nonce_account: self.nonce_account,
+            markets_config_file: self.config.clone(),
+            markets_file: self.markets_file.clone(),
+            discover_markets: self.discover_markets,
+            exclude_markets: self.exclude_markets.clone(),
+            jito_block_engine_url: self.jito_block_engine_url.clone(),
+            jito_tip_lamports: self.jito_tip_lamports,
+            send_mode: self.send_mode,
+            event_types: self.event_types,
+            consume_limit: self.consume_limit,
+            validate_first_send: self.validate_first_send,
+            rpc_preflight: self.rpc_preflight,
+            verify_consumption: self.verify_consumption,
+            leader_aware_send: self.leader_aware_send,
+            min_fill_value: self.min_fill_value,
+            min_crank_interval_ms: self.min_crank_interval_ms,
+            instruction_queue_size: self.instruction_queue_size,
+            priority_owners: self.priority_owners.clone(),
+            once: self.once,
+            debug_market: self.debug_market,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        let required = [
+            "crank",
+            "--crank-authority",
+            "keypair.json",
+            "--discover-markets",
+        ];
+        Args::try_parse_from(required.iter().chain(args))
+    }
+
+    #[test]
+    fn rejects_zero_worker_threads() {
+        let error = parse(&["--worker-threads", "0"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation);
+        let args = parse(&["--worker-threads", "2"]).unwrap();
+        assert_eq!(args.worker_threads, Some(2));
+    }
 }
@@ -1,32 +1,246 @@
-use crate::states::{BlockData, TransactionConfirmRecord, TransactionSendRecord};
+use crate::{
+    health::HealthStatus,
+    states::{BlockData, TransactionConfirmRecord, TransactionSendRecord},
+    stats::CrankStats,
+};
+use async_trait::async_trait;
 use chrono::Utc;
 use dashmap::DashMap;
-use log::{debug, warn};
-use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcBlockConfig};
+use futures::StreamExt;
+use itertools::Itertools;
+use log::{debug, info, warn};
+use openbook_v2::error::OpenBookError;
+use rand::Rng;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind, Result as ClientResult},
+    nonblocking::rpc_client::RpcClient,
+    rpc_config::RpcBlockConfig,
+    rpc_custom_error::{
+        JSON_RPC_SERVER_ERROR_LONG_TERM_STORAGE_SLOT_SKIPPED, JSON_RPC_SERVER_ERROR_SLOT_SKIPPED,
+    },
+    rpc_request::{RpcError, MAX_GET_SIGNATURE_STATUSES_QUERY_ITEMS},
+    rpc_response::RpcResult,
+};
 use solana_sdk::{
     commitment_config::{CommitmentConfig, CommitmentLevel},
+    instruction::InstructionError,
+    pubkey::Pubkey,
     signature::Signature,
     slot_history::Slot,
+    transaction::TransactionError,
 };
 use solana_transaction_status::{
-    RewardType, TransactionDetails, UiConfirmedBlock, UiTransactionEncoding,
+    option_serializer::OptionSerializer, RewardType, TransactionDetails, TransactionStatus,
+    UiConfirmedBlock, UiTransactionEncoding,
+};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
-use std::{sync::Arc, time::Duration};
 use tokio::{
-    sync::broadcast::Sender, sync::mpsc::UnboundedReceiver, task::JoinHandle, time::Instant,
+    sync::broadcast::{error::RecvError, Receiver, Sender},
+    sync::mpsc::UnboundedReceiver,
+    task::JoinHandle,
+    time::Instant,
 };
 
+// a confirmed transaction is finalized about 32 slots later, statuses stay in the status
+// cache for about 300 slots so a missing status after this delay means the transaction was
+// dropped
+const FINALIZATION_DELAY: Duration = Duration::from_secs(30);
+// interval between two passes of the finalized recheck, short enough that transactions are
+// usually checked while still in the status cache
+const FINALIZED_RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+// confirmation latencies the adaptive timeout is computed from
+const ADAPTIVE_TIMEOUT_SAMPLES: usize = 1000;
+// the adaptive timeout is this many times the 99th percentile of the confirmation latency
+const ADAPTIVE_TIMEOUT_FACTOR: u32 = 2;
+// and stays below this many times the configured timeout so that it cannot keep growing
+const ADAPTIVE_TIMEOUT_MAX_FACTOR: u32 = 4;
+
+/// Rpc calls of the confirmation services, implemented by `RpcClient` and by test doubles
+/// serving synthetic blocks
+#[async_trait]
+pub trait BlockFetcher: Send + Sync {
+    async fn get_blocks_with_commitment(
+        &self,
+        start_slot: Slot,
+        end_slot: Option<Slot>,
+        commitment_config: CommitmentConfig,
+    ) -> ClientResult<Vec<Slot>>;
+
+    async fn get_block_with_config(
+        &self,
+        slot: Slot,
+        config: RpcBlockConfig,
+    ) -> ClientResult<UiConfirmedBlock>;
+
+    /// only used by the signature status fallback and the finalized recheck
+    async fn get_signature_statuses(
+        &self,
+        signatures: &[Signature],
+    ) -> RpcResult<Vec<Option<TransactionStatus>>>;
+
+    /// statuses of transactions older than the status cache, only used by the finalized
+    /// recheck
+    async fn get_signature_statuses_with_history(
+        &self,
+        signatures: &[Signature],
+    ) -> RpcResult<Vec<Option<TransactionStatus>>>;
+}
+
+#[async_trait]
+impl BlockFetcher for RpcClient {
+    async fn get_blocks_with_commitment(
+        &self,
+        start_slot: Slot,
+        end_slot: Option<Slot>,
+        commitment_config: CommitmentConfig,
+    ) -> ClientResult<Vec<Slot>> {
+        RpcClient::get_blocks_with_commitment(self, start_slot, end_slot, commitment_config).await
+    }
+
+    async fn get_block_with_config(
+        &self,
+        slot: Slot,
+        config: RpcBlockConfig,
+    ) -> ClientResult<UiConfirmedBlock> {
+        RpcClient::get_block_with_config(self, slot, config).await
+    }
+
+    async fn get_signature_statuses(
+        &self,
+        signatures: &[Signature],
+    ) -> RpcResult<Vec<Option<TransactionStatus>>> {
+        RpcClient::get_signature_statuses(self, signatures).await
+    }
+
+    async fn get_signature_statuses_with_history(
+        &self,
+        signatures: &[Signature],
+    ) -> RpcResult<Vec<Option<TransactionStatus>>> {
+        RpcClient::get_signature_statuses_with_history(self, signatures).await
+    }
+}
+
+/// Number of the OpenBook error a transaction failed with, preceded by its name when the
+/// program logged it or the error is known. Anchor numbers program errors from 6000, below
+/// are the errors of the system and compute budget instructions
+fn openbook_error(error: &TransactionError, logs: &[String]) -> Option<String> {
+    let TransactionError::InstructionError(_, InstructionError::Custom(code)) = error else {
+        return None;
+    };
+    if *code < anchor_lang::error::ERROR_CODE_OFFSET {
+        return None;
+    }
+    // AnchorError thrown in ...: Error Code: EventHeapEmpty. Error Number: 6016. Error Message: ...
+    let number = format!("Error Number: {code}.");
+    let name = logs
+        .iter()
+        .filter(|x| x.contains(&number))
+        .find_map(|x| x.split("Error Code: ").nth(1)?.split('.').next())
+        .map(str::to_string)
+        .or_else(|| openbook_error_name(*code));
+    Some(match name {
+        Some(name) => format!("{name} ({code})"),
+        None => code.to_string(),
+    })
+}
+
+// every variant of the enum in declaration order so that their codes follow each other from
+// ERROR_CODE_OFFSET, errors missing here are shown as their raw code
+const OPENBOOK_ERRORS: &[OpenBookError] = &[
+    OpenBookError::SomeError,
+    OpenBookError::InvalidInputNameLength,
+    OpenBookError::InvalidInputMarketExpired,
+    OpenBookError::InvalidInputMarketFees,
+    OpenBookError::InvalidInputLots,
+    OpenBookError::InvalidInputLotsSize,
+    OpenBookError::InvalidInputOrdersAmounts,
+    OpenBookError::InvalidInputCancelSize,
+    OpenBookError::InvalidInputPriceLots,
+    OpenBookError::InvalidInputPegLimit,
+    OpenBookError::InvalidInputOrderType,
+    OpenBookError::InvalidInputOrderId,
+    OpenBookError::InvalidInputHeapSlots,
+    OpenBookError::InvalidOracleTypes,
+    OpenBookError::InvalidSecondOracle,
+    OpenBookError::NoCloseMarketAdmin,
+    OpenBookError::InvalidCloseMarketAdmin,
+    OpenBookError::InvalidOpenOrdersAdmin,
+    OpenBookError::InvalidConsumeEventsAdmin,
+    OpenBookError::InvalidMarketVault,
+    OpenBookError::IndexerActiveOO,
+    OpenBookError::OraclePegInvalidOracleState,
+    OpenBookError::UnknownOracleType,
+    OpenBookError::OracleConfidence,
+    OpenBookError::OracleStale,
+    OpenBookError::OrderIdNotFound,
+    OpenBookError::EventHeapContainsElements,
+    OpenBookError::InvalidOrderPostIOC,
+    OpenBookError::InvalidOrderPostMarket,
+    OpenBookError::WouldSelfTrade,
+    OpenBookError::MarketHasExpired,
+    OpenBookError::InvalidPriceLots,
+    OpenBookError::InvalidOraclePrice,
+    OpenBookError::MarketHasNotExpired,
+    OpenBookError::NoOwnerOrDelegate,
+    OpenBookError::NoOwner,
+    OpenBookError::OpenOrdersFull,
+    OpenBookError::BookContainsElements,
+    OpenBookError::OpenOrdersOrderNotFound,
+    OpenBookError::InvalidPostAmount,
+    OpenBookError::DisabledOraclePeg,
+    OpenBookError::NonEmptyMarket,
+    OpenBookError::NonEmptyOpenOrdersPosition,
+];
+
+/// Name of an OpenBook custom error code
+pub fn openbook_error_name(code: u32) -> Option<String> {
+    OPENBOOK_ERRORS
+        .iter()
+        .find(|x| u32::from(**x) == code)
+        .map(|x| x.name())
+}
+
+/// Replaces the hex code of an OpenBook error in "custom program error: 0x1771" by its name,
+/// other errors are returned unchanged
+pub fn describe_error(error: &str) -> String {
+    const CUSTOM_ERROR: &str = "custom program error: 0x";
+    let Some(start) = error.find(CUSTOM_ERROR).map(|x| x + CUSTOM_ERROR.len()) else {
+        return error.to_string();
+    };
+    let end = error[start..]
+        .find(|x: char| !x.is_ascii_hexdigit())
+        .map_or(error.len(), |x| start + x);
+    match u32::from_str_radix(&error[start..end], 16)
+        .ok()
+        .and_then(openbook_error_name)
+    {
+        Some(name) => format!("{}{name}{}", &error[..start - 2], &error[end..]),
+        None => error.to_string(),
+    }
+}
+
 pub async fn process_blocks(
     block: &UiConfirmedBlock,
     tx_confirm_records: Sender<TransactionConfirmRecord>,
     tx_block_data: Sender<BlockData>,
     transaction_map: Arc<DashMap<Signature, (TransactionSendRecord, Instant)>>,
     slot: u64,
+    block_data_only_ours: bool,
 ) {
     let mut mm_transaction_count: u64 = 0;
-    let rewards = block.rewards.as_ref().unwrap();
-    let slot_leader = match rewards
+    let mut transactions_by_market: BTreeMap<String, u64> = BTreeMap::new();
+    let slot_leader = match block
+        .rewards
         .iter()
+        .flatten()
         .find(|r| r.reward_type == Some(RewardType::Fee))
     {
         Some(x) => x.pubkey.clone(),
@@ -55,7 +269,7 @@ pub async fn process_blocks(
                 // add CU in counter
                 let tx_cu = if let Some(meta) = &meta {
                     match meta.compute_units_consumed {
-                        solana_transaction_status::option_serializer::OptionSerializer::Some(x) => {
+                        OptionSerializer::Some(x) => {
                             cu_consumed = cu_consumed.saturating_add(x);
                             x
                         }
@@ -66,8 +280,13 @@ pub async fn process_blocks(
                 };
 
                 if let Some(transaction_record) = transaction_record_op {
-                    let transaction_record = transaction_record.0;
+                    let (transaction_record, sent_instant) = transaction_record;
                     mm_transaction_count += 1;
+                    for market in &transaction_record.markets {
+                        *transactions_by_market
+                            .entry(market.to_string())
+                            .or_default() += 1;
+                    }
                     cu_consumed_by_obv2 += tx_cu;
 
                     match tx_confirm_records.send(TransactionConfirmRecord {
@@ -86,13 +305,25 @@ pub async fn process_blocks(
                         } else {
                             None
                         },
+                        program_error: meta.as_ref().and_then(|meta| {
+                            let logs = match &meta.log_messages {
+                                OptionSerializer::Some(logs) => logs.as_slice(),
+                                _ => &[],
+                            };
+                            openbook_error(meta.err.as_ref()?, logs)
+                        }),
                         block_hash: Some(block.blockhash.clone()),
-                        market: transaction_record.market.map(|x| x.to_string()),
+                        market: transaction_record.markets_string(),
+                        events: transaction_record.events.clone(),
                         user: transaction_record.user.map(|x| x.to_string()),
                         slot_processed: Some(slot),
+                        slot_latency: Some(slot.saturating_sub(transaction_record.sent_slot)),
                         slot_leader: Some(slot_leader.clone()),
                         timed_out: false,
                         priority_fees: transaction_record.priority_fees,
+                        reorged: false,
+                        run_tag: None,
+                        confirmation_latency: Some(sent_instant.elapsed()),
                     }) {
                         Ok(_) => {}
                         Err(e) => {
@@ -106,8 +337,13 @@ pub async fn process_blocks(
         }
 
         // push block data
-        {
-            let filled_percentage = (cu_consumed_by_obv2 * 100) as f32 / cu_consumed as f32;
+        if mm_transaction_count > 0 || !block_data_only_ours {
+            // empty or vote only blocks may not consume any CU
+            let filled_percentage = if cu_consumed == 0 {
+                0.0
+            } else {
+                (cu_consumed_by_obv2 * 100) as f32 / cu_consumed as f32
+            };
             let _ = tx_block_data.send(BlockData {
                 block_hash: block.blockhash.clone(),
                 block_leader: slot_leader,
@@ -120,21 +356,26 @@ pub async fn process_blocks(
                 number_of_mm_transactions: mm_transaction_count,
                 total_transactions: nb_transactions as u64,
                 cu_consumed,
+                cu_consumed_by_openbook: cu_consumed_by_obv2,
                 percentage_filled_by_openbook: filled_percentage,
+                transactions_by_market: serde_json::to_string(&transactions_by_market)
+                    .unwrap_or_default(),
+                run_tag: None,
             });
         }
     }
 }
 
 async fn get_blocks_with_retry(
-    client: Arc<RpcClient>,
+    client: &dyn BlockFetcher,
     start_block: u64,
+    end_block: Option<Slot>,
     commitment_confirmation: CommitmentConfig,
 ) -> Result<Vec<Slot>, ()> {
     const N_TRY_REQUEST_BLOCKS: u64 = 4;
     for _ in 0..N_TRY_REQUEST_BLOCKS {
         let block_slots = client
-            .get_blocks_with_commitment(start_block, None, commitment_confirmation)
+            .get_blocks_with_commitment(start_block, end_block, commitment_confirmation)
             .await;
 
         match block_slots {
@@ -149,18 +390,447 @@ async fn get_blocks_with_retry(
     Err(())
 }
 
+// cranks sharing an RPC would otherwise fetch their blocks in sync
+fn jittered(interval: Duration, jitter_percent: u64) -> Duration {
+    if jitter_percent == 0 {
+        return interval;
+    }
+    let jitter_percent = jitter_percent as f64;
+    let jitter = rand::thread_rng().gen_range(-jitter_percent..=jitter_percent) / 100.0;
+    interval.mul_f64(1.0 + jitter)
+}
+
+// the slot had no block, as opposed to a failure to fetch it
+fn is_slot_skipped(error: &ClientError) -> bool {
+    matches!(
+        error.kind(),
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. })
+            if *code == JSON_RPC_SERVER_ERROR_SLOT_SKIPPED
+                || *code == JSON_RPC_SERVER_ERROR_LONG_TERM_STORAGE_SLOT_SKIPPED
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfirmationConfig {
+    /// fetch blocks which failed to download once more in the next pass
+    pub retry_failed_blocks: bool,
+    /// number of slots before the current slot to start fetching blocks from
+    pub lookback_slots: u64,
+    /// transactions not confirmed after this duration are marked as timed out
+    pub timeout: Duration,
+    /// interval between two passes looking for timed out transactions
+    pub cleaner_interval: Duration,
+    /// interval between two fetches of the new blocks
+    pub refresh_interval: Duration,
+    /// move every refresh randomly by up to this percentage of the refresh interval
+    pub refresh_jitter_percent: u64,
+    /// confirm transactions missed by block polling with getSignatureStatuses
+    pub signature_status_fallback: bool,
+    /// emit block data only for blocks containing at least one of our transactions
+    pub block_data_only_ours: bool,
+    /// commitment of the blocks and signature statuses confirming transactions, confirmed
+    /// or finalized
+    pub commitment: CommitmentLevel,
+    /// check that confirmed transactions get finalized and report the ones dropped by a
+    /// reorg again, flagged as reorged
+    pub recheck_finalized: bool,
+    /// number of blocks fetched at the same time
+    pub max_concurrent_block_fetches: usize,
+    /// evict the oldest pending transactions when more are waiting for a confirmation
+    pub max_pending_transactions: Option<usize>,
+    /// save pending transactions to this file on every cleaner pass and restore them on
+    /// start
+    pub pending_transactions_file: Option<String>,
+    /// timeouts replacing `timeout` for the transactions cranking these markets
+    pub market_timeouts: HashMap<Pubkey, Duration>,
+    /// warn when the next block to confirm with is more slots behind the current slot
+    pub lag_warning_slots: u64,
+    /// wait for confirmations up to twice the 99th percentile of the recent confirmation
+    /// latencies when it is above the timeout
+    pub adaptive_timeout: bool,
+    /// request block rewards to tell the slot leader of confirmed transactions, without
+    /// them the slot leader is left empty
+    pub leader_tracking: bool,
+    /// only confirm with blocks at least this many slots behind the current slot, so that
+    /// a reorg cannot replace them, disabled when 0
+    pub depth_slots: u64,
+}
+
+/// Latencies of the last confirmations, from the send record to the confirming block
+#[derive(Clone, Default)]
+struct RecentLatencies(Arc<Mutex<VecDeque<Duration>>>);
+
+impl RecentLatencies {
+    fn start_recording(&self, mut records: Receiver<TransactionConfirmRecord>) -> JoinHandle<()> {
+        let latencies = self.0.clone();
+        tokio::spawn(async move {
+            loop {
+                let record = match records.recv().await {
+                    Ok(record) => record,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let Some(latency) = record.confirmation_latency else {
+                    continue;
+                };
+                let mut latencies = latencies.lock().unwrap();
+                if latencies.len() == ADAPTIVE_TIMEOUT_SAMPLES {
+                    latencies.pop_front();
+                }
+                latencies.push_back(latency);
+            }
+        })
+    }
+
+    fn percentile(&self, percentile: usize) -> Option<Duration> {
+        let mut sorted = self.0.lock().unwrap().iter().copied().collect_vec();
+        sorted.sort_unstable();
+        sorted
+            .get(sorted.len().checked_sub(1)? * percentile / 100)
+            .copied()
+    }
+
+    /// timeout covering the slow confirmations of the moment, never above
+    /// ADAPTIVE_TIMEOUT_MAX_FACTOR times the configured timeout
+    fn timeout(&self, base_timeout: Duration) -> Duration {
+        self.percentile(99).map_or(Duration::ZERO, |p99| {
+            (p99 * ADAPTIVE_TIMEOUT_FACTOR).min(base_timeout * ADAPTIVE_TIMEOUT_MAX_FACTOR)
+        })
+    }
+}
+
+impl ConfirmationConfig {
+    /// Last slot whose block can confirm transactions, None without a confirmation depth
+    fn deepest_confirmable_slot(&self, current_slot: &AtomicU64) -> Option<Slot> {
+        (self.depth_slots > 0).then(|| {
+            current_slot
+                .load(Ordering::Relaxed)
+                .saturating_sub(self.depth_slots)
+        })
+    }
+
+    /// Timeout of the transaction, the longest override of its markets if any
+    fn timeout_for(&self, sent_record: &TransactionSendRecord) -> Duration {
+        sent_record
+            .markets
+            .iter()
+            .filter_map(|x| self.market_timeouts.get(x))
+            .copied()
+            .max()
+            .unwrap_or(self.timeout)
+    }
+}
+
+/// Error of the transactions evicted from a full pending map, neither confirmed nor timed out
+pub const EVICTED_ERROR: &str = "evicted";
+
+fn timed_out_record(
+    signature: &Signature,
+    sent_record: &TransactionSendRecord,
+) -> TransactionConfirmRecord {
+    TransactionConfirmRecord {
+        signature: signature.to_string(),
+        confirmed_slot: None,
+        confirmed_at: None,
+        sent_at: sent_record.sent_at.to_string(),
+        sent_slot: sent_record.sent_slot,
+        successful: false,
+        error: Some("timeout".to_string()),
+        block_hash: None,
+        market: sent_record.markets_string(),
+        events: sent_record.events.clone(),
+        user: sent_record.user.map(|x| x.to_string()),
+        slot_processed: None,
+        slot_latency: None,
+        slot_leader: None,
+        timed_out: true,
+        priority_fees: sent_record.priority_fees,
+        reorged: false,
+        run_tag: None,
+        confirmation_latency: None,
+        program_error: None,
+    }
+}
+
+// evicts a tenth of the map at once so that a full map is not sorted on every insert
+fn evict_oldest(
+    transaction_map: &DashMap<Signature, (TransactionSendRecord, Instant)>,
+    tx_confirm_records: &Sender<TransactionConfirmRecord>,
+    max_pending_transactions: usize,
+) {
+    let nb_evicted =
+        transaction_map.len() - max_pending_transactions + max_pending_transactions / 10;
+    let oldest = transaction_map
+        .iter()
+        .map(|x| (*x.key(), x.value().1))
+        .sorted_by_key(|x| x.1)
+        .take(nb_evicted)
+        .collect_vec();
+    warn!(
+        "{} transactions pending confirmation, evicting the {} oldest",
+        transaction_map.len(),
+        oldest.len()
+    );
+    for (signature, _) in oldest {
+        if let Some((signature, (sent_record, _))) = transaction_map.remove(&signature) {
+            let _ = tx_confirm_records.send(TransactionConfirmRecord {
+                timed_out: false,
+                error: Some(EVICTED_ERROR.to_string()),
+                ..timed_out_record(&signature, &sent_record)
+            });
+        }
+    }
+}
+
+// written to a temporary file first so that a crash never leaves a truncated snapshot
+async fn save_pending_transactions(
+    path: &str,
+    transaction_map: &DashMap<Signature, (TransactionSendRecord, Instant)>,
+) -> anyhow::Result<()> {
+    let records = transaction_map
+        .iter()
+        .map(|x| x.value().0.clone())
+        .collect_vec();
+    let tmp_path = format!("{path}.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec(&records)?).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// Tracks the transactions of the previous run again, the ones older than the timeout are
+/// reported as timed out right away
+async fn restore_pending_transactions(
+    path: &str,
+    transaction_map: &DashMap<Signature, (TransactionSendRecord, Instant)>,
+    tx_confirm_records: &Sender<TransactionConfirmRecord>,
+    config: &ConfirmationConfig,
+) -> anyhow::Result<()> {
+    if !tokio::fs::try_exists(path).await? {
+        return Ok(());
+    }
+    let records: Vec<TransactionSendRecord> =
+        serde_json::from_slice(&tokio::fs::read(path).await?)?;
+    let mut nb_timed_out = 0;
+    for record in &records {
+        let age = (Utc::now() - record.sent_at).to_std().unwrap_or_default();
+        let timeout = config.timeout_for(record);
+        match Instant::now().checked_sub(age).filter(|_| age <= timeout) {
+            Some(sent_instant) => {
+                transaction_map.insert(record.signature, (record.clone(), sent_instant));
+            }
+            None => {
+                let _ = tx_confirm_records.send(timed_out_record(&record.signature, record));
+                nb_timed_out += 1;
+            }
+        }
+    }
+    info!(
+        "restored {} pending transactions from {path}, {nb_timed_out} of them timed out",
+        records.len()
+    );
+    Ok(())
+}
+
+/// Confirms pending transactions older than `min_age` which have a status with a slot,
+/// the others are left to the timeout logic
+async fn confirm_by_signature_statuses(
+    client: &dyn BlockFetcher,
+    transaction_map: &DashMap<Signature, (TransactionSendRecord, Instant)>,
+    tx_confirm_records: &Sender<TransactionConfirmRecord>,
+    min_age: Duration,
+    commitment: CommitmentConfig,
+    max_slot: Option<Slot>,
+) {
+    let signatures = transaction_map
+        .iter()
+        .filter(|x| x.1.elapsed() > min_age)
+        .map(|x| *x.key())
+        .collect_vec();
+    for signatures in signatures.chunks(MAX_GET_SIGNATURE_STATUSES_QUERY_ITEMS) {
+        let statuses = match client.get_signature_statuses(signatures).await {
+            Ok(statuses) => statuses.value,
+            Err(e) => {
+                warn!("failed to get signature statuses: {}", e);
+                continue;
+            }
+        };
+        for (signature, status) in signatures.iter().zip(statuses) {
+            let Some(status) = status.filter(|x| {
+                x.satisfies_commitment(commitment) && !matches!(max_slot, Some(max) if x.slot > max)
+            }) else {
+                continue;
+            };
+            // confirmed by blocks in the meantime
+            let Some((_, (sent_record, _))) = transaction_map.remove(signature) else {
+                continue;
+            };
+            debug!("{signature} confirmed through signature statuses");
+            let _ = tx_confirm_records.send(TransactionConfirmRecord {
+                signature: signature.to_string(),
+                confirmed_slot: Some(status.slot),
+                confirmed_at: Some(Utc::now().to_string()),
+                sent_at: sent_record.sent_at.to_string(),
+                sent_slot: sent_record.sent_slot,
+                successful: status.err.is_none(),
+                program_error: status.err.as_ref().and_then(|x| openbook_error(x, &[])),
+                error: status.err.map(|x| x.to_string()),
+                block_hash: None,
+                market: sent_record.markets_string(),
+                events: sent_record.events,
+                user: sent_record.user.map(|x| x.to_string()),
+                slot_processed: Some(status.slot),
+                slot_latency: Some(status.slot.saturating_sub(sent_record.sent_slot)),
+                slot_leader: None,
+                timed_out: false,
+                priority_fees: sent_record.priority_fees,
+                reorged: false,
+                run_tag: None,
+                confirmation_latency: None,
+            });
+        }
+    }
+}
+
+/// Looks up the signature statuses of confirmed transactions older than the finalization
+/// delay, the finalized ones are forgotten. The ones missing from the status cache are looked
+/// up in the ledger history as they may only have been checked too late, the ones missing
+/// from it too were dropped by a reorg
+async fn recheck_finalized(
+    client: &dyn BlockFetcher,
+    confirmed: &mut HashMap<Signature, (TransactionConfirmRecord, Instant)>,
+    tx_confirm_records: &Sender<TransactionConfirmRecord>,
+) {
+    let signatures = confirmed
+        .iter()
+        .filter(|x| x.1 .1.elapsed() > FINALIZATION_DELAY)
+        .map(|x| *x.0)
+        .collect_vec();
+    for signatures in signatures.chunks(MAX_GET_SIGNATURE_STATUSES_QUERY_ITEMS) {
+        let statuses = match client.get_signature_statuses(signatures).await {
+            Ok(statuses) => statuses.value,
+            Err(e) => {
+                warn!("failed to get signature statuses: {}", e);
+                continue;
+            }
+        };
+        let mut missing = vec![];
+        for (signature, status) in signatures.iter().zip(statuses) {
+            match status {
+                Some(status) if status.satisfies_commitment(CommitmentConfig::finalized()) => {
+                    confirmed.remove(signature);
+                }
+                // confirmed but not finalized yet
+                Some(_) => {}
+                None => missing.push(*signature),
+            }
+        }
+        if missing.is_empty() {
+            continue;
+        }
+        let statuses = match client.get_signature_statuses_with_history(&missing).await {
+            Ok(statuses) => statuses.value,
+            Err(e) => {
+                // checked again in the next pass
+                warn!("failed to get signature statuses with history: {}", e);
+                continue;
+            }
+        };
+        for (signature, status) in missing.iter().zip(statuses) {
+            match status {
+                Some(status) if status.satisfies_commitment(CommitmentConfig::finalized()) => {
+                    confirmed.remove(signature);
+                }
+                Some(_) => {}
+                None => {
+                    let Some((record, _)) = confirmed.remove(signature) else {
+                        continue;
+                    };
+                    warn!("{signature} was confirmed but dropped by a reorg");
+                    let _ = tx_confirm_records.send(TransactionConfirmRecord {
+                        successful: false,
+                        error: Some("reorged".to_string()),
+                        reorged: true,
+                        ..record
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn start_finalized_recheck(
+    client: Arc<dyn BlockFetcher>,
+    tx_confirm_records: Sender<TransactionConfirmRecord>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut records = tx_confirm_records.subscribe();
+        let mut confirmed = HashMap::new();
+        let mut interval = tokio::time::interval(FINALIZED_RECHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                record = records.recv() => match record {
+                    Ok(record) if record.confirmed_at.is_some() && !record.reorged => {
+                        if let Ok(signature) = Signature::from_str(&record.signature) {
+                            confirmed.insert(signature, (record, Instant::now()));
+                        }
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                },
+                _ = interval.tick() => {
+                    recheck_finalized(client.as_ref(), &mut confirmed, &tx_confirm_records).await;
+                }
+            }
+        }
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn confirmations_by_blocks(
-    client: Arc<RpcClient>,
+    client: Arc<dyn BlockFetcher>,
     mut tx_record_rx: UnboundedReceiver<TransactionSendRecord>,
     tx_confirm_records: tokio::sync::broadcast::Sender<TransactionConfirmRecord>,
     tx_block_data: tokio::sync::broadcast::Sender<BlockData>,
-    from_slot: u64,
+    current_slot: Arc<AtomicU64>,
+    config: ConfirmationConfig,
+    health: HealthStatus,
+    stats: CrankStats,
 ) -> Vec<JoinHandle<()>> {
+    info!(
+        "confirming transactions timeout={}s cleaner_interval={}s refresh_interval={}s±{}% commitment={}",
+        config.timeout.as_secs(),
+        config.cleaner_interval.as_secs(),
+        config.refresh_interval.as_secs(),
+        config.refresh_jitter_percent,
+        config.commitment
+    );
     let transaction_map = Arc::new(DashMap::new());
+    let signature_status_client = client.clone();
+    let signature_status_map = transaction_map.clone();
+    let signature_status_records = tx_confirm_records.clone();
+    let signature_status_slot = current_slot.clone();
+    let recheck_client = client.clone();
+    let recheck_records = tx_confirm_records.clone();
+    let lag_stats = stats.clone();
 
     let map_filler_jh = {
         let transaction_map = transaction_map.clone();
+        let tx_confirm_records = tx_confirm_records.clone();
+        let config = config.clone();
+        let stats = stats.clone();
         tokio::spawn(async move {
+            if let Some(path) = &config.pending_transactions_file {
+                if let Err(e) = restore_pending_transactions(
+                    path,
+                    &transaction_map,
+                    &tx_confirm_records,
+                    &config,
+                )
+                .await
+                {
+                    warn!("cannot restore pending transactions from {path}: {e}");
+                }
+            }
             loop {
                 if let Ok(tx_record) =
                     tokio::time::timeout(tokio::time::Duration::from_secs(1), tx_record_rx.recv())
@@ -168,6 +838,9 @@ pub fn confirmations_by_blocks(
                 {
                     match tx_record {
                         Some(tx_record) => {
+                            if let Some(built_at) = tx_record.built_at {
+                                stats.add_send_latency(built_at.elapsed());
+                            }
                             debug!(
                                 "add to queue len={} sig={}",
                                 transaction_map.len() + 1,
@@ -175,6 +848,11 @@ pub fn confirmations_by_blocks(
                             );
                             transaction_map
                                 .insert(tx_record.signature, (tx_record, Instant::now()));
+                            if let Some(max) = config.max_pending_transactions {
+                                if transaction_map.len() > max {
+                                    evict_oldest(&transaction_map, &tx_confirm_records, max);
+                                }
+                            }
                         }
                         None => {
                             break;
@@ -185,39 +863,40 @@ pub fn confirmations_by_blocks(
         })
     };
 
+    let recent_latencies = config.adaptive_timeout.then(RecentLatencies::default);
+    let latencies_jh = recent_latencies
+        .as_ref()
+        .map(|x| x.start_recording(tx_confirm_records.subscribe()));
+
     let cleaner_jh = {
         let transaction_map = transaction_map.clone();
         let tx_confirm_records = tx_confirm_records.clone();
+        let config = config.clone();
         tokio::spawn(async move {
             loop {
-                tokio::time::sleep(Duration::from_secs(10)).await;
+                tokio::time::sleep(config.cleaner_interval).await;
                 {
                     let mut to_remove = vec![];
+                    // congestion slows every confirmation down, waiting longer for them
+                    // avoids counting them as timed out
+                    let min_timeout = recent_latencies
+                        .as_ref()
+                        .map_or(Duration::ZERO, |x| x.timeout(config.timeout));
+                    if min_timeout > config.timeout {
+                        debug!("adaptive confirmation timeout {}s", min_timeout.as_secs());
+                    }
 
                     for tx_data in transaction_map.iter() {
                         let sent_record = &tx_data.0;
                         let instant = tx_data.1;
                         let signature = tx_data.key();
-                        let remove = instant.elapsed() > Duration::from_secs(120);
+                        let remove =
+                            instant.elapsed() > config.timeout_for(sent_record).max(min_timeout);
 
                         // add to timeout if not retaining
                         if remove {
-                            let _ = tx_confirm_records.send(TransactionConfirmRecord {
-                                signature: signature.to_string(),
-                                confirmed_slot: None,
-                                confirmed_at: None,
-                                sent_at: sent_record.sent_at.to_string(),
-                                sent_slot: sent_record.sent_slot,
-                                successful: false,
-                                error: Some("timeout".to_string()),
-                                block_hash: None,
-                                market: sent_record.market.map(|x| x.to_string()),
-                                user: sent_record.user.map(|x| x.to_string()),
-                                slot_processed: None,
-                                slot_leader: None,
-                                timed_out: true,
-                                priority_fees: sent_record.priority_fees,
-                            });
+                            let _ =
+                                tx_confirm_records.send(timed_out_record(signature, sent_record));
                             to_remove.push(*signature);
                         }
                     }
@@ -226,55 +905,154 @@ pub fn confirmations_by_blocks(
                         transaction_map.remove(&signature);
                     }
                 }
+                stats.set_pending_transactions(transaction_map.len());
+                if let Some(path) = &config.pending_transactions_file {
+                    if let Err(e) = save_pending_transactions(path, &transaction_map).await {
+                        warn!("cannot save pending transactions to {path}: {e}");
+                    }
+                }
             }
         })
     };
 
     let block_confirmation_jh = {
+        let config = config.clone();
         tokio::spawn(async move {
-            let mut start_block = from_slot;
+            // the current slot when this task starts rather than when it was spawned, the
+            // transactions sent meanwhile are caught up on the first fetch
+            let mut start_block = current_slot
+                .load(Ordering::Relaxed)
+                .saturating_sub(config.lookback_slots);
+            let mut first_fetch = true;
             let mut start_instant = tokio::time::Instant::now();
-            let refresh_in = Duration::from_secs(10);
+            // blocks are only listed once they reach the commitment, so start_block never
+            // moves past the finalized slot and blocks not yet finalized are not fetched
             let commitment_confirmation = CommitmentConfig {
-                commitment: CommitmentLevel::Confirmed,
+                commitment: config.commitment,
             };
+            let mut retry_slots: Vec<Slot> = vec![];
+            health.set_confirmation_running(true);
             loop {
+                let refresh_in = jittered(config.refresh_interval, config.refresh_jitter_percent);
                 let wait_duration = tokio::time::Instant::now() - start_instant;
                 if wait_duration < refresh_in {
                     tokio::time::sleep(refresh_in - wait_duration).await;
                 }
                 start_instant = tokio::time::Instant::now();
 
-                let block_slots =
-                    get_blocks_with_retry(client.clone(), start_block, commitment_confirmation)
-                        .await;
+                // the crank may send before this task starts, start from the earliest of
+                // those sends so that their blocks are not skipped. Restored transactions
+                // have no build instant and are left to the lookback slots
+                if std::mem::take(&mut first_fetch) {
+                    if let Some(earliest_sent_slot) = transaction_map
+                        .iter()
+                        .filter(|x| x.0.built_at.is_some())
+                        .map(|x| x.0.sent_slot)
+                        .min()
+                    {
+                        if earliest_sent_slot < start_block {
+                            debug!("confirming from slot {earliest_sent_slot} of the first sends instead of {start_block}");
+                            start_block = earliest_sent_slot;
+                        }
+                    }
+                }
+
+                // blocks are only read once they are deep enough that a reorg cannot replace
+                // them, their transactions stay pending until then
+                let end_block = config.deepest_confirmable_slot(&current_slot);
+                if end_block.is_some_and(|end_block| end_block < start_block) {
+                    continue;
+                }
+                let block_slots = get_blocks_with_retry(
+                    client.as_ref(),
+                    start_block,
+                    end_block,
+                    commitment_confirmation,
+                )
+                .await;
                 if block_slots.is_err() {
+                    health.set_confirmation_running(false);
                     break;
                 }
 
                 let block_slots = block_slots.unwrap();
-                if block_slots.is_empty() {
+                let retried_slots = std::mem::take(&mut retry_slots);
+                if let Some(last_slot) = block_slots.last() {
+                    let nb_skipped =
+                        (*last_slot + 1 - start_block).saturating_sub(block_slots.len() as u64);
+                    if nb_skipped > 0 {
+                        debug!(
+                            "{nb_skipped} skipped slots without blocks between {start_block} and {last_slot}"
+                        );
+                    }
+                    start_block = *last_slot + 1;
+                }
+                // confirmations lag behind the tip when block fetching cannot keep up, which
+                // shows up as timeouts later
+                let lag = current_slot
+                    .load(Ordering::Relaxed)
+                    .saturating_sub(config.depth_slots)
+                    .saturating_sub(start_block);
+                lag_stats.set_confirmation_lag(lag);
+                if lag > config.lag_warning_slots {
+                    warn!(
+                        "confirmation lags {lag} slots behind the current slot, block fetching cannot keep up"
+                    );
+                }
+                if block_slots.is_empty() && retried_slots.is_empty() {
                     continue;
                 }
-                start_block = *block_slots.last().unwrap() + 1;
-
-                let blocks = block_slots.iter().map(|slot| {
-                    client.get_block_with_config(
-                        *slot,
-                        RpcBlockConfig {
-                            encoding: Some(UiTransactionEncoding::Base64),
-                            transaction_details: Some(TransactionDetails::Full),
-                            rewards: Some(true),
-                            commitment: Some(commitment_confirmation),
-                            max_supported_transaction_version: Some(0),
-                        },
-                    )
+                let block_slots = retried_slots
+                    .iter()
+                    .chain(block_slots.iter())
+                    .copied()
+                    .collect_vec();
+
+                // bounded so that catching up does not burst the rpc with block requests
+                let blocks = block_slots.iter().map(|&slot| {
+                    let client = client.clone();
+                    async move {
+                        client
+                            .get_block_with_config(
+                                slot,
+                                RpcBlockConfig {
+                                    encoding: Some(UiTransactionEncoding::Base64),
+                                    transaction_details: Some(TransactionDetails::Full),
+                                    rewards: Some(config.leader_tracking),
+                                    commitment: Some(commitment_confirmation),
+                                    max_supported_transaction_version: Some(0),
+                                },
+                            )
+                            .await
+                    }
                 });
-                let blocks = futures::future::join_all(blocks).await;
+                let blocks = futures::stream::iter(blocks.collect_vec())
+                    .buffered(config.max_concurrent_block_fetches)
+                    .collect::<Vec<_>>()
+                    .await;
                 for block_slot in blocks.iter().zip(block_slots) {
                     let block = match block_slot.0 {
                         Ok(x) => x,
-                        Err(_) => continue,
+                        Err(e) if is_slot_skipped(e) => {
+                            debug!("slot {} was skipped", block_slot.1);
+                            continue;
+                        }
+                        Err(e) => {
+                            if config.retry_failed_blocks && !retried_slots.contains(&block_slot.1)
+                            {
+                                warn!(
+                                    "failed to fetch block {}, retrying in next pass: {}",
+                                    block_slot.1, e
+                                );
+                                retry_slots.push(block_slot.1);
+                            } else {
+                                warn!(
+                                    "failed to fetch block {}, its transactions will time out: {}",
+                                    block_slot.1, e
+                                );
+                            }
+                            continue;
+                        }
                     };
                     let tx_confirm_records = tx_confirm_records.clone();
                     let tx_block_data = tx_block_data.clone();
@@ -285,11 +1063,355 @@ pub fn confirmations_by_blocks(
                         tx_block_data,
                         transaction_map,
                         block_slot.1,
+                        config.block_data_only_ours,
                     )
                     .await;
                 }
             }
         })
     };
-    vec![map_filler_jh, cleaner_jh, block_confirmation_jh]
+    let mut tasks = vec![map_filler_jh, cleaner_jh, block_confirmation_jh];
+    tasks.extend(latencies_jh);
+
+    if config.recheck_finalized && config.commitment != CommitmentLevel::Finalized {
+        tasks.push(start_finalized_recheck(recheck_client, recheck_records));
+    }
+
+    if config.signature_status_fallback {
+        tasks.push(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(config.cleaner_interval).await;
+                confirm_by_signature_statuses(
+                    signature_status_client.as_ref(),
+                    &signature_status_map,
+                    &signature_status_records,
+                    config.timeout / 2,
+                    CommitmentConfig {
+                        commitment: config.commitment,
+                    },
+                    config.deepest_confirmable_slot(&signature_status_slot),
+                )
+                .await;
+            }
+        }));
+    }
+    tasks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_client::rpc_response::{Response, RpcResponseContext};
+    use solana_sdk::{
+        message::Message, signature::Keypair, signer::Signer, transaction::Transaction,
+    };
+    use solana_transaction_status::{
+        Encodable, EncodedTransactionWithStatusMeta, TransactionStatusMeta,
+    };
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const BLOCK_SLOT: Slot = 5;
+
+    // serves the given blocks, the other slots are skipped
+    struct SyntheticBlocks(HashMap<Slot, UiConfirmedBlock>);
+
+    #[async_trait]
+    impl BlockFetcher for SyntheticBlocks {
+        async fn get_blocks_with_commitment(
+            &self,
+            start_slot: Slot,
+            end_slot: Option<Slot>,
+            _commitment_config: CommitmentConfig,
+        ) -> ClientResult<Vec<Slot>> {
+            Ok(self
+                .0
+                .keys()
+                .copied()
+                .filter(|x| (start_slot..=end_slot.unwrap_or(Slot::MAX)).contains(x))
+                .sorted()
+                .collect())
+        }
+
+        async fn get_block_with_config(
+            &self,
+            slot: Slot,
+            _config: RpcBlockConfig,
+        ) -> ClientResult<UiConfirmedBlock> {
+            self.0
+                .get(&slot)
+                .cloned()
+                .ok_or_else(|| ClientErrorKind::Custom(format!("no block at {slot}")).into())
+        }
+
+        async fn get_signature_statuses(
+            &self,
+            signatures: &[Signature],
+        ) -> RpcResult<Vec<Option<TransactionStatus>>> {
+            Ok(Response {
+                context: RpcResponseContext {
+                    slot: BLOCK_SLOT,
+                    api_version: None,
+                },
+                value: vec![None; signatures.len()],
+            })
+        }
+
+        async fn get_signature_statuses_with_history(
+            &self,
+            signatures: &[Signature],
+        ) -> RpcResult<Vec<Option<TransactionStatus>>> {
+            self.get_signature_statuses(signatures).await
+        }
+    }
+
+    // a transaction with the given status and its signature
+    fn transaction(
+        status: Result<(), TransactionError>,
+    ) -> (Signature, EncodedTransactionWithStatusMeta) {
+        let signature = Signature::new_unique();
+        let transaction = Transaction {
+            signatures: vec![signature],
+            message: Message::new(&[], Some(&Keypair::new().pubkey())),
+        };
+        let meta = TransactionStatusMeta {
+            status,
+            compute_units_consumed: Some(1000),
+            ..TransactionStatusMeta::default()
+        };
+        let transaction = EncodedTransactionWithStatusMeta {
+            transaction: transaction.encode(UiTransactionEncoding::Base64),
+            meta: Some(meta.into()),
+            version: None,
+        };
+        (signature, transaction)
+    }
+
+    fn block(transactions: Vec<EncodedTransactionWithStatusMeta>) -> UiConfirmedBlock {
+        UiConfirmedBlock {
+            previous_blockhash: String::new(),
+            blockhash: "blockhash".to_string(),
+            parent_slot: BLOCK_SLOT - 1,
+            transactions: Some(transactions),
+            signatures: None,
+            rewards: None,
+            block_time: None,
+            block_height: None,
+        }
+    }
+
+    fn config() -> ConfirmationConfig {
+        ConfirmationConfig {
+            retry_failed_blocks: false,
+            lookback_slots: 10,
+            timeout: Duration::from_secs(60),
+            cleaner_interval: Duration::from_secs(60),
+            refresh_interval: Duration::from_millis(10),
+            refresh_jitter_percent: 0,
+            signature_status_fallback: false,
+            block_data_only_ours: false,
+            commitment: CommitmentLevel::Confirmed,
+            recheck_finalized: false,
+            max_concurrent_block_fetches: 1,
+            max_pending_transactions: None,
+            pending_transactions_file: None,
+            market_timeouts: HashMap::new(),
+            lag_warning_slots: u64::MAX,
+            adaptive_timeout: false,
+            leader_tracking: false,
+            depth_slots: 0,
+        }
+    }
+
+    // confirms the sent transaction with the block, returns its confirm record if any and
+    // the block data
+    async fn confirm_with_block(
+        sent: Signature,
+        block: UiConfirmedBlock,
+    ) -> (Option<TransactionConfirmRecord>, BlockData) {
+        let (tx_record_sx, tx_record_rx) = unbounded_channel();
+        let (tx_confirm_records, mut confirm_records) = tokio::sync::broadcast::channel(16);
+        let (tx_block_data, mut block_data) = tokio::sync::broadcast::channel(16);
+        tx_record_sx
+            .send(TransactionSendRecord {
+                signature: sent,
+                sent_at: Utc::now(),
+                sent_slot: BLOCK_SLOT - 1,
+                user: None,
+                markets: vec![Pubkey::new_unique()],
+                priority_fees: 0,
+                built_at: None,
+                events: vec![],
+            })
+            .unwrap();
+        let tasks = confirmations_by_blocks(
+            Arc::new(SyntheticBlocks(HashMap::from([(BLOCK_SLOT, block)]))),
+            tx_record_rx,
+            tx_confirm_records,
+            tx_block_data,
+            Arc::new(AtomicU64::new(BLOCK_SLOT + 1)),
+            config(),
+            HealthStatus::default(),
+            CrankStats::new(None, None, HealthStatus::default(), Duration::ZERO, None),
+        );
+        let block_data = tokio::time::timeout(Duration::from_secs(5), block_data.recv())
+            .await
+            .expect("the block is processed")
+            .unwrap();
+        let record = confirm_records.try_recv().ok();
+        for task in tasks {
+            task.abort();
+        }
+        (record, block_data)
+    }
+
+    #[tokio::test]
+    async fn confirms_successful_transaction() {
+        let (signature, transaction) = transaction(Ok(()));
+        let (record, block_data) = confirm_with_block(signature, block(vec![transaction])).await;
+        let record = record.expect("the transaction is confirmed");
+        assert_eq!(record.signature, signature.to_string());
+        assert!(record.successful);
+        assert_eq!(record.confirmed_slot, Some(BLOCK_SLOT));
+        assert_eq!(record.slot_latency, Some(1));
+        assert_eq!(record.error, None);
+        assert_eq!(block_data.number_of_mm_transactions, 1);
+        assert_eq!(block_data.cu_consumed_by_openbook, 1000);
+    }
+
+    #[tokio::test]
+    async fn confirms_failed_transaction() {
+        let error = TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(u32::from(OPENBOOK_ERRORS[0])),
+        );
+        let (signature, transaction) = transaction(Err(error));
+        let (record, _) = confirm_with_block(signature, block(vec![transaction])).await;
+        let record = record.expect("the transaction is confirmed");
+        assert!(!record.successful);
+        assert!(record.error.is_some());
+        assert_eq!(
+            record.program_error,
+            Some(format!(
+                "{} ({})",
+                OPENBOOK_ERRORS[0].name(),
+                u32::from(OPENBOOK_ERRORS[0])
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_transaction_not_ours() {
+        let (_, transaction) = transaction(Ok(()));
+        let (record, block_data) =
+            confirm_with_block(Signature::new_unique(), block(vec![transaction])).await;
+        assert!(record.is_none());
+        assert_eq!(block_data.number_of_mm_transactions, 0);
+        assert_eq!(block_data.total_transactions, 1);
+        assert_eq!(block_data.cu_consumed, 1000);
+        assert_eq!(block_data.cu_consumed_by_openbook, 0);
+    }
+
+    // a variant added to or removed from the middle of the enum shifts the codes of the
+    // variants after it
+    #[test]
+    fn lists_openbook_errors_in_enum_order() {
+        for (index, error) in OPENBOOK_ERRORS.iter().enumerate() {
+            assert_eq!(
+                u32::from(*error),
+                anchor_lang::error::ERROR_CODE_OFFSET + index as u32,
+                "{} is out of place",
+                error.name()
+            );
+        }
+    }
+
+    // statuses missing from the status cache, the given ones are finalized in the history
+    struct StatusHistory(Vec<Signature>);
+
+    #[async_trait]
+    impl BlockFetcher for StatusHistory {
+        async fn get_blocks_with_commitment(
+            &self,
+            _start_slot: Slot,
+            _end_slot: Option<Slot>,
+            _commitment_config: CommitmentConfig,
+        ) -> ClientResult<Vec<Slot>> {
+            Ok(vec![])
+        }
+
+        async fn get_block_with_config(
+            &self,
+            slot: Slot,
+            _config: RpcBlockConfig,
+        ) -> ClientResult<UiConfirmedBlock> {
+            Err(ClientErrorKind::Custom(format!("no block at {slot}")).into())
+        }
+
+        async fn get_signature_statuses(
+            &self,
+            signatures: &[Signature],
+        ) -> RpcResult<Vec<Option<TransactionStatus>>> {
+            SyntheticBlocks(HashMap::new())
+                .get_signature_statuses(signatures)
+                .await
+        }
+
+        async fn get_signature_statuses_with_history(
+            &self,
+            signatures: &[Signature],
+        ) -> RpcResult<Vec<Option<TransactionStatus>>> {
+            let status = TransactionStatus {
+                slot: BLOCK_SLOT,
+                confirmations: None,
+                status: Ok(()),
+                err: None,
+                confirmation_status: Some(
+                    solana_transaction_status::TransactionConfirmationStatus::Finalized,
+                ),
+            };
+            Ok(Response {
+                context: RpcResponseContext {
+                    slot: BLOCK_SLOT,
+                    api_version: None,
+                },
+                value: signatures
+                    .iter()
+                    .map(|x| self.0.contains(x).then(|| status.clone()))
+                    .collect(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn flags_reorg_only_when_missing_from_history() {
+        let (finalized, reorged) = (Signature::new_unique(), Signature::new_unique());
+        let send_record = TransactionSendRecord {
+            signature: finalized,
+            sent_at: Utc::now(),
+            sent_slot: BLOCK_SLOT,
+            user: None,
+            markets: vec![],
+            priority_fees: 0,
+            built_at: None,
+            events: vec![],
+        };
+        let confirmed_at = Instant::now() - FINALIZATION_DELAY - Duration::from_secs(1);
+        let mut confirmed = [finalized, reorged]
+            .into_iter()
+            .map(|x| (x, (timed_out_record(&x, &send_record), confirmed_at)))
+            .collect();
+        let (tx_confirm_records, mut records) = tokio::sync::broadcast::channel(4);
+        recheck_finalized(
+            &StatusHistory(vec![finalized]),
+            &mut confirmed,
+            &tx_confirm_records,
+        )
+        .await;
+
+        assert!(confirmed.is_empty());
+        let record = records.try_recv().ok().unwrap();
+        assert_eq!(record.signature, reorged.to_string());
+        assert!(record.reorged);
+        assert!(records.try_recv().is_err());
+    }
 }
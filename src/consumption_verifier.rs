@@ -0,0 +1,280 @@
+use crate::{openbook_v2_sink::HeapState, states::TransactionConfirmRecord, stats::CrankStats};
+use anchor_lang::AccountDeserialize;
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::{debug, warn};
+use openbook_v2::state::EventHeap;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    nonblocking::rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcTransactionConfig},
+};
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature};
+use solana_transaction_status::{option_serializer::OptionSerializer, UiTransactionEncoding};
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+// the oldest sent transactions are forgotten above this, they timed out long ago
+const MAX_PENDING_VERIFICATIONS: usize = 10_000;
+
+// a consume events instruction consuming no event only loads the market and its heap,
+// consuming one also loads and updates an open orders account
+const EMPTY_CONSUME_EVENTS_MAX_CU: u64 = 10_000;
+
+/// Event heaps of the markets of the sent transactions as last seen before sending them
+#[derive(Default)]
+pub struct PendingVerifications {
+    signatures: VecDeque<Signature>,
+    heaps: HashMap<Signature, Vec<(Pubkey, HeapState)>>,
+}
+
+impl PendingVerifications {
+    pub fn insert(&mut self, signature: Signature, heaps: Vec<(Pubkey, HeapState)>) {
+        if heaps.is_empty() {
+            return;
+        }
+        if self.signatures.len() >= MAX_PENDING_VERIFICATIONS {
+            if let Some(oldest) = self.signatures.pop_front() {
+                self.heaps.remove(&oldest);
+            }
+        }
+        self.signatures.push_back(signature);
+        self.heaps.insert(signature, heaps);
+    }
+
+    fn take(&mut self, signature: &Signature) -> Option<Vec<(Pubkey, HeapState)>> {
+        self.heaps.remove(signature)
+    }
+}
+
+/// Reads again the event heaps of the markets of every successful transaction once it is
+/// confirmed and counts the markets in which no event was consumed, e.g. when another crank
+/// consumed the events first. The heaps tell whether any crank consumed events since they were
+/// last seen, the compute units of the instructions of the transaction tell whether it did.
+pub fn start_consumption_verifier(
+    pending: Arc<Mutex<PendingVerifications>>,
+    tx_confirm_records: broadcast::Receiver<TransactionConfirmRecord>,
+    rpc_client: Arc<RpcClient>,
+    program_id: Pubkey,
+    stats: CrankStats,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tx_confirm_records = tx_confirm_records;
+        // run within the verifier so that they stop with it
+        let mut verifications = FuturesUnordered::new();
+        loop {
+            let record = tokio::select! {
+                record = tx_confirm_records.recv() => record,
+                Some(()) = verifications.next(), if !verifications.is_empty() => continue,
+            };
+            let record = match record {
+                Ok(record) => record,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let Ok(signature) = Signature::from_str(&record.signature) else {
+                continue;
+            };
+            // failed and timed out transactions are forgotten as well
+            let Some(heaps) = pending.lock().unwrap().take(&signature) else {
+                continue;
+            };
+            if !record.successful || record.reorged || record.confirmed_slot.is_none() {
+                continue;
+            }
+            // one request per transaction must not hold back the next confirmations
+            let rpc_client = rpc_client.clone();
+            let stats = stats.clone();
+            // the instructions of the transaction follow the order of its markets
+            let markets = record
+                .market
+                .iter()
+                .flat_map(|x| x.split(';'))
+                .filter_map(|x| Pubkey::from_str(x).ok())
+                .collect::<Vec<_>>();
+            verifications.push(async move {
+                verify_consumption(
+                    &rpc_client,
+                    &program_id,
+                    &signature,
+                    record.confirmed_slot,
+                    &markets,
+                    heaps,
+                    &stats,
+                )
+                .await;
+            });
+        }
+    })
+}
+
+async fn verify_consumption(
+    rpc_client: &RpcClient,
+    program_id: &Pubkey,
+    signature: &Signature,
+    confirmed_slot: Option<u64>,
+    markets: &[Pubkey],
+    heaps: Vec<(Pubkey, HeapState)>,
+    stats: &CrankStats,
+) {
+    let event_heap_pks = heaps.iter().map(|(_, x)| x.event_heap).collect::<Vec<_>>();
+    let config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        commitment: Some(CommitmentConfig::confirmed()),
+        // the heaps must include the transaction
+        min_context_slot: confirmed_slot,
+        data_slice: None,
+    };
+    let accounts = match rpc_client
+        .get_multiple_accounts_with_config(&event_heap_pks, config)
+        .await
+    {
+        Ok(response) => response.value,
+        Err(e) => {
+            warn!("cannot fetch the event heaps cranked by {signature}: {e}");
+            return;
+        }
+    };
+    // fetched once the heaps show that some crank consumed events
+    let mut instruction_cus = None;
+    for ((market, before), account) in heaps.iter().zip(accounts) {
+        let Some(account) = account else {
+            continue;
+        };
+        let Ok(after) = EventHeap::try_deserialize(&mut account.data.as_slice()) else {
+            continue;
+        };
+        let pushed = after.header.seq_num.saturating_sub(before.seq_num) as i64;
+        let consumed = pushed - (after.iter().count() as i64 - before.len as i64);
+        if consumed <= 0 {
+            warn!(
+                "{signature} was confirmed but no event of market {market} was consumed, seq_num {} len {} before and seq_num {} len {} after",
+                before.seq_num,
+                before.len,
+                after.header.seq_num,
+                after.iter().count()
+            );
+            stats.inc_no_effect();
+            continue;
+        }
+        if instruction_cus.is_none() {
+            instruction_cus =
+                Some(transaction_instruction_cus(rpc_client, program_id, signature).await);
+        }
+        let instruction_cu = markets
+            .iter()
+            .position(|x| x == market)
+            .and_then(|i| instruction_cus.as_ref()?.as_ref()?.get(i).copied());
+        match instruction_cu {
+            Some(cu) if cu <= EMPTY_CONSUME_EVENTS_MAX_CU => {
+                warn!(
+                    "{signature} was confirmed but consumed no event of market {market}, {consumed} events were consumed by other cranks"
+                );
+                stats.inc_no_effect();
+            }
+            Some(cu) => debug!(
+                "{signature} consumed events of market {market} with {cu} CU, {consumed} events were consumed since the heap was last seen"
+            ),
+            // the events consumed by any crank are the best guess without the logs
+            None => debug!(
+                "{signature} consumed up to {consumed} events of market {market}"
+            ),
+        }
+    }
+}
+
+/// compute units of the consume events instructions of the transaction, from its logs
+async fn transaction_instruction_cus(
+    rpc_client: &RpcClient,
+    program_id: &Pubkey,
+    signature: &Signature,
+) -> Option<Vec<u64>> {
+    let config = RpcTransactionConfig {
+        encoding: Some(UiTransactionEncoding::Base64),
+        commitment: Some(CommitmentConfig::confirmed()),
+        max_supported_transaction_version: Some(0),
+    };
+    let transaction = match rpc_client
+        .get_transaction_with_config(signature, config)
+        .await
+    {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            warn!("cannot fetch the logs of {signature}: {e}");
+            return None;
+        }
+    };
+    match transaction.transaction.meta?.log_messages {
+        OptionSerializer::Some(logs) => Some(top_level_compute_units(&logs, program_id)),
+        _ => None,
+    }
+}
+
+/// Compute units consumed by each top level instruction of the program in the order they ran,
+/// the instructions it invokes itself, such as event logs, are counted in their caller
+fn top_level_compute_units(logs: &[String], program_id: &Pubkey) -> Vec<u64> {
+    let program_id = program_id.to_string();
+    let mut depth = 0usize;
+    let mut compute_units = vec![];
+    for log in logs {
+        // "Program log: ..." and other program output have a colon after "Program"
+        let words = log.split(' ').collect::<Vec<_>>();
+        let [_, program, action, rest @ ..] = words.as_slice() else {
+            continue;
+        };
+        if !log.starts_with("Program ") || program.ends_with(':') {
+            continue;
+        }
+        match *action {
+            "invoke" => depth += 1,
+            "success" | "failed:" => depth = depth.saturating_sub(1),
+            "consumed" if depth == 1 && *program == program_id => {
+                if let Some(Ok(cu)) = rest.first().map(|x| x.parse()) {
+                    compute_units.push(cu);
+                }
+            }
+            _ => {}
+        }
+    }
+    compute_units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_compute_units_of_top_level_instructions() {
+        let program_id = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let logs = logs(&[
+            "Program ComputeBudget111111111111111111111111111111 invoke [1]",
+            "Program ComputeBudget111111111111111111111111111111 success",
+            &format!("Program {program_id} invoke [1]"),
+            "Program log: Instruction: ConsumeEvents",
+            &format!("Program {program_id} invoke [2]"),
+            &format!("Program {program_id} consumed 2000 of 150000 compute units"),
+            &format!("Program {program_id} success"),
+            &format!("Program {program_id} consumed 25000 of 200000 compute units"),
+            &format!("Program {program_id} success"),
+            &format!("Program {other} invoke [1]"),
+            &format!("Program {other} consumed 700 of 175000 compute units"),
+            &format!("Program {other} success"),
+            &format!("Program {program_id} invoke [1]"),
+            "Program log: Instruction: ConsumeEvents",
+            &format!("Program {program_id} consumed 6000 of 174300 compute units"),
+            &format!("Program {program_id} success"),
+        ]);
+        assert_eq!(
+            top_level_compute_units(&logs, &program_id),
+            vec![25000, 6000]
+        );
+    }
+}
@@ -1,8 +1,24 @@
 use crate::{
-    markets::MarketData, openbook_v2_sink::OpenbookV2CrankSink, states::TransactionSendRecord,
+    consumption_verifier::{start_consumption_verifier, PendingVerifications},
+    jito_manager::next_tip_account,
+    leader_schedule::LeaderSchedule,
+    markets::MarketData,
+    openbook_v2_sink::{
+        EventTypeFilter, HeapState, MarketInstructions, OpenbookV2CrankSink, MAX_ACCS_PER_TX,
+        MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE,
+    },
+    priority_fees::AdaptiveFee,
+    remote_signer::{CrankAuthority, RemoteSigner},
+    rpc_manager::BlockhashNotFoundSends,
+    states::{
+        ConsumedEvent, EventCounts, HeapDepthRecord, TransactionConfirmRecord,
+        TransactionSendRecord,
+    },
+    stats::CrankStats,
 };
+use anchor_lang::InstructionData;
 use anyhow::anyhow;
-use async_channel::unbounded;
+use async_channel::bounded;
 use async_trait::async_trait;
 use chrono::Utc;
 use itertools::Itertools;
@@ -11,104 +27,489 @@ use jsonrpc_core_client::transports::{http, ws};
 use log::*;
 use solana_account_decoder::{UiAccount, UiAccountEncoding};
 use solana_client::{
-    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    nonblocking::rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSimulateTransactionConfig},
     rpc_response::{OptionalContext, Response, RpcKeyedAccount},
 };
 use solana_program::{slot_history::Slot, stake_history::Epoch};
 use solana_rpc::rpc_pubsub::RpcSolPubSubClient;
 use solana_sdk::{
     account::{Account, AccountSharedData, ReadableAccount, WritableAccount},
+    address_lookup_table_account::AddressLookupTableAccount,
+    clock::MAX_PROCESSING_AGE,
     commitment_config::{CommitmentConfig, CommitmentLevel},
     compute_budget::ComputeBudgetInstruction,
     hash::Hash,
     instruction::Instruction,
+    message::{v0, Message, VersionedMessage},
+    packet::PACKET_DATA_SIZE,
     pubkey::Pubkey,
-    signature::Keypair,
+    signature::{Keypair, Signature},
     signer::Signer,
-    transaction::Transaction,
+    system_instruction,
+    transaction::VersionedTransaction,
 };
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
     str::FromStr,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     time::{Duration, Instant},
 };
 use tokio::{
-    sync::{mpsc::UnboundedSender, RwLock},
+    sync::{broadcast, mpsc::UnboundedSender, RwLock},
     task::JoinHandle,
 };
 
+// a nonce unchanged for this long was not advanced by the previous transaction which was
+// then most likely dropped, so it is used again
+const NONCE_ADVANCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Durable nonce signed over instead of the recent blockhash
 #[derive(Debug, Clone)]
+pub struct DurableNonce {
+    pub account: Pubkey,
+    pub authority: Arc<Keypair>,
+    /// blockhash stored in the nonce account, refreshed by the nonce polling service
+    pub value: Arc<RwLock<Option<Hash>>>,
+}
+
+impl DurableNonce {
+    // every transaction advances the nonce so each nonce value can only be used once
+    async fn next_value(&self, last_used: Option<Hash>) -> Option<Hash> {
+        let deadline = Instant::now() + NONCE_ADVANCE_TIMEOUT;
+        loop {
+            let value = *self.value.read().await;
+            if value.is_some() && (value != last_used || Instant::now() >= deadline) {
+                return value;
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct KeeperConfig {
     pub program_id: Pubkey,
     pub rpc_url: String,
     pub websocket_url: String,
+    /// when set, consume events of markets updated within this window are sent together
+    pub market_batch_window: Option<Duration>,
+    /// compute unit price, updated in the background when fees are dynamic
+    pub prioritization_fee: Arc<AtomicU64>,
+    /// warn when a market event heap holds at least this many events
+    pub backlog_alert_threshold: Option<usize>,
+    /// log an error when a heap with a backlog keeps the same seq_num for this many updates
+    pub stuck_heap_updates: usize,
+    /// send v0 transactions referencing event accounts through this table, kept up to date
+    /// in the background
+    pub lookup_table: Option<Arc<RwLock<AddressLookupTableAccount>>>,
+    /// tip jito with this many lamports in every transaction
+    pub jito_tip_lamports: Option<u64>,
+    /// events consumed by the crank
+    pub event_types: EventTypeFilter,
+    /// consume at most this many events per instruction, accounts are still gathered for
+    /// as many events as fit in the transaction
+    pub consume_limit: Option<usize>,
+    /// simulate the first transaction of every market and only send it if it succeeds
+    pub validate_first_send: bool,
+    /// hold transactions until a leader which included our transactions is about to lead
+    pub leader_schedule: Option<LeaderSchedule>,
+    /// crank on fills only once they are worth this much, in price lots times base lots
+    pub min_fill_value: Option<u64>,
+    /// do not crank a market again before this long after its last crank
+    pub min_crank_interval: Option<Duration>,
+    /// instructions waiting for the transaction sender, the stale ones are dropped above it
+    pub instruction_queue_size: usize,
+    /// crank heaps holding events of these accounts right away
+    pub priority_owners: HashSet<Pubkey>,
+    /// raise the compute unit price of markets whose event heap fills up quickly
+    pub adaptive_fee: Option<AdaptiveFee>,
+    /// stream the event heaps followed over the global endpoints from this gRPC endpoint
+    pub grpc_source: Option<GrpcSourceConfig>,
+    /// sign over this durable nonce instead of the recent blockhash
+    pub durable_nonce: Option<DurableNonce>,
+    /// crank every heap holding events through `MarketsHandle::crank_once` instead of
+    /// following account updates
+    pub once: bool,
+    /// sign a transaction again with a fresh blockhash and resend it once when it timed out
+    /// after its blockhash expired
+    pub retry_expired_blockhash: bool,
+    /// transactions rejected by the rpc as their blockhash was not found, retried as well
+    pub blockhash_not_found_sends: Option<BlockhashNotFoundSends>,
+    /// print the events and the crank decision of every update instead of cranking
+    pub debug_events: bool,
+    /// read the event heaps of the markets of every successful transaction again once it
+    /// is confirmed and count the markets in which no event was consumed
+    pub verify_consumption: bool,
+}
+
+impl KeeperConfig {
+    /// event accounts referenced by a single consume events transaction
+    pub fn max_event_accounts(&self) -> usize {
+        // accounts found in the lookup table take 1 byte instead of 32, so more fit in a tx
+        if self.lookup_table.is_some() {
+            MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE
+        } else {
+            MAX_ACCS_PER_TX
+        }
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn start(
     config: KeeperConfig,
     blockhash: Arc<RwLock<Hash>>,
     current_slot: Arc<AtomicU64>,
     markets: &[MarketData],
-    identity: &Keypair,
-    prioritization_fee: u64,
-    tx_rx: UnboundedSender<(Transaction, TransactionSendRecord)>,
-) -> Vec<JoinHandle<()>> {
-    let (instruction_sender, instruction_receiver) = unbounded::<(Pubkey, Vec<Instruction>)>();
-    let identity = Keypair::from_bytes(identity.to_bytes().as_slice()).unwrap();
+    identity: &CrankAuthority,
+    fee_payer: &Keypair,
+    tx_rx: UnboundedSender<(VersionedTransaction, TransactionSendRecord)>,
+    stats: CrankStats,
+    heap_depth_sender: Option<broadcast::Sender<HeapDepthRecord>>,
+    tx_confirm_records: &broadcast::Sender<TransactionConfirmRecord>,
+) -> (Vec<JoinHandle<()>>, MarketsHandle) {
+    let (instruction_sender, instruction_receiver) =
+        bounded::<MarketInstructions>(config.instruction_queue_size);
+    let stale_instruction_receiver = instruction_receiver.clone();
+    let identity = identity.clone();
+    let fee_payer = Keypair::from_bytes(fee_payer.to_bytes().as_slice()).unwrap();
+    let prioritization_fee = config.prioritization_fee.clone();
+    let max_accounts = config.max_event_accounts();
+    let lookup_table = config.lookup_table.clone();
+    let sender_stats = stats.clone();
+    let durable_nonce = config.durable_nonce.clone();
+    let leader_schedule = config.leader_schedule.clone();
+    let adaptive_fee = config.adaptive_fee.clone();
+    let validation_client = config
+        .validate_first_send
+        .then(|| RpcClient::new(config.rpc_url.clone()));
+    let sink = Arc::new(OpenbookV2CrankSink::new(
+        markets.to_vec(),
+        (instruction_sender, stale_instruction_receiver),
+        &config,
+        stats,
+        heap_depth_sender,
+    ));
+    let sender_sink = sink.clone();
+    // transactions signed over a durable nonce do not expire
+    let retryable = (config.retry_expired_blockhash && durable_nonce.is_none())
+        .then(|| Arc::new(Mutex::new(RetryableTransactions::default())));
+    let retry_task = retryable.clone().map(|retryable| {
+        start_expired_blockhash_retry(
+            retryable,
+            sink.clone(),
+            config.blockhash_not_found_sends.clone(),
+            tx_confirm_records.subscribe(),
+            blockhash.clone(),
+            current_slot.clone(),
+            identity.clone(),
+            Keypair::from_bytes(&fee_payer.to_bytes()).unwrap(),
+            tx_rx.clone(),
+            sender_stats.clone(),
+        )
+    });
+    let pending_verifications = config
+        .verify_consumption
+        .then(|| Arc::new(Mutex::new(PendingVerifications::default())));
+    let verification_task = pending_verifications.clone().map(|pending| {
+        start_consumption_verifier(
+            pending,
+            tx_confirm_records.subscribe(),
+            Arc::new(RpcClient::new(config.rpc_url.clone())),
+            config.program_id,
+            sender_stats.clone(),
+        )
+    });
     let t1 = tokio::spawn(async move {
         info!(
-            "crank-tx-sender signing with keypair pk={:?}",
-            identity.pubkey()
+            "crank-tx-sender signing with keypair pk={:?} fee payer pk={:?}",
+            identity.pubkey(),
+            fee_payer.pubkey()
         );
 
+        let mut pending = None;
+        let mut last_nonce = None;
+        let mut validated_markets = HashSet::new();
         loop {
-            if let Ok((market, mut ixs)) = instruction_receiver.recv().await {
+            let next = match pending.take() {
+                Some(next) => Ok(next),
+                None => instruction_receiver.recv().await,
+            };
+            if let Ok(next) = next {
+                let mut batch = ConsumeEventsBatch::new(next, max_accounts);
+                // pack instructions of other markets received during the batch window
+                if let Some(batch_window) = config.market_batch_window {
+                    let deadline = tokio::time::Instant::now() + batch_window;
+                    while let Ok(Ok(next)) =
+                        tokio::time::timeout_at(deadline, instruction_receiver.recv()).await
+                    {
+                        if let Err(next) = batch.try_add(next) {
+                            pending = Some(next);
+                            break;
+                        }
+                    }
+                }
+                let market_configs = sender_sink.market_configs(&batch.market_pks());
+                let cu_limit = batch.compute_unit_limit(|market| {
+                    market_configs
+                        .get(market)
+                        .and_then(|x| x.compute_unit_limit)
+                });
+                let (mut markets, mut ixs, built_at, mut events) = batch.into_parts();
+                sender_stats.set_pending_instructions(instruction_receiver.len());
+
+                // wait before building the transaction so that its blockhash stays fresh
+                if let Some(leader_schedule) = &leader_schedule {
+                    leader_schedule
+                        .wait_for_reachable_leader(&current_slot, &sender_stats)
+                        .await;
+                }
+
+                // set an explicit limit so that large consume events do not silently run out of CU
+                ixs.insert(
+                    0,
+                    ComputeBudgetInstruction::set_compute_unit_limit(cu_limit),
+                );
+
                 // add priority fees
+                let prioritization_fee = prioritization_fee.load(Ordering::Relaxed);
+                let prioritization_fee = match &adaptive_fee {
+                    Some(adaptive_fee) => adaptive_fee.fee(&markets, prioritization_fee),
+                    None => prioritization_fee,
+                };
+                // markets with their own price override the global one
+                let prioritization_fee = markets
+                    .iter()
+                    .map(|market| {
+                        market_configs
+                            .get(market)
+                            .and_then(|x| x.compute_unit_price)
+                            .unwrap_or(prioritization_fee)
+                    })
+                    .max()
+                    .unwrap_or(prioritization_fee);
                 ixs.push(ComputeBudgetInstruction::set_compute_unit_price(
                     prioritization_fee,
                 ));
 
-                let tx = Transaction::new_signed_with_payer(
-                    &ixs,
-                    Some(&identity.pubkey()),
-                    &[&identity],
-                    *blockhash.read().await,
+                if let Some(tip) = config.jito_tip_lamports {
+                    ixs.push(system_instruction::transfer(
+                        &fee_payer.pubkey(),
+                        &next_tip_account(),
+                        tip,
+                    ));
+                }
+
+                let previous_nonce = last_nonce;
+                let blockhash = match &durable_nonce {
+                    Some(durable_nonce) => {
+                        let Some(nonce) = durable_nonce.next_value(last_nonce).await else {
+                            error!("nonce account {} not fetched, dropping transaction for {markets:?}", durable_nonce.account);
+                            sender_stats.inc_dropped();
+                            continue;
+                        };
+                        last_nonce = Some(nonce);
+                        // advancing the nonce must be the first instruction
+                        ixs.insert(
+                            0,
+                            system_instruction::advance_nonce_account(
+                                &durable_nonce.account,
+                                &durable_nonce.authority.pubkey(),
+                            ),
+                        );
+                        nonce
+                    }
+                    None => *blockhash.read().await,
+                };
+                let mut other_signers = identity.keypair().into_iter().collect_vec();
+                if let Some(durable_nonce) = &durable_nonce {
+                    other_signers.push(durable_nonce.authority.as_ref());
+                }
+                let lookup_table = match &lookup_table {
+                    Some(lookup_table) => Some(lookup_table.read().await.clone()),
+                    None => None,
+                };
+                let nb_markets = markets.len();
+                let message = build_message_fitting_packet(
+                    &mut ixs,
+                    &mut markets,
+                    &mut events,
+                    &config.program_id,
+                    |ixs| build_message(ixs, lookup_table.as_ref(), &fee_payer.pubkey(), blockhash),
                 );
+                if markets.len() < nb_markets {
+                    warn!(
+                        "{} markets did not fit in the transaction, cranking {markets:?}",
+                        nb_markets - markets.len()
+                    );
+                }
+                let tx = match message {
+                    Ok(message) => {
+                        sign_transaction(
+                            message,
+                            &fee_payer,
+                            &other_signers,
+                            identity.remote_signer(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                let tx = match tx {
+                    Ok(tx) => tx,
+                    Err(e) => {
+                        error!("cannot build transaction for {markets:?}: {e}");
+                        sender_stats.inc_dropped();
+                        continue;
+                    }
+                };
+
+                if let Some(validation_client) = &validation_client {
+                    if !validate_first_send(
+                        validation_client,
+                        &mut validated_markets,
+                        &markets,
+                        &tx,
+                    )
+                    .await
+                    {
+                        // the nonce was not used
+                        last_nonce = previous_nonce;
+                        sender_stats.inc_dropped();
+                        continue;
+                    }
+                }
 
                 let tx_send_record = TransactionSendRecord {
                     signature: tx.signatures[0],
                     sent_at: Utc::now(),
                     sent_slot: current_slot.load(Ordering::Acquire),
-                    market: Some(market),
+                    markets,
                     priority_fees: prioritization_fee,
                     user: None,
+                    built_at: Some(built_at),
+                    events: events.iter().map(|x| EventCounts::of(x)).collect(),
                 };
+                if let Some(retryable) = &retryable {
+                    let heap_states = sender_sink.heap_states(&tx_send_record.markets);
+                    retryable
+                        .lock()
+                        .unwrap()
+                        .insert(&tx, &tx_send_record, heap_states);
+                }
+                if let Some(pending) = &pending_verifications {
+                    let heap_states = sender_sink.heap_states(&tx_send_record.markets);
+                    pending
+                        .lock()
+                        .unwrap()
+                        .insert(tx_send_record.signature, heap_states);
+                }
 
-                let _ = tx_rx.send((tx, tx_send_record));
+                if tx_rx.send((tx, tx_send_record)).is_err() {
+                    warn!("transaction channel closed, dropping transaction");
+                    sender_stats.inc_dropped();
+                }
             }
         }
     });
 
     let event_heaps = markets.iter().map(|x| x.event_heap).collect_vec();
-    let markets = markets.to_vec();
+    // markets with their own endpoints are followed over a subscription per endpoint, the
+    // first one uses the global endpoints
+    let mut subscriptions = vec![(MarketEndpoints::default(), Arc::default())];
+    for endpoints in markets.iter().map(MarketEndpoints::of) {
+        if !subscriptions.iter().any(|(x, _)| *x == endpoints) {
+            subscriptions.push((endpoints, Arc::default()));
+        }
+    }
+    // the global endpoints are not subscribed to when every market has its own
+    let global_subscribed = subscriptions.len() == 1
+        || markets
+            .iter()
+            .any(|x| MarketEndpoints::of(x) == MarketEndpoints::default());
+    let markets_handle = MarketsHandle {
+        event_heaps: Arc::new(std::sync::RwLock::new(event_heaps)),
+        subscriptions: Arc::new(subscriptions),
+        global_subscribed,
+        sink: sink.clone(),
+    };
+    markets_handle.route_event_heaps(markets);
+
+    if config.once {
+        return (vec![t1], markets_handle);
+    }
+
+    let mut services = vec![t1];
+    services.extend(retry_task);
+    services.extend(verification_task);
+    if config.min_crank_interval.is_some() {
+        services.push(start_cooldown_recheck(sink.clone()));
+    }
+    for (index, (endpoints, matched_pubkeys)) in markets_handle.subscriptions.iter().enumerate() {
+        // markets later routed to the global endpoints are rejected by MarketsHandle::update
+        if index == 0 && !markets_handle.global_subscribed {
+            continue;
+        }
+        services.push(start_subscription(
+            config.program_id,
+            endpoints
+                .rpc_url
+                .clone()
+                .unwrap_or_else(|| config.rpc_url.clone()),
+            endpoints
+                .ws_url
+                .clone()
+                .unwrap_or_else(|| config.websocket_url.clone()),
+            matched_pubkeys.clone(),
+            sink.clone(),
+            // markets with their own endpoints keep their websocket
+            if index == 0 {
+                config.grpc_source.clone()
+            } else {
+                None
+            },
+        ));
+    }
+
+    (services, markets_handle)
+}
 
-    let t2 = tokio::spawn(async move {
+/// Processes the heaps whose updates were discarded while cooling down once their cooldown is
+/// over, a quiet heap would otherwise wait for its next update
+fn start_cooldown_recheck(sink: Arc<OpenbookV2CrankSink>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(next) = sink.process_cooled_down_updates().await {
+            tokio::time::sleep(next).await;
+        }
+    })
+}
+
+/// Follows the event heaps in `matched_pubkeys` over the given endpoints, or over gRPC
+/// when a gRPC source is given
+fn start_subscription(
+    program_id: Pubkey,
+    rpc_url: String,
+    websocket_url: String,
+    matched_pubkeys: MatchedPubkeys,
+    sink: Arc<OpenbookV2CrankSink>,
+    grpc_source: Option<GrpcSourceConfig>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let event_heaps = matched_pubkeys.read().unwrap().clone();
         let routes = vec![AccountWriteRoute {
-            matched_pubkeys: event_heaps.clone(),
-            sink: Arc::new(OpenbookV2CrankSink::new(
-                markets,
-                instruction_sender,
-                config.program_id,
-            )),
+            matched_pubkeys,
+            sink,
             timeout_interval: Duration::default(),
         }];
 
         let filter_config = FilterConfig {
-            program_ids: vec![config.program_id.to_string()],
+            program_ids: vec![program_id.to_string()],
             account_ids: event_heaps.iter().map(|x| x.to_string()).collect_vec(),
         };
 
@@ -117,26 +518,587 @@ pub fn start(
         let (account_write_queue_sender, slot_queue_sender) =
             init(routes).expect("filter initializes");
 
-        info!(
-            "start processing websocket events program_id={:?} ws_url={:?}",
-            config.program_id, config.websocket_url
+        match grpc_source {
+            #[cfg(feature = "grpc")]
+            Some(grpc_source) => {
+                info!(
+                    "start processing grpc events program_id={:?} grpc_url={:?}",
+                    program_id, grpc_source.url
+                );
+                crate::grpc_source::process_grpc_events(
+                    grpc_source,
+                    rpc_url,
+                    program_id,
+                    filter_config,
+                    account_write_queue_sender,
+                    slot_queue_sender,
+                )
+                .await;
+            }
+            _ => {
+                info!(
+                    "start processing websocket events program_id={:?} ws_url={:?}",
+                    program_id, websocket_url
+                );
+
+                process_events(
+                    &SourceConfig {
+                        dedup_queue_size: 0,
+                        rpc_http_url: rpc_url,
+                        program_id: program_id.to_string(),
+                        rpc_ws_url: websocket_url,
+                    },
+                    &filter_config,
+                    account_write_queue_sender,
+                    slot_queue_sender,
+                )
+                .await;
+            }
+        }
+    })
+}
+
+/// Simulates the transaction when one of its markets was never cranked successfully, returns
+/// whether it can be sent. Markets are validated by their first successful simulation.
+async fn validate_first_send(
+    client: &RpcClient,
+    validated_markets: &mut HashSet<Pubkey>,
+    markets: &[Pubkey],
+    tx: &VersionedTransaction,
+) -> bool {
+    if markets.iter().all(|x| validated_markets.contains(x)) {
+        return true;
+    }
+    let config = RpcSimulateTransactionConfig {
+        sig_verify: false,
+        commitment: Some(CommitmentConfig::processed()),
+        ..RpcSimulateTransactionConfig::default()
+    };
+    match client.simulate_transaction_with_config(tx, config).await {
+        Ok(response) => match response.value.err {
+            None => {
+                info!("first transaction for {markets:?} simulated successfully");
+                validated_markets.extend(markets);
+                true
+            }
+            Some(e) => {
+                error!(
+                    "first transaction for {markets:?} fails in simulation, not sending it: {e} logs {:?}",
+                    response.value.logs
+                );
+                false
+            }
+        },
+        Err(e) => {
+            warn!("cannot simulate the first transaction for {markets:?}, not sending it: {e}");
+            false
+        }
+    }
+}
+
+/// A v0 message referencing the lookup table when there is one, a legacy message otherwise
+fn build_message(
+    ixs: &[Instruction],
+    lookup_table: Option<&AddressLookupTableAccount>,
+    fee_payer: &Pubkey,
+    blockhash: Hash,
+) -> anyhow::Result<VersionedMessage> {
+    Ok(match lookup_table {
+        Some(lookup_table) => VersionedMessage::V0(v0::Message::try_compile(
+            fee_payer,
+            ixs,
+            std::slice::from_ref(lookup_table),
+            blockhash,
+        )?),
+        None => VersionedMessage::Legacy(Message::new_with_blockhash(
+            ixs,
+            Some(fee_payer),
+            &blockhash,
+        )),
+    })
+}
+
+/// Builds the message of the instructions, dropping the last event accounts of the last
+/// consume events instruction until the transaction fits in a packet. Consume events stops at
+/// the first event whose accounts are missing, so only the newest events are left out and
+/// the limit of the instruction is lowered to the events left. An instruction left without
+/// events is dropped with its market.
+fn build_message_fitting_packet(
+    ixs: &mut Vec<Instruction>,
+    markets: &mut Vec<Pubkey>,
+    events: &mut Vec<Vec<ConsumedEvent>>,
+    program_id: &Pubkey,
+    build: impl Fn(&[Instruction]) -> anyhow::Result<VersionedMessage>,
+) -> anyhow::Result<VersionedMessage> {
+    loop {
+        let message = build(ixs)?;
+        // signatures take the same room whatever their value
+        let transaction = VersionedTransaction {
+            signatures: vec![
+                Signature::default();
+                message.header().num_required_signatures as usize
+            ],
+            message,
+        };
+        if bincode::serialized_size(&transaction)? as usize <= PACKET_DATA_SIZE {
+            return Ok(transaction.message);
+        }
+        // the consume events instructions follow the order of the markets
+        let consume_events = ixs
+            .iter()
+            .positions(|ix| ix.program_id == *program_id)
+            .collect_vec();
+        let Some(&last) = consume_events.last() else {
+            anyhow::bail!("the transaction does not fit in a packet without any event");
+        };
+        ixs[last].accounts.pop();
+        let nb_event_accounts = ixs[last].accounts.len() - CONSUME_EVENTS_ACCOUNTS;
+        let last_events = &mut events[consume_events.len() - 1];
+        last_events.retain(|x| x.nb_event_accounts <= nb_event_accounts);
+        if last_events.is_empty() {
+            ixs.remove(last);
+            markets.truncate(consume_events.len() - 1);
+            events.truncate(consume_events.len() - 1);
+        } else {
+            ixs[last].data = openbook_v2::instruction::ConsumeEvents {
+                limit: last_events.len(),
+            }
+            .data();
+        }
+    }
+}
+
+/// The fee payer always signs, the crank authority and the nonce authority only when an
+/// instruction requires it, a remote crank authority is asked for its signature
+async fn sign_transaction(
+    message: VersionedMessage,
+    fee_payer: &Keypair,
+    other_signers: &[&Keypair],
+    remote_signer: Option<&RemoteSigner>,
+) -> anyhow::Result<VersionedTransaction> {
+    let message_data = message.serialize();
+    let num_required_signatures = message.header().num_required_signatures as usize;
+    let mut signatures = vec![];
+    for signer in &message.static_account_keys()[..num_required_signatures] {
+        let keypair = std::iter::once(fee_payer)
+            .chain(other_signers.iter().copied())
+            .find(|x| x.pubkey() == *signer);
+        let signature = match (keypair, remote_signer) {
+            (Some(keypair), _) => keypair.sign_message(&message_data),
+            (None, Some(remote_signer)) if remote_signer.pubkey() == *signer => {
+                remote_signer.sign_message(&message_data).await?
+            }
+            _ => anyhow::bail!("no signer for {signer}"),
+        };
+        signatures.push(signature);
+    }
+    Ok(VersionedTransaction {
+        signatures,
+        message,
+    })
+}
+
+// the oldest sent transactions are forgotten above this, they timed out long ago
+const MAX_RETRYABLE_TRANSACTIONS: usize = 10_000;
+// error of the transactions whose blockhash the validators do not know
+const BLOCKHASH_NOT_FOUND: &str = "Blockhash not found";
+
+/// Whether one of the heaps still starts with the events it held when the transaction was
+/// sent, unknown heaps are assumed to
+fn holds_same_events(sent: &[(Pubkey, HeapState)], current: &[(Pubkey, HeapState)]) -> bool {
+    let oldest_event = |x: &HeapState| x.seq_num.saturating_sub(x.len as u64);
+    sent.is_empty()
+        || sent.iter().any(|(market, sent)| {
+            current.iter().any(|(x, current)| {
+                x == market && current.len > 0 && oldest_event(current) == oldest_event(sent)
+            })
+        })
+}
+
+/// Sent transactions which can be signed again with a fresh blockhash, each one once
+#[derive(Default)]
+struct RetryableTransactions {
+    signatures: VecDeque<Signature>,
+    transactions: HashMap<Signature, RetryableTransaction>,
+}
+
+// the heaps of the markets as seen when the transaction was sent
+type RetryableTransaction = (
+    VersionedMessage,
+    TransactionSendRecord,
+    Vec<(Pubkey, HeapState)>,
+);
+
+impl RetryableTransactions {
+    fn insert(
+        &mut self,
+        tx: &VersionedTransaction,
+        record: &TransactionSendRecord,
+        heap_states: Vec<(Pubkey, HeapState)>,
+    ) {
+        if self.signatures.len() >= MAX_RETRYABLE_TRANSACTIONS {
+            if let Some(oldest) = self.signatures.pop_front() {
+                self.transactions.remove(&oldest);
+            }
+        }
+        self.signatures.push_back(record.signature);
+        self.transactions.insert(
+            record.signature,
+            (tx.message.clone(), record.clone(), heap_states),
         );
+    }
 
-        process_events(
-            &SourceConfig {
-                dedup_queue_size: 0,
-                rpc_http_url: config.rpc_url,
-                program_id: config.program_id.to_string(),
-                rpc_ws_url: config.websocket_url,
-            },
-            &filter_config,
-            account_write_queue_sender,
-            slot_queue_sender,
-        )
-        .await;
-    });
+    fn take(&mut self, signature: &Signature) -> Option<RetryableTransaction> {
+        self.transactions.remove(signature)
+    }
+}
+
+/// Signs again the transactions which can never land as their blockhash expired or was not
+/// found with the latest blockhash and resends them, as long as the heaps still hold the
+/// events they consume. Resent transactions are not tracked so each one is only retried once.
+#[allow(clippy::too_many_arguments)]
+fn start_expired_blockhash_retry(
+    retryable: Arc<Mutex<RetryableTransactions>>,
+    sink: Arc<OpenbookV2CrankSink>,
+    blockhash_not_found_sends: Option<BlockhashNotFoundSends>,
+    tx_confirm_records: broadcast::Receiver<TransactionConfirmRecord>,
+    blockhash: Arc<RwLock<Hash>>,
+    current_slot: Arc<AtomicU64>,
+    identity: CrankAuthority,
+    fee_payer: Keypair,
+    tx_rx: UnboundedSender<(VersionedTransaction, TransactionSendRecord)>,
+    stats: CrankStats,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tx_confirm_records = tx_confirm_records;
+        let other_signers = identity.keypair().into_iter().collect_vec();
+        loop {
+            let record = match tx_confirm_records.recv().await {
+                Ok(record) => record,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let Ok(signature) = Signature::from_str(&record.signature) else {
+                continue;
+            };
+            let rejected_at_send = blockhash_not_found_sends
+                .as_ref()
+                .is_some_and(|x| x.take(&signature));
+            // confirmed transactions are forgotten as well
+            let Some((mut message, send_record, heap_states)) =
+                retryable.lock().unwrap().take(&signature)
+            else {
+                continue;
+            };
+            let slot = current_slot.load(Ordering::Acquire);
+            // a transaction timing out with a valid blockhash may still land
+            let expired = slot.saturating_sub(record.sent_slot) > MAX_PROCESSING_AGE as u64;
+            let blockhash_not_found = record.error.as_deref() == Some(BLOCKHASH_NOT_FOUND);
+            if !blockhash_not_found && !(record.timed_out && (expired || rejected_at_send)) {
+                continue;
+            }
+            // another transaction consumed the events meanwhile, the next heap update
+            // builds a new instruction when events are left
+            let current_heap_states = sink.heap_states(&send_record.markets);
+            if !holds_same_events(&heap_states, &current_heap_states) {
+                debug!("events of {signature} already consumed, not resending it");
+                continue;
+            }
+            message.set_recent_blockhash(*blockhash.read().await);
+            let tx = match sign_transaction(
+                message,
+                &fee_payer,
+                &other_signers,
+                identity.remote_signer(),
+            )
+            .await
+            {
+                Ok(tx) => tx,
+                Err(e) => {
+                    error!("cannot sign again {signature} with a fresh blockhash: {e}");
+                    continue;
+                }
+            };
+            debug!(
+                "blockhash of {signature} expired, resending it as {}",
+                tx.signatures[0]
+            );
+            let send_record = TransactionSendRecord {
+                signature: tx.signatures[0],
+                sent_at: Utc::now(),
+                sent_slot: slot,
+                ..send_record
+            };
+            stats.inc_blockhash_retries();
+            if tx_rx.send((tx, send_record)).is_err() {
+                warn!("transaction channel closed, dropping transaction");
+                stats.inc_dropped();
+            }
+        }
+    })
+}
 
-    vec![t1, t2]
+// consume events admin, event heap and market, the event accounts follow
+const CONSUME_EVENTS_ACCOUNTS: usize = 3;
+const CONSUME_EVENTS_BASE_CU: u32 = 20_000;
+const CONSUME_EVENTS_CU_PER_ACCOUNT: u32 = 12_000;
+pub(crate) const MAX_COMPUTE_UNIT_LIMIT: u32 = 1_400_000;
+
+/// Estimated CU needed by consume events instructions, slightly over estimated as every
+/// account can be touched by several events
+fn estimated_compute_units(ixs: &[Instruction]) -> u32 {
+    ixs.iter()
+        .map(|ix| {
+            let event_accounts = ix.accounts.len().saturating_sub(CONSUME_EVENTS_ACCOUNTS) as u32;
+            CONSUME_EVENTS_BASE_CU + CONSUME_EVENTS_CU_PER_ACCOUNT * event_accounts
+        })
+        .sum::<u32>()
+        .min(MAX_COMPUTE_UNIT_LIMIT)
+}
+
+/// Consume events instructions of one or several markets sent in a single transaction
+struct ConsumeEventsBatch {
+    markets: Vec<MarketInstructions>,
+    /// a batch never references more accounts than a single market transaction would
+    max_accounts: usize,
+}
+
+impl ConsumeEventsBatch {
+    fn new(first: MarketInstructions, max_event_accounts: usize) -> Self {
+        Self {
+            markets: vec![first],
+            max_accounts: max_event_accounts + 3,
+        }
+    }
+
+    fn accounts<'a>(markets: impl Iterator<Item = &'a MarketInstructions>) -> HashSet<Pubkey> {
+        markets
+            .flat_map(|(_, ixs, _, _)| ixs.iter())
+            .flat_map(|ix| ix.accounts.iter().map(|meta| meta.pubkey))
+            .collect()
+    }
+
+    /// add the instructions of a market, giving them back if the batch is full
+    fn try_add(&mut self, next: MarketInstructions) -> Result<(), MarketInstructions> {
+        // a newer update of the same market supersedes the previous one
+        let others = self
+            .markets
+            .iter()
+            .filter(|(market, _, _, _)| *market != next.0);
+        let nb_accounts = Self::accounts(others.chain(std::iter::once(&next))).len();
+        if nb_accounts > self.max_accounts {
+            return Err(next);
+        }
+        self.markets.retain(|(market, _, _, _)| *market != next.0);
+        self.markets.push(next);
+        Ok(())
+    }
+
+    fn market_pks(&self) -> Vec<Pubkey> {
+        self.markets
+            .iter()
+            .map(|(market, _, _, _)| *market)
+            .collect()
+    }
+
+    /// CU limit of the batch, markets with a configured limit use it instead of the estimate
+    fn compute_unit_limit(&self, configured_limit: impl Fn(&Pubkey) -> Option<u32>) -> u32 {
+        self.markets
+            .iter()
+            .map(|(market, ixs, _, _)| {
+                configured_limit(market).unwrap_or_else(|| estimated_compute_units(ixs))
+            })
+            .sum::<u32>()
+            .min(MAX_COMPUTE_UNIT_LIMIT)
+    }
+
+    /// markets, instructions, when the oldest instruction was built and the events of each
+    /// market
+    fn into_parts(
+        self,
+    ) -> (
+        Vec<Pubkey>,
+        Vec<Instruction>,
+        Instant,
+        Vec<Vec<ConsumedEvent>>,
+    ) {
+        let mut markets = vec![];
+        let mut ixs = vec![];
+        let mut built_at = Instant::now();
+        let mut events = vec![];
+        for (market, market_ixs, market_built_at, market_events) in self.markets {
+            markets.push(market);
+            ixs.extend(market_ixs);
+            built_at = built_at.min(market_built_at);
+            events.push(market_events);
+        }
+        (markets, ixs, built_at, events)
+    }
+}
+
+type MatchedPubkeys = Arc<std::sync::RwLock<Vec<Pubkey>>>;
+
+/// Endpoints over which the event heap of a market is followed, the global ones when unset
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct MarketEndpoints {
+    rpc_url: Option<String>,
+    ws_url: Option<String>,
+}
+
+impl MarketEndpoints {
+    fn of(market: &MarketData) -> Self {
+        Self {
+            rpc_url: market.config.rpc_url.clone(),
+            ws_url: market.config.ws_url.clone(),
+        }
+    }
+}
+
+/// Handle to change the set of cranked markets while the crank is running
+#[derive(Clone)]
+pub struct MarketsHandle {
+    event_heaps: Arc<std::sync::RwLock<Vec<Pubkey>>>,
+    // event heaps routed from each subscription, the first one uses the global endpoints
+    subscriptions: Arc<Vec<(MarketEndpoints, MatchedPubkeys)>>,
+    global_subscribed: bool,
+    sink: Arc<OpenbookV2CrankSink>,
+}
+
+impl MarketsHandle {
+    /// subscriptions only exist for the endpoints known when the crank started, markets
+    /// moved to other endpoints later are followed over the global ones
+    fn route_event_heaps(&self, markets: &[MarketData]) {
+        let mut routed = vec![vec![]; self.subscriptions.len()];
+        for market in markets {
+            let index = self.subscription_index(market).unwrap_or_else(|| {
+                warn!(
+                    "endpoints of market {} were not subscribed to at start, following it over the global endpoints",
+                    market.market_pk
+                );
+                0
+            });
+            routed[index].push(market.event_heap);
+        }
+        for ((_, matched_pubkeys), event_heaps) in self.subscriptions.iter().zip(routed) {
+            *matched_pubkeys.write().unwrap() = event_heaps;
+        }
+    }
+
+    /// subscription following the endpoints of the market, None when they were not known at
+    /// start
+    fn subscription_index(&self, market: &MarketData) -> Option<usize> {
+        let endpoints = MarketEndpoints::of(market);
+        self.subscriptions.iter().position(|(x, _)| *x == endpoints)
+    }
+
+    /// event heaps and markets written by consume events
+    pub fn cranked_accounts(&self) -> Vec<Pubkey> {
+        self.sink.cranked_accounts()
+    }
+
+    /// markets currently cranked
+    pub fn markets(&self) -> Vec<MarketData> {
+        self.sink.markets()
+    }
+
+    /// Replaces the cranked markets, fails when a market would be followed over the global
+    /// endpoints while they are not subscribed to
+    pub fn update(&self, markets: &[MarketData]) -> anyhow::Result<()> {
+        if !self.global_subscribed {
+            if let Some(market) = markets
+                .iter()
+                .find(|x| self.subscription_index(x).unwrap_or(0) == 0)
+            {
+                anyhow::bail!(
+                    "market {} would be followed over the global endpoints, which are not subscribed to as every market had its own at start, restart the crank to crank it",
+                    market.market_pk
+                );
+            }
+        }
+        let new_event_heaps = markets.iter().map(|x| x.event_heap).collect_vec();
+        {
+            let old_event_heaps = self.event_heaps.read().unwrap();
+            let added = new_event_heaps
+                .iter()
+                .filter(|x| !old_event_heaps.contains(x))
+                .count();
+            let removed = old_event_heaps
+                .iter()
+                .filter(|x| !new_event_heaps.contains(x))
+                .count();
+            info!(
+                "updating markets added={added} removed={removed} total={}",
+                new_event_heaps.len()
+            );
+        }
+        // update the sink first so that it knows every heap the filter may route to it
+        self.sink.update_markets(markets);
+        self.route_event_heaps(markets);
+        *self.event_heaps.write().unwrap() = new_event_heaps;
+        Ok(())
+    }
+
+    /// Fetches every event heap once and queues consume events for those holding events,
+    /// returns the number of queued instructions
+    pub async fn crank_once(&self, client: &RpcClient) -> anyhow::Result<usize> {
+        let event_heaps = self.event_heaps.read().unwrap().clone();
+        let mut nb_queued = 0;
+        // getMultipleAccounts takes at most 100 accounts
+        for event_heaps in event_heaps.chunks(100) {
+            let response = client
+                .get_multiple_accounts_with_commitment(event_heaps, CommitmentConfig::confirmed())
+                .await?;
+            for (event_heap, account) in event_heaps.iter().zip(response.value) {
+                let Some(account) = account else {
+                    warn!("event heap {event_heap} not found");
+                    continue;
+                };
+                let account = AccountData {
+                    slot: response.context.slot,
+                    write_version: 0,
+                    account: account.into(),
+                };
+                match self.sink.process(event_heap, &account).await {
+                    Ok(()) => nb_queued += 1,
+                    Err(e) => debug!("not cranking event heap {event_heap}: {e}"),
+                }
+            }
+        }
+        Ok(nb_queued)
+    }
+
+    /// consume events instructions not yet picked up to be sent
+    pub fn pending_instructions(&self) -> usize {
+        self.sink.pending_instructions()
+    }
+
+    /// closes the heap depth records channel so that its writer finishes the file
+    pub fn close_heap_depth_records(&self) {
+        self.sink.close_heap_depth_records();
+    }
+
+    /// pauses or resumes cranking every market
+    pub fn set_paused(&self, paused: bool) {
+        self.sink.set_paused(paused);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.sink.is_paused()
+    }
+
+    /// holds back or resumes cranking every market while transactions drain
+    pub fn set_backpressure(&self, backpressure: bool) {
+        self.sink.set_backpressure(backpressure);
+    }
+
+    pub fn has_backpressure(&self) -> bool {
+        self.sink.has_backpressure()
+    }
+
+    /// stops cranking the market until `until`, or for good when unset
+    pub fn disable_market(&self, market: Pubkey, until: Option<Instant>) {
+        self.sink.disable_market(market, until);
+    }
 }
 
 /// Code copied from mango-feeds
@@ -155,7 +1117,7 @@ pub trait AccountWriteSink {
 
 #[derive(Clone)]
 pub struct AccountWriteRoute {
-    pub matched_pubkeys: Vec<Pubkey>,
+    pub matched_pubkeys: Arc<std::sync::RwLock<Vec<Pubkey>>>,
     pub sink: Arc<dyn AccountWriteSink + Send + Sync>,
     pub timeout_interval: Duration,
 }
@@ -168,6 +1130,23 @@ pub struct SourceConfig {
     pub rpc_ws_url: String,
 }
 
+/// Yellowstone gRPC endpoint streaming the event heaps instead of the websocket, requires
+/// the grpc feature
+#[derive(Clone)]
+pub struct GrpcSourceConfig {
+    pub url: String,
+    pub x_token: Option<String>,
+}
+
+impl std::fmt::Debug for GrpcSourceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GrpcSourceConfig")
+            .field("url", &self.url)
+            .field("x_token", &self.x_token.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FilterConfig {
     pub program_ids: Vec<String>,
@@ -201,7 +1180,12 @@ pub struct AccountWrite {
 }
 
 impl AccountWrite {
-    fn from(pubkey: Pubkey, slot: u64, write_version: u64, account: Account) -> AccountWrite {
+    pub(crate) fn from(
+        pubkey: Pubkey,
+        slot: u64,
+        write_version: u64,
+        account: Account,
+    ) -> AccountWrite {
         AccountWrite {
             pubkey,
             slot,
@@ -248,15 +1232,15 @@ pub fn init(
 
     let mut last_updated = HashMap::<String, AccountWriteRecord>::new();
 
-    let all_queue_pks: BTreeSet<Pubkey> = routes
-        .iter()
-        .flat_map(|r| r.matched_pubkeys.iter())
-        .copied()
-        .collect();
-
     // update handling thread, reads both slots and account updates
     tokio::spawn(async move {
         loop {
+            // matched pubkeys can change at runtime
+            let all_queue_pks: BTreeSet<Pubkey> = routes
+                .iter()
+                .flat_map(|r| r.matched_pubkeys.read().unwrap().clone())
+                .collect();
+
             tokio::select! {
                 Ok(account_write) = account_write_queue_receiver.recv() => {
                     if !all_queue_pks.contains(&account_write.pubkey) {
@@ -299,7 +1283,8 @@ pub fn init(
             }
 
             for route in routes.iter() {
-                for pk in route.matched_pubkeys.iter() {
+                let matched_pubkeys = route.matched_pubkeys.read().unwrap().clone();
+                for pk in matched_pubkeys.iter() {
                     match chain_data.account(pk) {
                         Ok(account_info) => {
                             let pk_b58 = pk.to_string();
@@ -644,6 +1629,12 @@ pub struct ChainData {
     account_bytes_stored: usize,
 }
 
+impl Default for ChainData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ChainData {
     pub fn new() -> Self {
         Self {
@@ -811,3 +1802,220 @@ impl ChainData {
             .ok_or_else(|| anyhow::anyhow!("account {} has no live data", pubkey))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{health::HealthStatus, markets::MarketConfig};
+    use solana_sdk::instruction::AccountMeta;
+
+    // a consume events instruction of a market referencing `nb_event_accounts` accounts
+    fn consume_events(nb_event_accounts: usize) -> Instruction {
+        let accounts = (0..CONSUME_EVENTS_ACCOUNTS + nb_event_accounts)
+            .map(|_| AccountMeta::new(Pubkey::new_unique(), false))
+            .collect();
+        let data = openbook_v2::instruction::ConsumeEvents {
+            limit: nb_event_accounts,
+        }
+        .data();
+        Instruction::new_with_bytes(openbook_v2::ID, &data, accounts)
+    }
+
+    // events alternating fills and outs, each one bringing `accounts_per_event` new accounts
+    fn events(nb_events: usize, accounts_per_event: usize) -> Vec<ConsumedEvent> {
+        (1..=nb_events)
+            .map(|i| ConsumedEvent {
+                fill: i % 2 == 1,
+                nb_event_accounts: i * accounts_per_event,
+            })
+            .collect()
+    }
+
+    fn consume_limit(ix: &Instruction) -> u64 {
+        u64::from_le_bytes(ix.data[8..16].try_into().unwrap())
+    }
+
+    fn legacy_message(ixs: &[Instruction], fee_payer: &Pubkey) -> anyhow::Result<VersionedMessage> {
+        build_message(ixs, None, fee_payer, Hash::default())
+    }
+
+    #[test]
+    fn drops_newest_event_accounts_until_packet_fits() {
+        let fee_payer = Pubkey::new_unique();
+        let first = consume_events(10);
+        let mut ixs = vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(200_000),
+            first.clone(),
+            consume_events(MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE),
+        ];
+        let mut markets = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut consumed = vec![events(10, 1), events(MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE, 1)];
+        let message = build_message_fitting_packet(
+            &mut ixs,
+            &mut markets,
+            &mut consumed,
+            &openbook_v2::ID,
+            |ixs| legacy_message(ixs, &fee_payer),
+        )
+        .unwrap();
+
+        let transaction = VersionedTransaction {
+            signatures: vec![Signature::default()],
+            message,
+        };
+        assert!(bincode::serialized_size(&transaction).unwrap() as usize <= PACKET_DATA_SIZE);
+        assert_eq!(ixs[1], first);
+        assert!(ixs[2].accounts.len() > CONSUME_EVENTS_ACCOUNTS);
+        assert!(
+            ixs[2].accounts.len() < CONSUME_EVENTS_ACCOUNTS + MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE
+        );
+        assert_eq!(markets.len(), 2);
+        // the events left out are neither consumed nor counted
+        let nb_event_accounts = ixs[2].accounts.len() - CONSUME_EVENTS_ACCOUNTS;
+        assert_eq!(consumed[0], events(10, 1));
+        assert_eq!(consumed[1], events(nb_event_accounts, 1));
+        assert_eq!(consume_limit(&ixs[2]), nb_event_accounts as u64);
+    }
+
+    #[test]
+    fn recounts_events_whose_accounts_are_left_out() {
+        let fee_payer = Pubkey::new_unique();
+        let mut ixs = vec![consume_events(MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE)];
+        let mut markets = vec![Pubkey::new_unique()];
+        let mut consumed = vec![events(MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE / 2, 2)];
+        build_message_fitting_packet(
+            &mut ixs,
+            &mut markets,
+            &mut consumed,
+            &openbook_v2::ID,
+            |ixs| legacy_message(ixs, &fee_payer),
+        )
+        .unwrap();
+
+        // an event missing one of its two accounts is not consumed
+        let nb_event_accounts = ixs[0].accounts.len() - CONSUME_EVENTS_ACCOUNTS;
+        let nb_events = nb_event_accounts / 2;
+        assert!(nb_events < MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE / 2);
+        assert_eq!(consumed, vec![events(nb_events, 2)]);
+        assert_eq!(consume_limit(&ixs[0]), nb_events as u64);
+        assert_eq!(
+            EventCounts::of(&consumed[0]),
+            EventCounts {
+                fill: (nb_events as u64).div_ceil(2),
+                out: nb_events as u64 / 2,
+            }
+        );
+    }
+
+    #[test]
+    fn matches_blockhash_not_found_error() {
+        use solana_sdk::transaction::TransactionError;
+        assert_eq!(
+            TransactionError::BlockhashNotFound.to_string(),
+            BLOCKHASH_NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn resends_only_while_heap_holds_same_events() {
+        let market = Pubkey::new_unique();
+        let heap = |seq_num, len| {
+            vec![(
+                market,
+                HeapState {
+                    event_heap: Pubkey::new_unique(),
+                    seq_num,
+                    len,
+                },
+            )]
+        };
+        let sent = heap(10, 4);
+        // new events were pushed behind the same oldest event
+        assert!(holds_same_events(&sent, &heap(12, 6)));
+        // the oldest events were consumed meanwhile
+        assert!(!holds_same_events(&sent, &heap(12, 2)));
+        assert!(!holds_same_events(&sent, &heap(10, 0)));
+        // the market is no longer cranked
+        assert!(!holds_same_events(&sent, &[]));
+        assert!(holds_same_events(&[], &[]));
+    }
+
+    #[test]
+    fn drops_market_without_event_account_left() {
+        let fee_payer = Pubkey::new_unique();
+        let mut ixs = vec![
+            consume_events(MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE),
+            consume_events(MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE),
+        ];
+        let mut markets = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        let first_market = markets[0];
+        let mut consumed = vec![
+            events(MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE, 1),
+            events(MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE, 1),
+        ];
+        build_message_fitting_packet(
+            &mut ixs,
+            &mut markets,
+            &mut consumed,
+            &openbook_v2::ID,
+            |ixs| legacy_message(ixs, &fee_payer),
+        )
+        .unwrap();
+        assert_eq!(ixs.len(), 1);
+        assert_eq!(markets, vec![first_market]);
+        assert_eq!(consumed.len(), 1);
+    }
+
+    fn market(ws_url: Option<&str>) -> MarketData {
+        MarketData {
+            market_pk: Pubkey::new_unique(),
+            event_heap: Pubkey::new_unique(),
+            admin: None,
+            config: MarketConfig {
+                ws_url: ws_url.map(str::to_string),
+                ..MarketConfig::default()
+            },
+        }
+    }
+
+    // a handle subscribed to the endpoints of the markets as the crank starts it
+    fn markets_handle(markets: &[MarketData]) -> MarketsHandle {
+        let mut subscriptions = vec![(MarketEndpoints::default(), MatchedPubkeys::default())];
+        for endpoints in markets.iter().map(MarketEndpoints::of) {
+            if !subscriptions.iter().any(|(x, _)| *x == endpoints) {
+                subscriptions.push((endpoints, MatchedPubkeys::default()));
+            }
+        }
+        let stats = CrankStats::new(None, None, HealthStatus::default(), Duration::ZERO, None);
+        let sink = OpenbookV2CrankSink::new(
+            markets.to_vec(),
+            bounded(1),
+            &KeeperConfig::default(),
+            stats,
+            None,
+        );
+        MarketsHandle {
+            event_heaps: Arc::default(),
+            global_subscribed: markets
+                .iter()
+                .any(|x| MarketEndpoints::of(x) == MarketEndpoints::default()),
+            subscriptions: Arc::new(subscriptions),
+            sink: Arc::new(sink),
+        }
+    }
+
+    #[test]
+    fn rejects_market_without_endpoints_when_global_ones_are_not_subscribed() {
+        let own = market(Some("ws://own"));
+        let handle = markets_handle(std::slice::from_ref(&own));
+        let error = handle.update(&[own.clone(), market(None)]).unwrap_err();
+        assert!(error.to_string().contains("global endpoints"));
+        assert_eq!(handle.markets().len(), 1);
+        handle
+            .update(&[own.clone(), market(Some("ws://own"))])
+            .unwrap();
+
+        let handle = markets_handle(&[own.clone(), market(None)]);
+        handle.update(&[own, market(None)]).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use crate::crank::{get_snapshot, AccountWrite, FilterConfig, GrpcSourceConfig, SlotUpdate};
+use futures::StreamExt;
+use log::*;
+use openbook_v2::state::EventHeap;
+use solana_sdk::{account::Account, commitment_config::CommitmentLevel, pubkey::Pubkey};
+use std::{collections::HashMap, time::Duration};
+use yellowstone_grpc_client::GeyserGrpcClient;
+use yellowstone_grpc_proto::prelude::{
+    subscribe_request_filter_accounts_filter::Filter, subscribe_update::UpdateOneof,
+    CommitmentLevel as GrpcCommitmentLevel, SubscribeRequest, SubscribeRequestFilterAccounts,
+    SubscribeRequestFilterAccountsFilter, SubscribeRequestFilterSlots, SubscribeUpdateAccount,
+    SubscribeUpdateSlot,
+};
+
+// the stream sends pings, silence for this long means the connection is gone
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Streams the event heaps of the program and the slots from a Yellowstone gRPC endpoint
+/// into the same queues as the websocket source, reconnecting whenever the stream stops
+pub async fn process_grpc_events(
+    config: GrpcSourceConfig,
+    rpc_http_url: String,
+    program_id: Pubkey,
+    filter_config: FilterConfig,
+    account_write_queue_sender: async_channel::Sender<AccountWrite>,
+    slot_queue_sender: async_channel::Sender<SlotUpdate>,
+) {
+    loop {
+        // updates are only streamed when an account changes, start from a snapshot
+        match get_snapshot(rpc_http_url.clone(), &filter_config).await {
+            Ok((slot, accounts)) => {
+                for (pubkey, account) in accounts {
+                    let (Ok(pubkey), Some(account)) = (pubkey.parse(), account) else {
+                        continue;
+                    };
+                    let Some(account) = account.decode::<Account>() else {
+                        continue;
+                    };
+                    let _ = account_write_queue_sender
+                        .send(AccountWrite::from(pubkey, slot, 0, account))
+                        .await;
+                }
+            }
+            Err(e) => error!("failed to fetch the event heaps snapshot: {e}"),
+        }
+
+        if let Err(e) = feed_grpc_data(
+            &config,
+            program_id,
+            &account_write_queue_sender,
+            &slot_queue_sender,
+        )
+        .await
+        {
+            warn!("grpc stream {} failed: {e}", config.url);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn feed_grpc_data(
+    config: &GrpcSourceConfig,
+    program_id: Pubkey,
+    account_write_queue_sender: &async_channel::Sender<AccountWrite>,
+    slot_queue_sender: &async_channel::Sender<SlotUpdate>,
+) -> anyhow::Result<()> {
+    let mut client = GeyserGrpcClient::connect(config.url.clone(), config.x_token.clone(), None)?;
+
+    // only the accounts of the program with the size of an event heap, the filter drops
+    // the heaps of markets which are not cranked
+    let event_heap_size = 8 + std::mem::size_of::<EventHeap>() as u64;
+    let request = SubscribeRequest {
+        accounts: HashMap::from([(
+            "event_heaps".to_string(),
+            SubscribeRequestFilterAccounts {
+                owner: vec![program_id.to_string()],
+                filters: vec![SubscribeRequestFilterAccountsFilter {
+                    filter: Some(Filter::Datasize(event_heap_size)),
+                }],
+                ..Default::default()
+            },
+        )]),
+        slots: HashMap::from([("slots".to_string(), SubscribeRequestFilterSlots::default())]),
+        commitment: Some(GrpcCommitmentLevel::Processed as i32),
+        ..Default::default()
+    };
+    let (_subscribe_tx, mut stream) = client.subscribe_with_request(Some(request)).await?;
+    info!("streaming event heaps of {program_id} from {}", config.url);
+
+    loop {
+        let update = match tokio::time::timeout(STREAM_TIMEOUT, stream.next()).await {
+            Ok(Some(update)) => update?,
+            Ok(None) => anyhow::bail!("stream closed"),
+            Err(_) => anyhow::bail!("no update for {}s", STREAM_TIMEOUT.as_secs()),
+        };
+        match update.update_oneof {
+            Some(UpdateOneof::Account(SubscribeUpdateAccount {
+                account: Some(account),
+                slot,
+                ..
+            })) => {
+                let (Ok(pubkey), Ok(owner)) = (
+                    Pubkey::try_from(account.pubkey.as_slice()),
+                    Pubkey::try_from(account.owner.as_slice()),
+                ) else {
+                    warn!("invalid account update from the grpc stream");
+                    continue;
+                };
+                let account_write = AccountWrite {
+                    pubkey,
+                    slot,
+                    write_version: account.write_version,
+                    lamports: account.lamports,
+                    owner,
+                    executable: account.executable,
+                    rent_epoch: account.rent_epoch,
+                    data: account.data,
+                    is_selected: true,
+                };
+                account_write_queue_sender.send(account_write).await?;
+            }
+            Some(UpdateOneof::Slot(SubscribeUpdateSlot {
+                slot,
+                parent,
+                status,
+                ..
+            })) => {
+                let status = match GrpcCommitmentLevel::from_i32(status) {
+                    Some(GrpcCommitmentLevel::Processed) => CommitmentLevel::Processed,
+                    Some(GrpcCommitmentLevel::Confirmed) => CommitmentLevel::Confirmed,
+                    Some(GrpcCommitmentLevel::Finalized) => CommitmentLevel::Finalized,
+                    None => continue,
+                };
+                slot_queue_sender
+                    .send(SlotUpdate {
+                        slot,
+                        parent,
+                        status,
+                    })
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::result_writer::RecentTransactions;
+use log::{debug, error, info};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+/// State of the services reported by the health endpoint
+#[derive(Clone, Debug, Default)]
+pub struct HealthStatus {
+    last_blockhash_update: Arc<Mutex<Option<Instant>>>,
+    // blockhash refreshes which failed since the last successful one
+    blockhash_refresh_failures: Arc<AtomicU64>,
+    confirmation_running: Arc<AtomicBool>,
+    markets_fetched: Arc<AtomicBool>,
+    // configuration the crank started with, served at /config
+    effective_config: Arc<Mutex<Option<String>>>,
+}
+
+impl HealthStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_blockhash_updated(&self) {
+        *self.last_blockhash_update.lock().unwrap() = Some(Instant::now());
+        self.blockhash_refresh_failures.store(0, Ordering::Relaxed);
+    }
+
+    /// returns the number of refreshes which failed in a row
+    pub fn inc_blockhash_refresh_failures(&self) -> u64 {
+        self.blockhash_refresh_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1
+    }
+
+    pub fn blockhash_refresh_failures(&self) -> u64 {
+        self.blockhash_refresh_failures.load(Ordering::Relaxed)
+    }
+
+    /// time since the last blockhash update, None before the first one
+    pub fn blockhash_age(&self) -> Option<Duration> {
+        self.last_blockhash_update
+            .lock()
+            .unwrap()
+            .map(|updated| updated.elapsed())
+    }
+
+    pub fn set_confirmation_running(&self, running: bool) {
+        self.confirmation_running.store(running, Ordering::Relaxed);
+    }
+
+    pub fn set_markets_fetched(&self) {
+        self.markets_fetched.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_effective_config(&self, effective_config: String) {
+        *self.effective_config.lock().unwrap() = Some(effective_config);
+    }
+
+    fn liveness(&self, max_blockhash_age: Duration) -> Result<(), &'static str> {
+        match self.blockhash_age() {
+            None => return Err("no blockhash yet"),
+            Some(age) if age > max_blockhash_age => return Err("blockhash is stale"),
+            _ => {}
+        }
+        if !self.confirmation_running.load(Ordering::Relaxed) {
+            return Err("confirmation service is not running");
+        }
+        Ok(())
+    }
+
+    fn readiness(&self) -> Result<(), &'static str> {
+        if self.markets_fetched.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err("markets not fetched yet")
+        }
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    status: HealthStatus,
+    max_blockhash_age: Duration,
+    recent_transactions: Option<RecentTransactions>,
+) -> anyhow::Result<()> {
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (code, content_type, body) = match path {
+        "/healthz" => match status.liveness(max_blockhash_age) {
+            Ok(()) => ("200 OK", "text/plain", "ok".to_string()),
+            Err(reason) => ("503 Service Unavailable", "text/plain", reason.to_string()),
+        },
+        "/readyz" => match status.readiness() {
+            Ok(()) => ("200 OK", "text/plain", "ok".to_string()),
+            Err(reason) => ("503 Service Unavailable", "text/plain", reason.to_string()),
+        },
+        "/config" => match status.effective_config.lock().unwrap().clone() {
+            Some(config) => ("200 OK", "text/plain", config),
+            None => (
+                "503 Service Unavailable",
+                "text/plain",
+                "crank not started yet".to_string(),
+            ),
+        },
+        "/recent-transactions" if recent_transactions.is_some() => (
+            "200 OK",
+            "application/json",
+            recent_transactions.unwrap().to_json(),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {code}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Serves /healthz, /readyz and /config on the given port, and /recent-transactions when recent
+/// transactions are kept
+pub fn start_health_server(
+    port: u16,
+    status: HealthStatus,
+    max_blockhash_age: Duration,
+    recent_transactions: Option<RecentTransactions>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("cannot bind health endpoint on port {} : {}", port, e);
+                return;
+            }
+        };
+        info!("serving health endpoint on port {}", port);
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!("health endpoint accept failed {}", e);
+                    continue;
+                }
+            };
+            let status = status.clone();
+            let recent_transactions = recent_transactions.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    handle_connection(stream, status, max_blockhash_age, recent_transactions).await
+                {
+                    debug!("health endpoint request failed {}", e);
+                }
+            });
+        }
+    })
+}
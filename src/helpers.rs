@@ -1,11 +1,23 @@
-use crate::{rpc_manager::RpcManager, states::TransactionSendRecord, tpu_manager::TpuManager};
-use log::{debug, info};
-use solana_client::nonblocking::rpc_client::RpcClient;
+use crate::{
+    health::HealthStatus, jito_manager::JitoManager, rebroadcast::TransactionResender,
+    rpc_manager::RpcManager, states::TransactionSendRecord, tpu_manager::TpuManager,
+};
+use futures::StreamExt;
+use log::{debug, info, warn};
+use solana_address_lookup_table_program::state::AddressLookupTable;
+use solana_client::{
+    nonblocking::{pubsub_client::PubsubClient, rpc_client::RpcClient},
+    nonce_utils,
+};
 use solana_program::hash::Hash;
-use solana_sdk::transaction::Transaction;
+use solana_sdk::{
+    address_lookup_table_account::AddressLookupTableAccount, bs58,
+    commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Keypair,
+    transaction::VersionedTransaction,
+};
 use std::{
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
     time::Duration,
@@ -16,6 +28,75 @@ use tokio::{
     time::Instant,
 };
 
+/// Path over which transactions are sent when no jito block engine is used
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SendMode {
+    Tpu,
+    Rpc,
+    /// send every transaction over TPU and over RPC
+    Both,
+}
+
+const REDACTED: &str = "***";
+
+/// Hides the password, the query values and the long path segments of a url, which is
+/// where rpc providers and databases take their credentials
+pub fn redact_url(url: &str) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        return REDACTED.to_string();
+    };
+    if parsed.password().is_some() {
+        let _ = parsed.set_password(Some(REDACTED));
+    }
+    let path = parsed.path_segments().map(|segments| {
+        segments
+            .map(|x| if x.len() >= 20 { REDACTED } else { x })
+            .collect::<Vec<_>>()
+            .join("/")
+    });
+    if let Some(path) = path {
+        parsed.set_path(&path);
+    }
+    if parsed.query().is_some() {
+        let query = parsed
+            .query_pairs()
+            .map(|(key, _)| format!("{key}={REDACTED}"))
+            .collect::<Vec<_>>()
+            .join("&");
+        parsed.set_query(Some(&query));
+    }
+    parsed.to_string()
+}
+
+/// Loads a keypair from `env:<VAR>` which holds the key, a file path, or the key itself.
+/// The key is a json byte array as written by solana-keygen or a base58 secret.
+pub async fn load_keypair(source: &str) -> anyhow::Result<Keypair> {
+    if let Some(var) = source.strip_prefix("env:") {
+        let key = std::env::var(var).map_err(|e| anyhow::anyhow!("cannot read {var}: {e}"))?;
+        return parse_keypair(&key);
+    }
+    if tokio::fs::try_exists(source).await.unwrap_or(false) {
+        let keypair_file = tokio::fs::read_to_string(source).await?;
+        return parse_keypair(&keypair_file);
+    }
+    parse_keypair(source)
+}
+
+fn parse_keypair(key: &str) -> anyhow::Result<Keypair> {
+    let key = key.trim();
+    let bytes = if key.starts_with('[') {
+        serde_json::from_str::<Vec<u8>>(key)?
+    } else {
+        bs58::decode(key)
+            .into_vec()
+            .map_err(|_| anyhow::anyhow!("keypair is neither a file, a json array nor base58"))?
+    };
+    Keypair::from_bytes(&bytes).map_err(|e| anyhow::anyhow!("invalid keypair: {e}"))
+}
+
+// a single failed refresh is common, several in a row point at a degrading rpc
+const BLOCKHASH_REFRESH_FAILURES_WARNING: u64 = 3;
+
 pub async fn get_new_latest_blockhash(client: Arc<RpcClient>, blockhash: &Hash) -> Option<Hash> {
     let start = Instant::now();
     while start.elapsed().as_secs() < 5 {
@@ -35,7 +116,9 @@ pub async fn get_new_latest_blockhash(client: Arc<RpcClient>, blockhash: &Hash)
 pub async fn poll_blockhash_and_slot(
     blockhash: Arc<RwLock<Hash>>,
     slot: &AtomicU64,
+    slot_subscribed: &AtomicBool,
     client: Arc<RpcClient>,
+    health: HealthStatus,
 ) {
     let mut blockhash_last_updated = Instant::now();
     //let mut last_error_log = Instant::now();
@@ -43,11 +126,14 @@ pub async fn poll_blockhash_and_slot(
         let client = client.clone();
         let old_blockhash = *blockhash.read().await;
 
-        match client.get_slot().await {
-            Ok(new_slot) => slot.store(new_slot, Ordering::Release),
-            Err(e) => {
-                info!("Failed to download slot: {}, skip", e);
-                continue;
+        // the slot subscription gives fresher slots while it is alive
+        if !slot_subscribed.load(Ordering::Relaxed) {
+            match client.get_slot().await {
+                Ok(new_slot) => slot.store(new_slot, Ordering::Release),
+                Err(e) => {
+                    info!("Failed to download slot: {}, skip", e);
+                    continue;
+                }
             }
         }
 
@@ -56,8 +142,15 @@ pub async fn poll_blockhash_and_slot(
                 *blockhash.write().await = new_blockhash;
             }
             blockhash_last_updated = Instant::now();
+            health.set_blockhash_updated();
         } else {
             log::error!("Error updating recent blockhash");
+            let failures = health.inc_blockhash_refresh_failures();
+            if failures >= BLOCKHASH_REFRESH_FAILURES_WARNING {
+                warn!(
+                    "blockhash refresh failed {failures} times in a row, the rpc may be degrading"
+                );
+            }
             if blockhash_last_updated.elapsed().as_secs() > 120 {
                 log::error!("Failed to update blockhash quitting task");
                 break;
@@ -71,15 +164,135 @@ pub async fn poll_blockhash_and_slot(
 pub fn start_blockhash_polling_service(
     blockhash: Arc<RwLock<Hash>>,
     current_slot: Arc<AtomicU64>,
+    slot_subscribed: Arc<AtomicBool>,
+    client: Arc<RpcClient>,
+    health: HealthStatus,
+) -> JoinHandle<()> {
+    health.set_blockhash_updated();
+    tokio::spawn(async move {
+        poll_blockhash_and_slot(
+            blockhash.clone(),
+            current_slot.as_ref(),
+            slot_subscribed.as_ref(),
+            client,
+            health,
+        )
+        .await;
+    })
+}
+
+/// Updates the current slot from slotSubscribe notifications, `slot_subscribed` is set
+/// while the subscription is alive so that the polling service only polls the slot as
+/// a fallback
+pub fn start_slot_subscription_service(
+    ws_url: String,
+    current_slot: Arc<AtomicU64>,
+    slot_subscribed: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match PubsubClient::new(&ws_url).await {
+                Ok(pubsub_client) => match pubsub_client.slot_subscribe().await {
+                    Ok((mut slots, unsubscribe)) => {
+                        info!("subscribed to slots on {}", ws_url);
+                        while let Some(slot_info) = slots.next().await {
+                            current_slot.fetch_max(slot_info.slot, Ordering::AcqRel);
+                            slot_subscribed.store(true, Ordering::Relaxed);
+                        }
+                        unsubscribe().await;
+                        warn!("slot subscription dropped, polling slots");
+                    }
+                    Err(e) => warn!("cannot subscribe to slots, polling slots: {}", e),
+                },
+                Err(e) => warn!("cannot connect to {}, polling slots: {}", ws_url, e),
+            }
+            slot_subscribed.store(false, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    })
+}
+
+pub async fn fetch_nonce(client: &RpcClient, nonce_account: &Pubkey) -> anyhow::Result<Hash> {
+    let account = nonce_utils::nonblocking::get_account_with_commitment(
+        client,
+        nonce_account,
+        CommitmentConfig::confirmed(),
+    )
+    .await?;
+    Ok(nonce_utils::nonblocking::data_from_account(&account)?.blockhash())
+}
+
+/// Keeps `nonce` set to the blockhash stored in the nonce account, which changes every time
+/// a transaction advances it
+pub fn start_nonce_polling_service(
+    client: Arc<RpcClient>,
+    nonce_account: Pubkey,
+    nonce: Arc<RwLock<Option<Hash>>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match fetch_nonce(&client, &nonce_account).await {
+                Ok(value) => *nonce.write().await = Some(value),
+                Err(e) => warn!("failed to fetch nonce account {}: {}", nonce_account, e),
+            }
+            tokio::time::sleep(Duration::from_millis(400)).await;
+        }
+    })
+}
+
+pub async fn fetch_lookup_table(
+    client: &RpcClient,
+    key: Pubkey,
+) -> anyhow::Result<AddressLookupTableAccount> {
+    let lookup_table = fetch_lookup_table_addresses(client, key).await?;
+    info!(
+        "using lookup table {key} with {} addresses",
+        lookup_table.addresses.len()
+    );
+    Ok(lookup_table)
+}
+
+async fn fetch_lookup_table_addresses(
+    client: &RpcClient,
+    key: Pubkey,
+) -> anyhow::Result<AddressLookupTableAccount> {
+    let account = client.get_account(&key).await?;
+    let lookup_table = AddressLookupTable::deserialize(&account.data)?;
+    Ok(AddressLookupTableAccount {
+        key,
+        addresses: lookup_table.addresses.to_vec(),
+    })
+}
+
+/// Keeps `lookup_table` in sync with the table on chain, addresses extended after the start
+/// are then referenced through it as well
+pub fn start_lookup_table_polling_service(
     client: Arc<RpcClient>,
+    lookup_table: Arc<RwLock<AddressLookupTableAccount>>,
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
-        poll_blockhash_and_slot(blockhash.clone(), current_slot.as_ref(), client).await;
+        let key = lookup_table.read().await.key;
+        loop {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            match fetch_lookup_table_addresses(&client, key).await {
+                Ok(value) => {
+                    let mut lookup_table = lookup_table.write().await;
+                    if value.addresses != lookup_table.addresses {
+                        info!(
+                            "lookup table {key} changed, now {} addresses",
+                            value.addresses.len()
+                        );
+                        *lookup_table = value;
+                    }
+                }
+                Err(e) => warn!("failed to fetch lookup table {}: {}", key, e),
+            }
+        }
     })
 }
 
 pub fn create_tpu_transaction_bridge(
-    tx_rx: UnboundedReceiver<(Transaction, TransactionSendRecord)>,
+    tx_rx: UnboundedReceiver<(VersionedTransaction, TransactionSendRecord)>,
     tpu_manager: Arc<TpuManager>,
     max_batch_size: usize,
     recv_timeout: Duration,
@@ -109,7 +322,7 @@ pub fn create_tpu_transaction_bridge(
             }
 
             // create async task that sends tranasctions over TPU
-            let transactions: Vec<(Transaction, TransactionSendRecord)> =
+            let transactions: Vec<(VersionedTransaction, TransactionSendRecord)> =
                 transactions.drain(..).collect();
             let tpu_manager = tpu_manager.clone();
             tokio::spawn(async move {
@@ -119,8 +332,54 @@ pub fn create_tpu_transaction_bridge(
     })
 }
 
+/// Sends batches over TPU like the TPU bridge and the same transactions over RPC, only the
+/// TPU manager emits send records so each transaction is confirmed once
+pub fn create_dual_transaction_bridge(
+    tx_rx: UnboundedReceiver<(VersionedTransaction, TransactionSendRecord)>,
+    tpu_manager: Arc<TpuManager>,
+    rpc_manager: Arc<RpcManager>,
+    max_batch_size: usize,
+    recv_timeout: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut transactions = Vec::with_capacity(max_batch_size);
+        let mut tx_rx = tx_rx;
+        loop {
+            if transactions.len() < max_batch_size {
+                match tokio::time::timeout(recv_timeout, tx_rx.recv()).await {
+                    Ok(Some(tx)) => {
+                        transactions.push(tx);
+                        continue;
+                    }
+                    Ok(None) => {
+                        // channel broken
+                        break;
+                    }
+                    Err(_) => {
+                        // timed out continue to send pending transactions
+                    }
+                }
+            }
+            if transactions.is_empty() {
+                continue;
+            }
+
+            let transactions = std::mem::take(&mut transactions);
+            let rpc_transactions = transactions.iter().map(|(tx, _)| tx.clone()).collect();
+            let tpu_manager = tpu_manager.clone();
+            tokio::spawn(async move {
+                tpu_manager.send_transaction_batch(&transactions).await;
+            });
+            let rpc_manager = rpc_manager.clone();
+            tokio::spawn(async move {
+                rpc_manager.resend_transactions(rpc_transactions).await;
+            });
+        }
+    })
+}
+
 pub fn create_rpc_transaction_bridge(
-    tx_rx: UnboundedReceiver<(Transaction, TransactionSendRecord)>,
+    tx_rx: UnboundedReceiver<(VersionedTransaction, TransactionSendRecord)>,
     rpc_manager: Arc<RpcManager>,
     recv_timeout: Duration,
 ) -> JoinHandle<()> {
@@ -146,3 +405,31 @@ pub fn create_rpc_transaction_bridge(
         }
     })
 }
+
+pub fn create_jito_transaction_bridge(
+    tx_rx: UnboundedReceiver<(VersionedTransaction, TransactionSendRecord)>,
+    jito_manager: Arc<JitoManager>,
+    recv_timeout: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tx_rx = tx_rx;
+        loop {
+            match tokio::time::timeout(recv_timeout, tx_rx.recv()).await {
+                Ok(Some((tx, record))) => {
+                    let jito_manager = jito_manager.clone();
+                    tokio::spawn(async move {
+                        jito_manager.send_transaction(&tx, record).await;
+                    });
+                    continue;
+                }
+                Ok(None) => {
+                    // channel broken
+                    break;
+                }
+                Err(_) => {
+                    // timed out continue to send pending transactions
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,120 @@
+use crate::rebroadcast::TransactionResender;
+use crate::states::TransactionSendRecord;
+use crate::stats::CrankStats;
+use async_trait::async_trait;
+use bincode::serialize;
+use log::{error, info, warn};
+use solana_sdk::{bs58, pubkey, pubkey::Pubkey, transaction::VersionedTransaction};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Tip accounts of the jito block engine, the tips are spread over them
+pub const JITO_TIP_ACCOUNTS: [Pubkey; 8] = [
+    pubkey!("96gYZGLnJYVFmbjzopPSU6QiEV5fGqZNyN9nmNhvrZU5"),
+    pubkey!("HFqU5x63VTqvQss8hp11i4wVV8bD44PvwucfZ2bU7gRe"),
+    pubkey!("Cw8CFyM9FkoMi7K7Crf6HNQqf4uEMzpKw6QNghXLvLkY"),
+    pubkey!("ADaUMid9yfUytqMBgopwjb2DTLSokTSzL1zt6iGPaS49"),
+    pubkey!("DfXygSm4jCyNCybVYYK6DwvWqjKee8pbDmJGcLWNDXjh"),
+    pubkey!("ADuUkR4vqLUMWXxW9gh6D6L8pMSawimctcNZ5pGwDcEt"),
+    pubkey!("DttWaMuVvTiduZRnguLF7jNxTgiMBZ1hyAumKUiL2KRL"),
+    pubkey!("3AVi9Tg9Uo68tJfuvoKvqKNWKkC5wPdSSdeBnizKZ6jT"),
+];
+
+static NEXT_TIP_ACCOUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn next_tip_account() -> Pubkey {
+    JITO_TIP_ACCOUNTS[NEXT_TIP_ACCOUNT.fetch_add(1, Ordering::Relaxed) % JITO_TIP_ACCOUNTS.len()]
+}
+
+/// Sends every transaction as a single transaction bundle to a jito block engine
+#[derive(Clone)]
+pub struct JitoManager {
+    http_client: reqwest::Client,
+    bundles_url: String,
+    tx_send_record: UnboundedSender<TransactionSendRecord>,
+    stats: CrankStats,
+}
+
+impl JitoManager {
+    pub fn new(
+        block_engine_url: &str,
+        tx_send_record: UnboundedSender<TransactionSendRecord>,
+        stats: CrankStats,
+    ) -> Self {
+        let bundles_url = format!("{}/api/v1/bundles", block_engine_url.trim_end_matches('/'));
+        info!("sending bundles to {}", bundles_url);
+        Self {
+            http_client: reqwest::Client::new(),
+            bundles_url,
+            tx_send_record,
+            stats,
+        }
+    }
+
+    pub async fn send_transaction(
+        &self,
+        transaction: &VersionedTransaction,
+        transaction_sent_record: TransactionSendRecord,
+    ) -> bool {
+        self.stats.inc_send();
+
+        let sent = self.tx_send_record.send(transaction_sent_record);
+        if let Err(e) = sent {
+            self.stats.inc_dropped();
+            warn!("sending error on channel : {}", e);
+        }
+
+        self.send_bundle(std::slice::from_ref(transaction)).await
+    }
+
+    async fn send_bundle(&self, transactions: &[VersionedTransaction]) -> bool {
+        let transactions = transactions
+            .iter()
+            .map(|tx| {
+                bs58::encode(serialize(tx).expect("serialization should succeed")).into_string()
+            })
+            .collect::<Vec<_>>();
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "sendBundle",
+            "params": [transactions],
+        });
+
+        let response = match self
+            .http_client
+            .post(&self.bundles_url)
+            .json(&request)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                error!("error sending bundle {}", e);
+                return false;
+            }
+        };
+        match response.json::<serde_json::Value>().await {
+            Ok(body) => {
+                if let Some(e) = body.get("error") {
+                    error!("bundle rejected by the block engine {}", e);
+                    return false;
+                }
+                true
+            }
+            Err(e) => {
+                error!("invalid block engine response {}", e);
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TransactionResender for JitoManager {
+    async fn resend_transactions(&self, transactions: Vec<VersionedTransaction>) {
+        for transaction in &transactions {
+            self.send_bundle(std::slice::from_ref(transaction)).await;
+        }
+    }
+}
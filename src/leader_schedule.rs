@@ -0,0 +1,93 @@
+use crate::stats::CrankStats;
+use log::{debug, warn};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{pubkey::Pubkey, slot_history::Slot};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::Instant};
+
+// getSlotLeaders returns at most 5000 leaders, refreshed well before they run out
+const FETCHED_SLOTS: u64 = 1000;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// a transaction sent this many slots before the leader slot reaches it through TPU in time
+const LEADER_LOOKAHEAD_SLOTS: u64 = 4;
+// send anyway after this delay so that markets are not starved by a schedule without any
+// leader including our transactions
+const MAX_LEADER_WAIT: Duration = Duration::from_secs(10);
+
+/// Leaders of the upcoming slots, fetched with getSlotLeaders
+#[derive(Clone, Debug, Default)]
+pub struct LeaderSchedule {
+    // first slot of the schedule and the leaders of the following slots
+    leaders: Arc<RwLock<(Slot, Vec<Pubkey>)>>,
+}
+
+impl LeaderSchedule {
+    /// leaders of the slots from `slot` to `slot + nb_slots` excluded, empty when the
+    /// schedule does not cover them
+    fn leaders(&self, slot: Slot, nb_slots: u64) -> Vec<Pubkey> {
+        let leaders = self.leaders.read().unwrap();
+        let (first_slot, leaders) = &*leaders;
+        let Some(start) = slot.checked_sub(*first_slot) else {
+            return vec![];
+        };
+        leaders
+            .iter()
+            .skip(start as usize)
+            .take(nb_slots as usize)
+            .copied()
+            .collect()
+    }
+
+    /// Waits until a leader which already included some of our transactions, so which we
+    /// reach over TPU, is about to produce blocks. Returns right away while no leader has
+    /// included any of our transactions, and after MAX_LEADER_WAIT at most.
+    pub async fn wait_for_reachable_leader(&self, current_slot: &AtomicU64, stats: &CrankStats) {
+        let reachable_leaders = stats.landed_leaders().await;
+        if reachable_leaders.is_empty() {
+            return;
+        }
+        let deadline = Instant::now() + MAX_LEADER_WAIT;
+        loop {
+            let slot = current_slot.load(Ordering::Relaxed);
+            if self
+                .leaders(slot, LEADER_LOOKAHEAD_SLOTS)
+                .iter()
+                .any(|x| reachable_leaders.contains(&x.to_string()))
+            {
+                return;
+            }
+            if Instant::now() >= deadline {
+                debug!("no reachable leader scheduled after slot {slot}, sending anyway");
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// Keeps the leader schedule of the next slots up to date
+pub fn start_leader_schedule_service(
+    rpc_client: Arc<RpcClient>,
+    current_slot: Arc<AtomicU64>,
+    leader_schedule: LeaderSchedule,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let slot = current_slot.load(Ordering::Relaxed);
+            match rpc_client.get_slot_leaders(slot, FETCHED_SLOTS).await {
+                Ok(leaders) => {
+                    debug!("fetched {} slot leaders from slot {slot}", leaders.len());
+                    *leader_schedule.leaders.write().unwrap() = (slot, leaders);
+                }
+                Err(e) => warn!("failed to fetch slot leaders from slot {slot}: {e}"),
+            }
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+        }
+    })
+}
@@ -0,0 +1,29 @@
+pub mod admin;
+pub mod blockhash_guard;
+pub mod circuit_breaker;
+pub mod confirmation_strategy;
+pub mod consumption_verifier;
+pub mod crank;
+#[cfg(feature = "grpc")]
+pub mod grpc_source;
+pub mod health;
+pub mod helpers;
+pub mod jito_manager;
+pub mod leader_schedule;
+pub mod market_limiter;
+pub mod markets;
+pub mod openbook_v2_sink;
+pub mod postgres_sink;
+pub mod priority_fees;
+pub mod rate_limiter;
+pub mod rebroadcast;
+pub mod remote_signer;
+pub mod result_writer;
+pub mod rpc_manager;
+mod runner;
+pub mod states;
+pub mod stats;
+pub mod statsd;
+pub mod tpu_manager;
+
+pub use runner::{Crank, CrankBuilder, CrankConfig};
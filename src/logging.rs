@@ -0,0 +1,54 @@
+use log::kv::{Error, Key, Value, VisitSource};
+use serde_json::{Map, Value as JsonValue};
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// one json object per line with level, target, timestamp, message and fields
+    Json,
+}
+
+struct JsonFields(Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = if let Some(x) = value.to_bool() {
+            JsonValue::from(x)
+        } else if let Some(x) = value.to_u64() {
+            JsonValue::from(x)
+        } else if let Some(x) = value.to_i64() {
+            JsonValue::from(x)
+        } else if let Some(x) = value.to_f64() {
+            JsonValue::from(x)
+        } else {
+            JsonValue::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Logs like `solana_logger::setup`, RUST_LOG defaulting to error, in the given format
+pub fn init_logger(format: LogFormat) {
+    match format {
+        LogFormat::Text => solana_logger::setup(),
+        LogFormat::Json => {
+            env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("error"))
+                .format(|buf, record| {
+                    let mut fields = JsonFields(Map::new());
+                    let _ = record.key_values().visit(&mut fields);
+                    let line = serde_json::json!({
+                        "timestamp": chrono::Utc::now().to_rfc3339(),
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                        "fields": fields.0,
+                    });
+                    writeln!(buf, "{line}")
+                })
+                .init();
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use crate::states::TransactionSendRecord;
+use itertools::Itertools;
+use log::warn;
+use solana_sdk::{pubkey::Pubkey, transaction::VersionedTransaction};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+type Transaction = (VersionedTransaction, TransactionSendRecord);
+
+/// Forwards every `interval` the transactions of at most `max_markets` distinct markets,
+/// the transactions of the other markets wait for the next interval. Markets are served in
+/// the order they started waiting so that none of them starves, and all the waiting
+/// transactions of a served market are forwarded together.
+pub fn start_market_limiter(
+    tx_rx: UnboundedReceiver<Transaction>,
+    tx_sx: UnboundedSender<Transaction>,
+    max_markets: usize,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tx_rx = tx_rx;
+        // batched transactions wait with the other transactions of the same markets
+        let mut waiting: HashMap<Vec<Pubkey>, Vec<Transaction>> = HashMap::new();
+        let mut order: VecDeque<Vec<Pubkey>> = VecDeque::new();
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                next = tx_rx.recv() => {
+                    let Some(next) = next else {
+                        break;
+                    };
+                    let markets = next.1.markets.iter().copied().sorted_unstable().collect_vec();
+                    let transactions = waiting.entry(markets.clone()).or_insert_with(|| {
+                        order.push_back(markets);
+                        vec![]
+                    });
+                    transactions.push(next);
+                }
+                _ = tick.tick() => {
+                    let mut served: HashSet<Pubkey> = HashSet::new();
+                    let mut skipped = VecDeque::new();
+                    while let Some(markets) = order.pop_front() {
+                        let new_markets = markets.iter().filter(|x| !served.contains(x)).count();
+                        // a batch of more markets than the limit is served alone
+                        if !served.is_empty() && served.len() + new_markets > max_markets {
+                            skipped.push_back(markets);
+                            continue;
+                        }
+                        served.extend(markets.iter().copied());
+                        for transaction in waiting.remove(&markets).unwrap_or_default() {
+                            if tx_sx.send(transaction).is_err() {
+                                warn!("transaction bridge channel closed");
+                                return;
+                            }
+                        }
+                    }
+                    // markets left waiting keep their place ahead of the new ones
+                    order = skipped;
+                }
+            }
+        }
+    })
+}
@@ -1,13 +1,293 @@
+use crate::crank::MAX_COMPUTE_UNIT_LIMIT;
+use anchor_lang::{AccountDeserialize, Discriminator};
+use chrono::NaiveTime;
+use itertools::Itertools;
+use openbook_v2::state::Market;
+use serde::Deserialize;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    nonblocking::rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, RpcFilterType},
+};
 use solana_program::pubkey::Pubkey;
+use std::{collections::HashMap, str::FromStr};
 
 #[derive(Clone, Debug)]
 pub struct MarketData {
     pub market_pk: Pubkey,
     pub event_heap: Pubkey,
     pub admin: Option<Pubkey>,
+    pub config: MarketConfig,
+}
+
+impl MarketData {
+    pub fn from_market(market_pk: Pubkey, market: &Market) -> Self {
+        Self {
+            market_pk,
+            event_heap: market.event_heap,
+            admin: market.consume_events_admin.into(),
+            config: MarketConfig::default(),
+        }
+    }
+}
+
+/// Per market overrides, unset values keep the global behavior
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MarketConfig {
+    /// crank when the event heap holds more than this many events
+    pub min_backlog: Option<usize>,
+    /// crank as soon as the event heap contains a fill
+    pub crank_on_any_fill: Option<bool>,
+    /// crank on fills only once they are worth this much, in price lots times base lots
+    pub min_fill_value: Option<u64>,
+    /// seconds after which an unconfirmed transaction cranking this market is marked as
+    /// timed out, only read when the crank starts
+    pub confirmation_timeout_secs: Option<u64>,
+    /// rpc endpoint the event heap is fetched from instead of the global one, only read
+    /// when the crank starts
+    pub rpc_url: Option<String>,
+    /// websocket endpoint the event heap is followed over instead of the global one, only
+    /// read when the crank starts
+    pub ws_url: Option<String>,
+    /// UTC time ranges like "08:00-17:30" during which the market is cranked, a range
+    /// ending before its start wraps past midnight. Always cranked when unset
+    pub schedule: Option<Vec<TimeRange>>,
+    /// compute unit limit of the consume events of this market instead of the estimate
+    /// from the number of event accounts
+    pub compute_unit_limit: Option<u32>,
+    /// compute unit price in micro lamports instead of the global, dynamic or adaptive one,
+    /// a batch of several markets pays the highest price of its markets
+    pub compute_unit_price: Option<u64>,
+}
+
+impl MarketConfig {
+    /// whether the schedule allows cranking at the given UTC time
+    pub fn is_scheduled(&self, time: NaiveTime) -> bool {
+        match &self.schedule {
+            Some(schedule) => schedule.iter().any(|x| x.contains(time)),
+            None => true,
+        }
+    }
+}
+
+/// UTC time range written as "HH:MM-HH:MM", the end is excluded
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeRange {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeRange {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for TimeRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parse = |x: &str| {
+            NaiveTime::parse_from_str(x.trim(), "%H:%M")
+                .map_err(|e| format!("invalid time range {value:?}: {e}"))
+        };
+        let (start, end) = value
+            .split_once('-')
+            .ok_or_else(|| format!("invalid time range {value:?}, expected HH:MM-HH:MM"))?;
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+/// Json config file with the per market overrides, keyed by market pubkey
+/// ```json
+/// { "markets": { "<market pubkey>": { "min_backlog": 10, "crank_on_any_fill": false, "min_fill_value": 1000, "confirmation_timeout_secs": 60, "rpc_url": "https://...", "ws_url": "wss://...", "schedule": ["08:00-17:30"], "compute_unit_limit": 200000, "compute_unit_price": 5000 } } }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MarketsConfig {
+    pub markets: HashMap<String, MarketConfig>,
+}
+
+impl MarketsConfig {
+    pub async fn load(path: &str) -> anyhow::Result<Self> {
+        let config = tokio::fs::read_to_string(path).await?;
+        let config: Self = serde_json::from_str(&config)?;
+        for (market, market_config) in &config.markets {
+            Pubkey::from_str(market)
+                .map_err(|e| anyhow::anyhow!("invalid market {market}: {e}"))?;
+            if let Some(limit) = market_config.compute_unit_limit {
+                anyhow::ensure!(
+                    limit > 0 && limit <= MAX_COMPUTE_UNIT_LIMIT,
+                    "compute_unit_limit of market {market} must be between 1 and {MAX_COMPUTE_UNIT_LIMIT}"
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn apply(&self, markets: &mut [MarketData]) {
+        for market in markets {
+            if let Some(config) = self.markets.get(&market.market_pk.to_string()) {
+                market.config = config.clone();
+            }
+        }
+    }
+}
+
+/// Parses market pubkeys given on the command line, invalid entries are logged with their
+/// index and skipped. A list none of whose entries is valid is an error, it would leave an
+/// empty allowlist which cranks every discovered market
+pub fn parse_markets(entries: &[String]) -> anyhow::Result<Vec<Pubkey>> {
+    let mut markets = vec![];
+    let mut nb_invalid = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        match Pubkey::from_str(entry.trim()) {
+            Ok(market) => markets.push(market),
+            Err(e) => {
+                log::warn!("--markets entry #{index} {entry:?} is not a valid market: {e}");
+                nb_invalid += 1;
+            }
+        }
+    }
+    if markets.is_empty() && nb_invalid > 0 {
+        anyhow::bail!("none of the {nb_invalid} --markets entries is valid");
+    }
+    Ok(markets)
+}
+
+/// Reads market pubkeys written one per line, `#` starts a comment, invalid lines are logged
+/// and skipped. A file listing markets none of which is valid is an error, it would leave an
+/// empty allowlist
+pub async fn load_markets_file(path: &str) -> anyhow::Result<Vec<Pubkey>> {
+    let content = tokio::fs::read_to_string(path).await?;
+    let mut markets = vec![];
+    let mut nb_invalid = 0;
+    for (index, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match Pubkey::from_str(line) {
+            Ok(market) => markets.push(market),
+            Err(e) => {
+                log::warn!("{path}:{}: invalid market {line:?}: {e}", index + 1);
+                nb_invalid += 1;
+            }
+        }
+    }
+    if markets.is_empty() && nb_invalid > 0 {
+        anyhow::bail!("none of the {nb_invalid} markets of {path} is valid");
+    }
+    Ok(markets)
 }
 
 #[derive(Clone, Debug)]
 pub struct Obv2Config {
     pub markets: Vec<MarketData>,
 }
+
+/// Fetches and deserializes the markets of the given pubkeys, or of every market of the
+/// program when discovering, each market once even when listed several times
+pub async fn load_markets(
+    rpc_client: &RpcClient,
+    program_id: &Pubkey,
+    market_pks: &[Pubkey],
+    discover: bool,
+    exclude: &[Pubkey],
+) -> anyhow::Result<Vec<MarketData>> {
+    let market_pks = market_pks.iter().copied().unique().collect_vec();
+    if discover {
+        discover_markets(rpc_client, program_id, &market_pks, exclude).await
+    } else {
+        fetch_markets(rpc_client, &market_pks).await
+    }
+}
+
+pub async fn fetch_markets(
+    rpc_client: &RpcClient,
+    market_pks: &[Pubkey],
+) -> anyhow::Result<Vec<MarketData>> {
+    let market_pks = market_pks.iter().copied().unique().collect_vec();
+    // getMultipleAccounts takes at most 100 accounts
+    let mut infos = vec![];
+    for market_pks in market_pks.chunks(100) {
+        infos.extend(rpc_client.get_multiple_accounts(market_pks).await?);
+    }
+
+    let mut markets = vec![];
+    for (pubkey, info) in market_pks.iter().zip(infos) {
+        let Some(info) = info else {
+            log::warn!("market {pubkey} not found, skipping it");
+            continue;
+        };
+        match Market::try_deserialize(&mut &info.data[..]) {
+            Ok(market) => markets.push(MarketData::from_market(*pubkey, &market)),
+            Err(e) => log::warn!("cannot deserialize market {pubkey}, skipping it: {e}"),
+        }
+    }
+    Ok(markets)
+}
+
+/// Find all markets owned by the program, optionally restricted to an allowlist
+/// and with the denylisted markets removed
+pub async fn discover_markets(
+    rpc_client: &RpcClient,
+    program_id: &Pubkey,
+    allowlist: &[Pubkey],
+    denylist: &[Pubkey],
+) -> anyhow::Result<Vec<MarketData>> {
+    let config = RpcProgramAccountsConfig {
+        filters: Some(vec![RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+            0,
+            &Market::DISCRIMINATOR,
+        ))]),
+        account_config: RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            ..RpcAccountInfoConfig::default()
+        },
+        with_context: None,
+    };
+    let accounts = rpc_client
+        .get_program_accounts_with_config(program_id, config)
+        .await?;
+
+    let markets = accounts
+        .iter()
+        .filter(|(pubkey, _)| allowlist.is_empty() || allowlist.contains(pubkey))
+        .filter(|(pubkey, _)| !denylist.contains(pubkey))
+        .filter_map(
+            |(pubkey, account)| match Market::try_deserialize(&mut &account.data[..]) {
+                Ok(market) => Some(MarketData::from_market(*pubkey, &market)),
+                Err(e) => {
+                    log::warn!("cannot deserialize market {pubkey}: {e}");
+                    None
+                }
+            },
+        )
+        .collect();
+    Ok(markets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_invalid_markets_unless_none_is_valid() {
+        let market = Pubkey::new_unique();
+        let entries = vec!["invalid".to_string(), market.to_string()];
+        assert_eq!(parse_markets(&entries).unwrap(), vec![market]);
+        assert!(parse_markets(&entries[..1]).is_err());
+        assert!(parse_markets(&[]).unwrap().is_empty());
+    }
+}
@@ -1,22 +1,73 @@
 use crate::{
-    crank::{AccountData, AccountWriteSink},
-    markets::MarketData,
+    crank::{AccountData, AccountWriteSink, KeeperConfig},
+    markets::{MarketConfig, MarketData},
+    priority_fees::AdaptiveFee,
+    states::{ConsumedEvent, HeapDepthRecord},
+    stats::CrankStats,
 };
 use anchor_lang::{AccountDeserialize, InstructionData, ToAccountMetas};
-use async_channel::Sender;
+use async_channel::{Receiver, Sender, TrySendError};
 use async_trait::async_trait;
 use bytemuck::cast_ref;
-use openbook_v2::state::{EventHeap, EventType, FillEvent, OutEvent};
+use chrono::Utc;
+use itertools::Itertools;
+use openbook_v2::state::{AnyEvent, EventHeap, EventType, FillEvent, OutEvent};
 use solana_program::{
     instruction::{AccountMeta, Instruction},
     pubkey::Pubkey,
 };
-use solana_sdk::account::ReadableAccount;
-use std::collections::{BTreeMap, HashSet};
+use solana_sdk::{
+    account::ReadableAccount, address_lookup_table_account::AddressLookupTableAccount,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
 
 const MAX_BACKLOG: usize = 2;
+// upper bound on the events gathered for one instruction, keeps compute usage in check,
+// the consume limit may lower the events actually consumed
 const MAX_EVENTS_PER_TX: usize = 50;
-const MAX_ACCS_PER_TX: usize = 24;
+// keeps a transaction without lookup table below the packet size
+pub const MAX_ACCS_PER_TX: usize = 24;
+// stays below the 64 account locks of a transaction
+pub const MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE: usize = 56;
+// an account key and its index in the instruction
+const ACCOUNT_BYTES: usize = 33;
+// an account index in the lookup table and in the instruction
+const LOOKUP_TABLE_ACCOUNT_BYTES: usize = 2;
+// room for the event accounts in a packet, as many accounts as without lookup table
+const EVENT_ACCOUNTS_PACKET_BYTES: usize = MAX_ACCS_PER_TX * ACCOUNT_BYTES;
+const BACKLOG_ALERT_INTERVAL: Duration = Duration::from_secs(60);
+// heap updates are logged at most once per interval for each heap
+const UPDATE_LOG_INTERVAL: Duration = Duration::from_secs(1);
+// a heap which cannot be deserialized fails on every update, it is warned about once per interval
+const DESERIALIZE_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Events the crank consumes, consume events stops at the first event it is not given the
+/// accounts of so only the oldest events of the selected type can be consumed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EventTypeFilter {
+    #[default]
+    All,
+    Fills,
+    Outs,
+}
+
+impl EventTypeFilter {
+    fn matches(&self, event: &AnyEvent) -> bool {
+        match self {
+            EventTypeFilter::All => true,
+            EventTypeFilter::Fills => event.event_type == EventType::Fill as u8,
+            EventTypeFilter::Outs => event.event_type == EventType::Out as u8,
+        }
+    }
+}
 
 pub trait ToAccountMetasWrapper {
     fn to_account_metas_wrapper(&self, program_id: Pubkey) -> Vec<AccountMeta>;
@@ -33,84 +84,688 @@ impl<T: ToAccountMetas> ToAccountMetasWrapper for T {
     }
 }
 
+/// Consume events instructions of a market, when they were built and the events they consume
+pub type MarketInstructions = (Pubkey, Vec<Instruction>, Instant, Vec<ConsumedEvent>);
+
+/// Event heap of a market as seen in its last update
+#[derive(Debug, Clone, Copy)]
+pub struct HeapState {
+    pub event_heap: Pubkey,
+    pub seq_num: u64,
+    pub len: usize,
+}
+
 pub struct OpenbookV2CrankSink {
-    instruction_sender: Sender<(Pubkey, Vec<Instruction>)>,
-    map_event_q_to_market: BTreeMap<Pubkey, Pubkey>,
+    instruction_sender: Sender<MarketInstructions>,
+    // set while cranking is paused, heap updates are still tracked
+    paused: AtomicBool,
+    // set while too many sent transactions are unconfirmed, cleared once they drained
+    backpressure: AtomicBool,
+    // takes the stale instructions out of a full queue
+    instruction_receiver: Receiver<MarketInstructions>,
+    // one sink at a time rearranges the full queue
+    queue_lock: Mutex<()>,
+    map_event_q_to_market: RwLock<BTreeMap<Pubkey, MarketData>>,
     program_id: Pubkey,
+    stats: CrankStats,
+    // taken on shutdown so that the heap depth writer sees the channel closed
+    heap_depth_sender: Mutex<Option<broadcast::Sender<HeapDepthRecord>>>,
+    backlog_alert_threshold: Option<usize>,
+    last_backlog_alerts: Mutex<HashMap<Pubkey, Instant>>,
+    last_update_logs: Mutex<HashMap<Pubkey, Instant>>,
+    last_deserialize_warnings: Mutex<HashMap<Pubkey, Instant>>,
+    // event heaps whose last update had a backlog
+    backlogged_heaps: Mutex<HashSet<Pubkey>>,
+    stuck_heap_updates: usize,
+    // last seq_num seen for each heap and for how many updates it did not change
+    seq_nums: Mutex<HashMap<Pubkey, (u64, usize)>>,
+    // last heap state seen for each market
+    heap_states: Mutex<HashMap<Pubkey, HeapState>>,
+    max_accounts: usize,
+    // accounts of the table take less room in a transaction
+    lookup_table: Option<Arc<tokio::sync::RwLock<AddressLookupTableAccount>>>,
+    event_types: EventTypeFilter,
+    consume_limit: Option<usize>,
+    min_fill_value: Option<u64>,
+    min_crank_interval: Option<Duration>,
+    // heaps holding events of these accounts are cranked regardless of the throttles
+    priority_owners: HashSet<Pubkey>,
+    // when the last consume events instruction of each heap was sent
+    last_cranks: Mutex<HashMap<Pubkey, Instant>>,
+    // last update of each heap discarded while cooling down, processed again once it is over
+    cooled_down_updates: Mutex<HashMap<Pubkey, AccountData>>,
+    adaptive_fee: Option<AdaptiveFee>,
+    once: bool,
+    // print the events and the crank decision of every update instead of cranking
+    debug: bool,
+    // markets disabled after failing repeatedly, until they are probed again if ever
+    disabled_markets: Mutex<HashMap<Pubkey, Option<Instant>>>,
 }
 
 impl OpenbookV2CrankSink {
     pub fn new(
         markets: Vec<MarketData>,
-        instruction_sender: Sender<(Pubkey, Vec<Instruction>)>,
-        program_id: Pubkey,
+        (instruction_sender, instruction_receiver): (
+            Sender<MarketInstructions>,
+            Receiver<MarketInstructions>,
+        ),
+        config: &KeeperConfig,
+        stats: CrankStats,
+        heap_depth_sender: Option<broadcast::Sender<HeapDepthRecord>>,
     ) -> Self {
-        let mut map_event_q_to_market = BTreeMap::new();
-        for market in &markets {
-            map_event_q_to_market.insert(market.event_heap, market.market_pk);
-        }
+        stats.set_markets_with_backlog(0, markets.len());
         Self {
             instruction_sender,
-            map_event_q_to_market,
-            program_id,
+            instruction_receiver,
+            queue_lock: Mutex::new(()),
+            paused: AtomicBool::new(false),
+            backpressure: AtomicBool::new(false),
+            map_event_q_to_market: RwLock::new(Self::event_q_to_market(&markets)),
+            program_id: config.program_id,
+            stats,
+            heap_depth_sender: Mutex::new(heap_depth_sender),
+            backlog_alert_threshold: config.backlog_alert_threshold,
+            last_backlog_alerts: Mutex::new(HashMap::new()),
+            last_update_logs: Mutex::new(HashMap::new()),
+            last_deserialize_warnings: Mutex::new(HashMap::new()),
+            backlogged_heaps: Mutex::new(HashSet::new()),
+            stuck_heap_updates: config.stuck_heap_updates,
+            seq_nums: Mutex::new(HashMap::new()),
+            heap_states: Mutex::new(HashMap::new()),
+            max_accounts: config.max_event_accounts(),
+            lookup_table: config.lookup_table.clone(),
+            event_types: config.event_types,
+            consume_limit: config.consume_limit,
+            min_fill_value: config.min_fill_value,
+            min_crank_interval: config.min_crank_interval,
+            priority_owners: config.priority_owners.clone(),
+            last_cranks: Mutex::new(HashMap::new()),
+            cooled_down_updates: Mutex::new(HashMap::new()),
+            adaptive_fee: config.adaptive_fee.clone(),
+            once: config.once,
+            debug: config.debug_events,
+            disabled_markets: Mutex::new(HashMap::new()),
         }
     }
-}
 
-#[async_trait]
-impl AccountWriteSink for OpenbookV2CrankSink {
-    async fn process(
+    // warns at most once per BACKLOG_ALERT_INTERVAL for each heap
+    fn alert_on_backlog(&self, event_heap: &Pubkey, market: &Pubkey, len: usize) {
+        let Some(threshold) = self.backlog_alert_threshold else {
+            return;
+        };
+        if len < threshold {
+            return;
+        }
+        let mut last_alerts = self.last_backlog_alerts.lock().unwrap();
+        if let Some(last_alert) = last_alerts.get(event_heap) {
+            if last_alert.elapsed() < BACKLOG_ALERT_INTERVAL {
+                return;
+            }
+        }
+        last_alerts.insert(*event_heap, Instant::now());
+        log::warn!(
+            "market {market} is falling behind, event heap {event_heap} has {len} events (threshold {threshold})"
+        );
+        self.stats.inc_backlog_alerts();
+    }
+
+    // at high update rates logging every update floods the logs, the trend is enough
+    fn should_log_update(&self, event_heap: &Pubkey) -> bool {
+        if !log::log_enabled!(log::Level::Debug) {
+            return false;
+        }
+        let mut last_logs = self.last_update_logs.lock().unwrap();
+        if last_logs
+            .get(event_heap)
+            .is_some_and(|x| x.elapsed() < UPDATE_LOG_INTERVAL)
+        {
+            return false;
+        }
+        last_logs.insert(*event_heap, Instant::now());
+        true
+    }
+
+    fn should_warn_deserialize_error(&self, event_heap: &Pubkey) -> bool {
+        let mut last_warnings = self.last_deserialize_warnings.lock().unwrap();
+        if last_warnings
+            .get(event_heap)
+            .is_some_and(|x| x.elapsed() < DESERIALIZE_WARNING_INTERVAL)
+        {
+            return false;
+        }
+        last_warnings.insert(*event_heap, Instant::now());
+        true
+    }
+
+    // a heap keeping its backlog and seq_num means our consume events keep failing
+    fn check_stuck_heap(
         &self,
-        pk: &solana_sdk::pubkey::Pubkey,
-        account: &AccountData,
-    ) -> Result<(), String> {
+        event_heap: &Pubkey,
+        market: &Pubkey,
+        seq_num: u64,
+        has_backlog: bool,
+    ) {
+        if self.stuck_heap_updates == 0 {
+            return;
+        }
+        let mut seq_nums = self.seq_nums.lock().unwrap();
+        let (last_seq_num, unchanged) = seq_nums.entry(*event_heap).or_insert((seq_num, 0));
+        if !has_backlog || *last_seq_num != seq_num {
+            *last_seq_num = seq_num;
+            *unchanged = 0;
+            return;
+        }
+        *unchanged += 1;
+        // escalate once every stuck_heap_updates updates while it stays stuck
+        if *unchanged % self.stuck_heap_updates == 0 {
+            log::error!(
+                "event heap {event_heap} of market {market} is stuck at seq_num {seq_num} for {unchanged} updates, recent errors: {:?}",
+                self.stats.recent_errors(market)
+            );
+        }
+    }
+
+    pub fn update_markets(&self, markets: &[MarketData]) {
+        let map_event_q_to_market = Self::event_q_to_market(markets);
+        let mut backlogged_heaps = self.backlogged_heaps.lock().unwrap();
+        backlogged_heaps.retain(|x| map_event_q_to_market.contains_key(x));
+        self.stats
+            .set_markets_with_backlog(backlogged_heaps.len(), map_event_q_to_market.len());
+        *self.map_event_q_to_market.write().unwrap() = map_event_q_to_market;
+    }
+
+    fn is_cooling_down(&self, event_heap: &Pubkey) -> bool {
+        let Some(min_crank_interval) = self.min_crank_interval else {
+            return false;
+        };
+        self.last_cranks
+            .lock()
+            .unwrap()
+            .get(event_heap)
+            .is_some_and(|x| x.elapsed() < min_crank_interval)
+    }
+
+    /// Processes again the last update of each heap discarded while cooling down once its
+    /// cooldown is over, returns how long until the next cooldown is over or None when
+    /// markets are not throttled
+    pub async fn process_cooled_down_updates(&self) -> Option<Duration> {
+        let min_crank_interval = self.min_crank_interval?;
+        let mut next = min_crank_interval;
+        let mut cooled_down = vec![];
+        {
+            let last_cranks = self.last_cranks.lock().unwrap();
+            self.cooled_down_updates
+                .lock()
+                .unwrap()
+                .retain(|event_heap, account| {
+                    let left = last_cranks.get(event_heap).map_or(Duration::ZERO, |x| {
+                        min_crank_interval.saturating_sub(x.elapsed())
+                    });
+                    if left.is_zero() {
+                        cooled_down.push((*event_heap, account.clone()));
+                        return false;
+                    }
+                    next = next.min(left);
+                    true
+                });
+        }
+        for (event_heap, account) in cooled_down {
+            if let Err(reason) = self.process(&event_heap, &account).await {
+                log::debug!("event heap {event_heap} not cranked after its cooldown: {reason}");
+            }
+        }
+        Some(next)
+    }
+
+    fn update_backlog(&self, event_heap: &Pubkey, has_backlog: bool) {
+        let mut backlogged_heaps = self.backlogged_heaps.lock().unwrap();
+        let changed = if has_backlog {
+            backlogged_heaps.insert(*event_heap)
+        } else {
+            backlogged_heaps.remove(event_heap)
+        };
+        if changed {
+            let num_markets = self.map_event_q_to_market.read().unwrap().len();
+            self.stats
+                .set_markets_with_backlog(backlogged_heaps.len(), num_markets);
+        }
+    }
+
+    /// Queues the instruction of a market. When the queue is full, the queued instruction of
+    /// the same market is replaced as it consumes an older state of its heap, or the new one
+    /// is dropped with an error when the market has none queued, the next update of its heap
+    /// queues it again
+    async fn queue_instruction(&self, instruction: MarketInstructions) -> Result<(), String> {
+        // a single pass must crank every heap
+        if self.once {
+            return self
+                .instruction_sender
+                .send(instruction)
+                .await
+                .map_err(|e| e.to_string());
+        }
+        let instruction = match self.instruction_sender.try_send(instruction) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(_)) => return Err("instruction channel closed".into()),
+            Err(TrySendError::Full(instruction)) => instruction,
+        };
+        let mut overflow = vec![];
+        let replaced = {
+            let _queue_lock = self.queue_lock.lock().unwrap();
+            let mut queued = std::iter::from_fn(|| self.instruction_receiver.try_recv().ok())
+                .collect::<VecDeque<_>>();
+            // the instructions of the other markets are never dropped for this one
+            let replaced = match queued.iter_mut().find(|x| x.0 == instruction.0) {
+                Some(stale) => {
+                    *stale = instruction;
+                    self.stats.inc_stale_instructions();
+                    true
+                }
+                None => false,
+            };
+            for instruction in queued {
+                match self.instruction_sender.try_send(instruction) {
+                    Ok(()) => {}
+                    Err(TrySendError::Closed(_)) => return Err("instruction channel closed".into()),
+                    // other markets were queued meanwhile
+                    Err(TrySendError::Full(instruction)) => overflow.push(instruction),
+                }
+            }
+            replaced
+        };
+        // the lock is released before waiting for the sender to drain the queue
+        for instruction in overflow {
+            self.instruction_sender
+                .send(instruction)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        if !replaced {
+            return Err("instruction queue full".into());
+        }
+        Ok(())
+    }
+
+    /// closes the heap depth records channel, later updates are no longer recorded
+    pub fn close_heap_depth_records(&self) {
+        self.heap_depth_sender.lock().unwrap().take();
+    }
+
+    /// stops queuing consume events until resumed, the transactions already sent are still
+    /// confirmed
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        self.stats.set_paused(paused);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_backpressure(&self, backpressure: bool) {
+        self.backpressure.store(backpressure, Ordering::Relaxed);
+        self.stats.set_backpressure(backpressure);
+    }
+
+    pub fn has_backpressure(&self) -> bool {
+        self.backpressure.load(Ordering::Relaxed)
+    }
+
+    pub fn pending_instructions(&self) -> usize {
+        self.instruction_sender.len()
+    }
+
+    /// stops cranking the market until `until`, or for good when unset
+    pub fn disable_market(&self, market: Pubkey, until: Option<Instant>) {
+        self.disabled_markets.lock().unwrap().insert(market, until);
+    }
+
+    // a market whose disable period is over is cranked again, a new failure disables it back
+    fn is_market_disabled(&self, market: &Pubkey) -> bool {
+        let mut disabled_markets = self.disabled_markets.lock().unwrap();
+        match disabled_markets.get(market) {
+            None => false,
+            Some(Some(until)) if *until <= Instant::now() => {
+                log::info!("probing market {market} again after its failures");
+                disabled_markets.remove(market);
+                false
+            }
+            Some(_) => true,
+        }
+    }
+
+    pub fn markets(&self) -> Vec<MarketData> {
+        self.map_event_q_to_market
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// last heap state seen of the given markets, the markets never seen are left out
+    pub fn heap_states(&self, market_pks: &[Pubkey]) -> Vec<(Pubkey, HeapState)> {
+        let heap_states = self.heap_states.lock().unwrap();
+        market_pks
+            .iter()
+            .filter_map(|x| heap_states.get(x).map(|state| (*x, *state)))
+            .collect()
+    }
+
+    /// configs of the given markets which are still cranked, by market
+    pub fn market_configs(&self, market_pks: &[Pubkey]) -> HashMap<Pubkey, MarketConfig> {
+        self.map_event_q_to_market
+            .read()
+            .unwrap()
+            .values()
+            .filter(|x| market_pks.contains(&x.market_pk))
+            .map(|x| (x.market_pk, x.config.clone()))
+            .collect()
+    }
+
+    pub fn cranked_accounts(&self) -> Vec<Pubkey> {
+        self.map_event_q_to_market
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|(event_heap, market)| [*event_heap, market.market_pk])
+            .collect()
+    }
+
+    fn event_q_to_market(markets: &[MarketData]) -> BTreeMap<Pubkey, MarketData> {
+        markets
+            .iter()
+            .map(|market| (market.event_heap, market.clone()))
+            .collect()
+    }
+}
+
+/// Value of the fill events in price lots times base lots
+fn fill_value<'a>(events: impl Iterator<Item = &'a AnyEvent>) -> u128 {
+    events
+        .filter(|e| e.event_type == EventType::Fill as u8)
+        .map(|e| {
+            let fill: &FillEvent = cast_ref(e);
+            (fill.price as i128 * fill.quantity as i128).unsigned_abs()
+        })
+        .sum()
+}
+
+/// One line with the type, sequence number, accounts and amounts of an event
+fn describe_event(event: &AnyEvent) -> String {
+    match EventType::try_from(event.event_type) {
+        Ok(EventType::Fill) => {
+            let fill: &FillEvent = cast_ref(event);
+            format!(
+                "fill seq_num={} maker={} taker={} price={} quantity={}",
+                fill.market_seq_num, fill.maker, fill.taker, fill.price, fill.quantity
+            )
+        }
+        Ok(EventType::Out) => {
+            let out: &OutEvent = cast_ref(event);
+            format!(
+                "out seq_num={} owner={} quantity={}",
+                out.seq_num, out.owner, out.quantity
+            )
+        }
+        Err(_) => format!("unknown event type {}", event.event_type),
+    }
+}
+
+/// Accounts consume events writes to for the event, None for an unknown event type
+fn event_accounts(event: &AnyEvent) -> Option<Vec<Pubkey>> {
+    match EventType::try_from(event.event_type) {
+        Ok(EventType::Fill) => {
+            let fill: &FillEvent = cast_ref(event);
+            Some(vec![fill.maker, fill.taker])
+        }
+        Ok(EventType::Out) => {
+            let out: &OutEvent = cast_ref(event);
+            Some(vec![out.owner])
+        }
+        Err(_) => None,
+    }
+}
+
+/// Accounts needed to consume the events of a heap, oldest event first.
+///
+/// `EventHeap::iter` walks the used list from its head which is the oldest event, and
+/// consume_events processes events in that same order, stopping at the first event whose
+/// accounts were not passed. Accounts are therefore gathered in heap order and gathering
+/// stops at the first event which does not fit, as accounts of newer events could not be
+/// used anyway. An event only counts the accounts not gathered yet, so events of makers and
+/// takers already present keep being taken once the account limit is reached. Gathering also
+/// stops before the accounts outgrow the room left in a packet, `account_bytes` is what an
+/// account takes in the transaction. Returns the accounts and the number of events they allow
+/// to consume.
+pub fn accounts_for_oldest_events<'a>(
+    events: impl Iterator<Item = &'a AnyEvent>,
+    max_accounts: usize,
+    account_bytes: impl Fn(&Pubkey) -> usize,
+) -> (Vec<Pubkey>, usize) {
+    let mut accounts: Vec<Pubkey> = vec![];
+    let mut bytes = 0;
+    let mut nb_events = 0;
+    for event in events.take(MAX_EVENTS_PER_TX) {
+        // unknown event, the program would not be able to consume it either
+        let Some(event_accounts) = event_accounts(event) else {
+            break;
+        };
+        let mut new_accounts = event_accounts
+            .into_iter()
+            .filter(|x| !accounts.contains(x))
+            .collect_vec();
+        new_accounts.dedup();
+        let new_bytes = new_accounts.iter().map(&account_bytes).sum::<usize>();
+        if accounts.len() + new_accounts.len() > max_accounts
+            || bytes + new_bytes > EVENT_ACCOUNTS_PACKET_BYTES
+        {
+            break;
+        }
+        accounts.extend(new_accounts);
+        bytes += new_bytes;
+        nb_events += 1;
+    }
+    (accounts, nb_events)
+}
+
+/// Event accounts which fit in a consume events instruction, the consume events admin of a
+/// market takes the place of one event account so that the transaction keeps the same
+/// number of accounts
+pub fn event_accounts_budget(max_accounts: usize, admin: Option<Pubkey>) -> usize {
+    max_accounts - usize::from(admin.is_some())
+}
+
+impl OpenbookV2CrankSink {
+    async fn process_event_heap(&self, pk: &Pubkey, account: &AccountData) -> Result<(), String> {
+        let received_at = Instant::now();
+        // superseded by this update
+        self.cooled_down_updates.lock().unwrap().remove(pk);
+        let update = account;
+        let slot = account.slot;
         let account = &account.account;
 
-        let (ix, mkt_pk): (Result<Instruction, String>, Pubkey) = {
+        let (ix, mkt_pk, events): (Result<Instruction, String>, Pubkey, Vec<ConsumedEvent>) = {
+            // the market may have been removed by a reload
+            let market = match self.map_event_q_to_market.read().unwrap().get(pk) {
+                Some(market) => market.clone(),
+                None => return Err("unknown event heap".into()),
+            };
+            let mkt_pk = market.market_pk;
+
             let mut header_data: &[u8] = account.data();
 
-            let event_heap: EventHeap = EventHeap::try_deserialize(&mut header_data)
-                .expect("event queue should be correctly deserailizable");
+            // an uninitialized or malformed heap must not stop the other markets
+            let event_heap: EventHeap = match EventHeap::try_deserialize(&mut header_data) {
+                Ok(event_heap) => event_heap,
+                Err(e) => {
+                    if self.should_warn_deserialize_error(pk) {
+                        log::warn!("cannot deserialize event heap {pk}: {e}");
+                    }
+                    return Err(format!("cannot deserialize event heap: {e}"));
+                }
+            };
+            if self.debug {
+                println!(
+                    "event heap {pk} of market {mkt_pk} at slot {slot}, seq_num={} len={}",
+                    event_heap.header.seq_num,
+                    event_heap.iter().count()
+                );
+                for (index, (event, _)) in event_heap.iter().enumerate() {
+                    println!("  #{index} {}", describe_event(event));
+                }
+            }
 
-            // only crank if at least 1 fill or a sufficient events of other categories are buffered
-            let contains_fill_events = event_heap
-                .iter()
-                .any(|e| e.0.event_type == EventType::Fill as u8);
+            // only crank if at least 1 fill or a sufficient events of other categories are buffered,
+            // both rules can be overridden per market
+            let crank_on_any_fill = market.config.crank_on_any_fill.unwrap_or(true);
+            let min_backlog = market.config.min_backlog.unwrap_or(MAX_BACKLOG);
             let len = event_heap.iter().count();
-            let has_backlog = len > MAX_BACKLOG;
+            let has_backlog = len > min_backlog;
+            // oldest events of the selected types, all events when not filtering
+            let selected_events = event_heap
+                .iter()
+                .map(|e| e.0)
+                .take_while(|e| self.event_types.matches(e))
+                .collect_vec();
+            let contains_fill_events = selected_events
+                .iter()
+                .any(|e| e.event_type == EventType::Fill as u8);
+            let has_selected_backlog = selected_events.len() > min_backlog;
             let seq_num = event_heap.header.seq_num;
-            log::debug!("evq {pk:?} seq_num={seq_num} len={len} contains_fill_events={contains_fill_events} has_backlog={has_backlog}");
+            if self.should_log_update(pk) {
+                log::debug!(
+                    event_heap:% = pk, seq_num, len, contains_fill_events, has_backlog;
+                    "evq {pk:?} seq_num={seq_num} len={len} contains_fill_events={contains_fill_events} has_backlog={has_backlog}"
+                );
+            }
 
-            if !contains_fill_events && !has_backlog {
-                return Err("throttled".into());
+            if let Some(heap_depth_sender) = &*self.heap_depth_sender.lock().unwrap() {
+                let _ = heap_depth_sender.send(HeapDepthRecord {
+                    timestamp: Utc::now().to_string(),
+                    slot,
+                    market: mkt_pk.to_string(),
+                    event_heap: pk.to_string(),
+                    depth: len as u64,
+                    seq_num,
+                });
             }
 
-            let mut events_accounts = HashSet::new();
-            event_heap.iter().take(MAX_EVENTS_PER_TX).for_each(|e| {
-                if events_accounts.len() < MAX_ACCS_PER_TX {
-                    match EventType::try_from(e.0.event_type).expect("openbook v2 event") {
-                        EventType::Fill => {
-                            let fill: &FillEvent = cast_ref(e.0);
-                            events_accounts.insert(fill.maker);
-                            events_accounts.insert(fill.taker);
-                        }
-                        EventType::Out => {
-                            let out: &OutEvent = cast_ref(e.0);
-                            events_accounts.insert(out.owner);
-                        }
+            if let Some(adaptive_fee) = &self.adaptive_fee {
+                adaptive_fee.record_depth(mkt_pk, len);
+            }
+
+            self.alert_on_backlog(pk, &mkt_pk, len);
+            self.check_stuck_heap(pk, &mkt_pk, seq_num, has_backlog);
+            self.heap_states.lock().unwrap().insert(
+                mkt_pk,
+                HeapState {
+                    event_heap: *pk,
+                    seq_num,
+                    len,
+                },
+            );
+            self.update_backlog(pk, has_backlog);
+
+            // dust fills are left for later unless the backlog rule applies
+            let fills_worth_cranking = contains_fill_events
+                && match market.config.min_fill_value.or(self.min_fill_value) {
+                    Some(min_fill_value) => {
+                        fill_value(selected_events.iter().copied()) >= min_fill_value as u128
                     }
-                }
-            });
+                    None => true,
+                };
+
+            if self.paused.load(Ordering::Relaxed) {
+                return Err("paused".into());
+            }
+            if self.backpressure.load(Ordering::Relaxed) {
+                return Err("too many unconfirmed transactions".into());
+            }
+
+            // outside its schedule a market waits even with a backlog
+            if !market.config.is_scheduled(Utc::now().time()) {
+                return Err("outside schedule".into());
+            }
 
-            let mkt_pk = self
-                .map_event_q_to_market
-                .get(pk)
-                .unwrap_or_else(|| panic!("{pk:?} is a known public key"));
+            if self.is_market_disabled(&mkt_pk) {
+                return Err("disabled after repeated failures".into());
+            }
+
+            // events are still consumed oldest first, consume events cannot skip the events
+            // before the priority ones
+            let has_priority_events = !self.priority_owners.is_empty()
+                && selected_events.iter().any(|e| {
+                    event_accounts(e)
+                        .unwrap_or_default()
+                        .iter()
+                        .any(|x| self.priority_owners.contains(x))
+                });
+
+            // a single pass consumes whatever is buffered
+            let should_crank = if self.once {
+                !selected_events.is_empty()
+            } else {
+                has_selected_backlog
+                    || crank_on_any_fill && fills_worth_cranking
+                    || has_priority_events
+            };
+            if !should_crank {
+                return Err("throttled".into());
+            }
+
+            // the previous transaction is likely still confirming, fills of markets
+            // cranking on any fill do not wait
+            let fill_bypass = crank_on_any_fill && fills_worth_cranking;
+            if !self.once && !fill_bypass && !has_priority_events && self.is_cooling_down(pk) {
+                self.cooled_down_updates
+                    .lock()
+                    .unwrap()
+                    .insert(*pk, update.clone());
+                return Err("cooling down".into());
+            }
+
+            // the table is only written when refreshed, its accounts are then counted as
+            // outside of it for this update
+            let lookup_table = self.lookup_table.as_ref().and_then(|x| x.try_read().ok());
+            let (events_accounts, nb_events) = accounts_for_oldest_events(
+                selected_events.iter().copied(),
+                event_accounts_budget(self.max_accounts, market.admin),
+                |account| match &lookup_table {
+                    Some(table) if table.addresses.contains(account) => LOOKUP_TABLE_ACCOUNT_BYTES,
+                    _ => ACCOUNT_BYTES,
+                },
+            );
+            drop(lookup_table);
+            // the accounts of the events past the limit are still passed, they are not used
+            let nb_events = self.consume_limit.map_or(nb_events, |x| nb_events.min(x));
+            // an instruction without events would cost fees for nothing
+            if nb_events == 0 {
+                return Err("no events fit".into());
+            }
+            // the accounts of an event are packed after those of the older events, the
+            // newest ones can then be left out of the transaction
+            let events = selected_events
+                .iter()
+                .take(nb_events)
+                .scan(0, |nb_event_accounts, e| {
+                    *nb_event_accounts = event_accounts(e)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|x| events_accounts.iter().position(|y| y == x))
+                        .map(|i| i + 1)
+                        .fold(*nb_event_accounts, usize::max);
+                    Some(ConsumedEvent {
+                        fill: e.event_type == EventType::Fill as u8,
+                        nb_event_accounts: *nb_event_accounts,
+                    })
+                })
+                .collect_vec();
 
             let mut accounts_meta = openbook_v2::accounts::ConsumeEvents {
-                consume_events_admin: None,
+                consume_events_admin: market.admin,
                 event_heap: *pk,
-                market: *mkt_pk,
+                market: mkt_pk,
             }
             .to_account_metas_wrapper(self.program_id);
 
@@ -122,22 +777,465 @@ impl AccountWriteSink for OpenbookV2CrankSink {
                 })
             }
 
-            let instruction_data = openbook_v2::instruction::ConsumeEvents {
-                limit: MAX_EVENTS_PER_TX,
-            };
+            // consume exactly the events whose accounts were packed or fewer with a consume
+            // limit, which also stops right after the selected events when filtering
+            let instruction_data = openbook_v2::instruction::ConsumeEvents { limit: nb_events };
 
             let ix = Instruction::new_with_bytes(
                 self.program_id,
                 instruction_data.data().as_slice(),
                 accounts_meta,
             );
-            (Ok(ix), *mkt_pk)
+            (Ok(ix), mkt_pk, events)
         };
 
-        if let Err(e) = self.instruction_sender.send((mkt_pk, vec![ix?])).await {
-            return Err(e.to_string());
+        let ix = ix?;
+        // nothing is sent, every update is shown with the decision it would get
+        if self.debug {
+            return Ok(());
+        }
+        self.stats.add_build_latency(received_at.elapsed());
+        if let Err(e) = self
+            .queue_instruction((mkt_pk, vec![ix], Instant::now(), events))
+            .await
+        {
+            self.stats.inc_dropped();
+            return Err(e);
         }
+        // a dropped instruction does not start the cooldown
+        if self.min_crank_interval.is_some() {
+            self.last_cranks.lock().unwrap().insert(*pk, Instant::now());
+        }
+        self.stats
+            .set_pending_instructions(self.instruction_sender.len());
 
         Ok(())
     }
 }
+
+#[async_trait]
+impl AccountWriteSink for OpenbookV2CrankSink {
+    async fn process(
+        &self,
+        pk: &solana_sdk::pubkey::Pubkey,
+        account: &AccountData,
+    ) -> Result<(), String> {
+        let result = self.process_event_heap(pk, account).await;
+        if self.debug {
+            match &result {
+                Ok(()) => println!("would crank event heap {pk}"),
+                Err(reason) => println!("would not crank event heap {pk}: {reason}"),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::HealthStatus;
+    use anchor_lang::Discriminator;
+    use bytemuck::Zeroable;
+    use solana_sdk::account::{AccountSharedData, WritableAccount};
+
+    fn fill(maker: Pubkey, taker: Pubkey) -> AnyEvent {
+        let mut fill = FillEvent::zeroed();
+        fill.event_type = EventType::Fill as u8;
+        fill.maker = maker;
+        fill.taker = taker;
+        fill.price = 1;
+        fill.quantity = 1;
+        *cast_ref(&fill)
+    }
+
+    fn out(owner: Pubkey) -> AnyEvent {
+        let mut out = OutEvent::zeroed();
+        out.event_type = EventType::Out as u8;
+        out.owner = owner;
+        out.quantity = 1;
+        *cast_ref(&out)
+    }
+
+    fn event_heap(events: &[AnyEvent]) -> Box<EventHeap> {
+        let mut event_heap = Box::new(EventHeap::zeroed());
+        event_heap.init();
+        for event in events {
+            event_heap.push_back(*event);
+        }
+        event_heap
+    }
+
+    // account data of an event heap as the program stores it
+    fn event_heap_account_data(event_heap: &EventHeap) -> AccountData {
+        let mut data = EventHeap::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(event_heap));
+        AccountData {
+            slot: 1,
+            write_version: 0,
+            account: AccountSharedData::create(0, data, openbook_v2::ID, false, 0),
+        }
+    }
+
+    fn market(admin: Option<Pubkey>) -> MarketData {
+        MarketData {
+            market_pk: Pubkey::new_unique(),
+            event_heap: Pubkey::new_unique(),
+            admin,
+            config: MarketConfig::default(),
+        }
+    }
+
+    // a sink with the instructions it queues collected by the returned receiver
+    fn sink(market: &MarketData) -> (OpenbookV2CrankSink, Receiver<MarketInstructions>) {
+        sink_with(
+            market,
+            KeeperConfig {
+                program_id: openbook_v2::ID,
+                instruction_queue_size: 8,
+                ..KeeperConfig::default()
+            },
+        )
+    }
+
+    fn sink_with(
+        market: &MarketData,
+        config: KeeperConfig,
+    ) -> (OpenbookV2CrankSink, Receiver<MarketInstructions>) {
+        let (sender, receiver) = async_channel::bounded(config.instruction_queue_size);
+        let stats = CrankStats::new(None, None, HealthStatus::default(), Duration::ZERO, None);
+        let sink = OpenbookV2CrankSink::new(
+            vec![market.clone()],
+            (sender, receiver.clone()),
+            &config,
+            stats,
+            None,
+        );
+        (sink, receiver)
+    }
+
+    fn queued_instruction(receiver: &Receiver<MarketInstructions>) -> Instruction {
+        let (_, mut ixs, _, _) = receiver.try_recv().expect("an instruction is queued");
+        assert!(receiver.is_empty());
+        assert_eq!(ixs.len(), 1);
+        ixs.remove(0)
+    }
+
+    // event accounts and events consumed of the single queued instruction
+    fn queued(receiver: &Receiver<MarketInstructions>) -> (Vec<AccountMeta>, u64) {
+        let ix = queued_instruction(receiver);
+        let limit = u64::from_le_bytes(ix.data[8..16].try_into().unwrap());
+        (ix.accounts[3..].to_vec(), limit)
+    }
+
+    fn instruction(market: Pubkey, tag: u8) -> MarketInstructions {
+        let ix = Instruction::new_with_bytes(openbook_v2::ID, &[tag], vec![]);
+        (market, vec![ix], Instant::now(), vec![])
+    }
+
+    #[tokio::test]
+    async fn replaces_only_instruction_of_same_market_when_queue_full() {
+        let market = market(None);
+        let (sink, receiver) = sink(&market);
+        let others = (0..7).map(|_| Pubkey::new_unique()).collect_vec();
+        sink.queue_instruction(instruction(market.market_pk, 0))
+            .await
+            .unwrap();
+        for other in &others {
+            sink.queue_instruction(instruction(*other, 0))
+                .await
+                .unwrap();
+        }
+        assert!(receiver.is_full());
+
+        // a market with nothing queued does not evict the others
+        let result = sink
+            .queue_instruction(instruction(Pubkey::new_unique(), 1))
+            .await;
+        assert_eq!(result, Err("instruction queue full".to_string()));
+        sink.queue_instruction(instruction(market.market_pk, 2))
+            .await
+            .unwrap();
+
+        let queued = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|(market, ixs, _, _)| (market, ixs[0].data[0]))
+            .collect_vec();
+        let expected = std::iter::once((market.market_pk, 2))
+            .chain(others.iter().map(|x| (*x, 0)))
+            .collect_vec();
+        assert_eq!(queued, expected);
+    }
+
+    #[tokio::test]
+    async fn dropped_instruction_does_not_start_cooldown() {
+        let mut market = market(None);
+        market.config.min_backlog = Some(0);
+        let (sink, receiver) = sink_with(
+            &market,
+            KeeperConfig {
+                program_id: openbook_v2::ID,
+                instruction_queue_size: 1,
+                min_crank_interval: Some(Duration::from_secs(60)),
+                ..KeeperConfig::default()
+            },
+        );
+        sink.queue_instruction(instruction(Pubkey::new_unique(), 0))
+            .await
+            .unwrap();
+        let update = event_heap_account_data(&event_heap(&[out(Pubkey::new_unique())]));
+        let result = sink.process(&market.event_heap, &update).await;
+        assert_eq!(result, Err("instruction queue full".to_string()));
+        assert!(sink.last_cranks.lock().unwrap().is_empty());
+
+        receiver.try_recv().unwrap();
+        sink.process(&market.event_heap, &update).await.unwrap();
+        assert!(sink
+            .last_cranks
+            .lock()
+            .unwrap()
+            .contains_key(&market.event_heap));
+    }
+
+    fn throttled_sink(market: &MarketData) -> (OpenbookV2CrankSink, Receiver<MarketInstructions>) {
+        sink_with(
+            market,
+            KeeperConfig {
+                program_id: openbook_v2::ID,
+                instruction_queue_size: 8,
+                min_crank_interval: Some(Duration::from_millis(50)),
+                ..KeeperConfig::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn processes_update_discarded_while_cooling_down_once_over() {
+        let mut market = market(None);
+        market.config.min_backlog = Some(0);
+        let (sink, receiver) = throttled_sink(&market);
+        let update = event_heap_account_data(&event_heap(&[out(Pubkey::new_unique())]));
+        sink.process(&market.event_heap, &update).await.unwrap();
+        queued_instruction(&receiver);
+
+        let result = sink.process(&market.event_heap, &update).await;
+        assert_eq!(result, Err("cooling down".to_string()));
+        let next = sink.process_cooled_down_updates().await.unwrap();
+        assert!(next <= Duration::from_millis(50));
+        assert!(receiver.is_empty());
+
+        tokio::time::sleep(next).await;
+        sink.process_cooled_down_updates().await.unwrap();
+        queued_instruction(&receiver);
+    }
+
+    #[tokio::test]
+    async fn debug_mode_neither_queues_nor_throttles() {
+        let mut market = market(None);
+        market.config.min_backlog = Some(0);
+        let (sink, receiver) = sink_with(
+            &market,
+            KeeperConfig {
+                program_id: openbook_v2::ID,
+                instruction_queue_size: 8,
+                min_crank_interval: Some(Duration::from_secs(60)),
+                debug_events: true,
+                ..KeeperConfig::default()
+            },
+        );
+        let update = event_heap_account_data(&event_heap(&[out(Pubkey::new_unique())]));
+        sink.process(&market.event_heap, &update).await.unwrap();
+        sink.process(&market.event_heap, &update).await.unwrap();
+        assert!(receiver.is_empty());
+        assert!(sink.last_cranks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fills_skip_cooldown_unless_crank_on_any_fill_is_disabled() {
+        let mut market = market(None);
+        let (sink, receiver) = throttled_sink(&market);
+        let update = event_heap_account_data(&event_heap(&[fill(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        )]));
+        sink.process(&market.event_heap, &update).await.unwrap();
+        sink.process(&market.event_heap, &update).await.unwrap();
+        assert_eq!(receiver.len(), 2);
+
+        market.config.crank_on_any_fill = Some(false);
+        market.config.min_backlog = Some(0);
+        let (sink, _receiver) = throttled_sink(&market);
+        sink.process(&market.event_heap, &update).await.unwrap();
+        let result = sink.process(&market.event_heap, &update).await;
+        assert_eq!(result, Err("cooling down".to_string()));
+    }
+
+    #[tokio::test]
+    async fn warns_once_per_interval_about_undeserializable_heap() {
+        let market = market(None);
+        let (sink, _receiver) = sink(&market);
+        let update = AccountData {
+            slot: 1,
+            write_version: 0,
+            account: AccountSharedData::create(0, vec![0; 8], openbook_v2::ID, false, 0),
+        };
+        for _ in 0..2 {
+            let result = sink.process(&market.event_heap, &update).await;
+            assert!(result
+                .unwrap_err()
+                .starts_with("cannot deserialize event heap"));
+        }
+        assert_eq!(sink.last_deserialize_warnings.lock().unwrap().len(), 1);
+        assert!(!sink.should_warn_deserialize_error(&market.event_heap));
+        assert!(sink.should_warn_deserialize_error(&Pubkey::new_unique()));
+    }
+
+    #[tokio::test]
+    async fn throttles_heap_without_fill_under_backlog() {
+        let market = market(None);
+        let (sink, receiver) = sink(&market);
+        let event_heap = event_heap(&[out(Pubkey::new_unique())]);
+        let result = sink
+            .process(&market.event_heap, &event_heap_account_data(&event_heap))
+            .await;
+        assert_eq!(result, Err("throttled".to_string()));
+        assert!(receiver.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cranks_heap_with_fill() {
+        let market = market(None);
+        let (sink, receiver) = sink(&market);
+        let (maker, taker) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let event_heap = event_heap(&[fill(maker, taker)]);
+        sink.process(&market.event_heap, &event_heap_account_data(&event_heap))
+            .await
+            .unwrap();
+        let (accounts, limit) = queued(&receiver);
+        let accounts = accounts.iter().map(|x| x.pubkey).collect_vec();
+        assert_eq!(accounts, vec![maker, taker]);
+        assert_eq!(limit, 1);
+    }
+
+    #[tokio::test]
+    async fn gathers_accounts_of_fill_and_out_events() {
+        let market = market(None);
+        let (sink, receiver) = sink(&market);
+        let (a, b, c) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let event_heap = event_heap(&[fill(a, b), out(c), fill(b, a), out(a)]);
+        sink.process(&market.event_heap, &event_heap_account_data(&event_heap))
+            .await
+            .unwrap();
+        let (accounts, limit) = queued(&receiver);
+        assert!(accounts.iter().all(|x| x.is_writable && !x.is_signer));
+        let accounts = accounts.iter().map(|x| x.pubkey).collect_vec();
+        assert_eq!(accounts, vec![a, b, c]);
+        assert_eq!(limit, 4);
+    }
+
+    #[tokio::test]
+    async fn stops_at_account_limit() {
+        let market = market(None);
+        let (sink, receiver) = sink(&market);
+        let events = (0..MAX_ACCS_PER_TX)
+            .map(|_| fill(Pubkey::new_unique(), Pubkey::new_unique()))
+            .collect_vec();
+        let event_heap = event_heap(&events);
+        sink.process(&market.event_heap, &event_heap_account_data(&event_heap))
+            .await
+            .unwrap();
+        let (accounts, limit) = queued(&receiver);
+        assert_eq!(accounts.len(), MAX_ACCS_PER_TX);
+        assert_eq!(limit as usize, MAX_ACCS_PER_TX / 2);
+    }
+
+    #[test]
+    fn stops_at_packet_size_with_accounts_outside_lookup_table() {
+        let events = (0..MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE)
+            .map(|_| fill(Pubkey::new_unique(), Pubkey::new_unique()))
+            .collect_vec();
+        let (accounts, nb_events) =
+            accounts_for_oldest_events(events.iter(), MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE, |_| {
+                ACCOUNT_BYTES
+            });
+        assert_eq!(accounts.len(), MAX_ACCS_PER_TX);
+        assert_eq!(nb_events, MAX_ACCS_PER_TX / 2);
+
+        let (accounts, nb_events) =
+            accounts_for_oldest_events(events.iter(), MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE, |_| {
+                LOOKUP_TABLE_ACCOUNT_BYTES
+            });
+        assert_eq!(accounts.len(), MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE);
+        assert_eq!(nb_events, MAX_ACCS_PER_TX_WITH_LOOKUP_TABLE / 2);
+    }
+
+    #[tokio::test]
+    async fn skips_heap_when_no_event_fits() {
+        let market = market(None);
+        let (sink, receiver) = sink(&market);
+        let mut unknown = AnyEvent::zeroed();
+        unknown.event_type = u8::MAX;
+        let event_heap = event_heap(&[unknown; MAX_BACKLOG + 1]);
+        let result = sink
+            .process(&market.event_heap, &event_heap_account_data(&event_heap))
+            .await;
+        assert_eq!(result, Err("no events fit".to_string()));
+        assert!(receiver.is_empty());
+    }
+
+    #[test]
+    fn gathers_accounts_oldest_event_first() {
+        let owners = (0..5).map(|_| Pubkey::new_unique()).collect_vec();
+        let event_heap = event_heap(&owners.iter().map(|x| out(*x)).collect_vec());
+        let (accounts, nb_events) =
+            accounts_for_oldest_events(event_heap.iter().map(|x| x.0), 3, |_| ACCOUNT_BYTES);
+        // the newest events do not fit, the oldest ones are consumed first
+        assert_eq!(accounts, owners[..3]);
+        assert_eq!(nb_events, 3);
+    }
+
+    #[test]
+    fn dedups_overlapping_makers_and_takers() {
+        let (a, b, c) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        // a self trade and fills between the same accounts only take their accounts once
+        let events = [fill(a, a), fill(a, b), fill(b, a), fill(c, b)];
+        let (accounts, nb_events) =
+            accounts_for_oldest_events(events.iter(), 10, |_| ACCOUNT_BYTES);
+        assert_eq!(accounts, vec![a, b, c]);
+        assert_eq!(nb_events, 4);
+        // at the account limit, events between accounts already gathered still fit
+        let (accounts, nb_events) = accounts_for_oldest_events(events.iter(), 2, |_| ACCOUNT_BYTES);
+        assert_eq!(accounts, vec![a, b]);
+        assert_eq!(nb_events, 3);
+    }
+
+    #[tokio::test]
+    async fn stays_within_account_limit_with_and_without_admin() {
+        for admin in [None, Some(Pubkey::new_unique())] {
+            let market = market(admin);
+            let (sink, receiver) = sink(&market);
+            let events = (0..MAX_ACCS_PER_TX)
+                .map(|_| out(Pubkey::new_unique()))
+                .collect_vec();
+            let event_heap = event_heap(&events);
+            sink.process(&market.event_heap, &event_heap_account_data(&event_heap))
+                .await
+                .unwrap();
+            let ix = queued_instruction(&receiver);
+            // admin or its placeholder, market and event heap
+            assert_eq!(
+                ix.accounts.len(),
+                3 + event_accounts_budget(MAX_ACCS_PER_TX, admin)
+            );
+            assert!(ix.accounts.len() <= MAX_ACCS_PER_TX + 3);
+            assert_eq!(ix.accounts[0].is_signer, admin.is_some());
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use crate::states::{BlockData, TransactionConfirmRecord};
+use log::{error, info, warn};
+use std::time::Duration;
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver},
+    task::JoinHandle,
+};
+use tokio_postgres::{types::ToSql, Client, NoTls};
+
+const MAX_BATCH_SIZE: usize = 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+type SqlParams = Vec<Box<dyn ToSql + Sync + Send>>;
+
+/// A record which is saved as a row of a postgres table
+pub trait PostgresRecord: Clone + Send + Sync + 'static {
+    const CREATE_TABLE: &'static str;
+    const INSERT: &'static str;
+    fn params(&self) -> SqlParams;
+}
+
+impl PostgresRecord for TransactionConfirmRecord {
+    const CREATE_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS transaction_confirmations (
+        signature TEXT NOT NULL,
+        sent_slot BIGINT NOT NULL,
+        sent_at TEXT NOT NULL,
+        confirmed_slot BIGINT,
+        confirmed_at TEXT,
+        successful BOOLEAN NOT NULL,
+        slot_leader TEXT,
+        error TEXT,
+        \"user\" TEXT,
+        market TEXT,
+        block_hash TEXT,
+        slot_processed BIGINT,
+        slot_latency BIGINT,
+        timed_out BOOLEAN NOT NULL,
+        priority_fees BIGINT NOT NULL
+    );
+    ALTER TABLE transaction_confirmations
+        ADD COLUMN IF NOT EXISTS reorged BOOLEAN NOT NULL DEFAULT false";
+    const INSERT: &'static str = "INSERT INTO transaction_confirmations (
+        signature, sent_slot, sent_at, confirmed_slot, confirmed_at, successful, slot_leader,
+        error, \"user\", market, block_hash, slot_processed, slot_latency, timed_out,
+        priority_fees, reorged
+    ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)";
+
+    fn params(&self) -> SqlParams {
+        vec![
+            Box::new(self.signature.clone()),
+            Box::new(self.sent_slot as i64),
+            Box::new(self.sent_at.clone()),
+            Box::new(self.confirmed_slot.map(|x| x as i64)),
+            Box::new(self.confirmed_at.clone()),
+            Box::new(self.successful),
+            Box::new(self.slot_leader.clone()),
+            Box::new(self.error.clone()),
+            Box::new(self.user.clone()),
+            Box::new(self.market.clone()),
+            Box::new(self.block_hash.clone()),
+            Box::new(self.slot_processed.map(|x| x as i64)),
+            Box::new(self.slot_latency.map(|x| x as i64)),
+            Box::new(self.timed_out),
+            Box::new(self.priority_fees as i64),
+            Box::new(self.reorged),
+        ]
+    }
+}
+
+impl PostgresRecord for BlockData {
+    const CREATE_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS block_data (
+        block_hash TEXT NOT NULL,
+        block_slot BIGINT NOT NULL,
+        block_leader TEXT NOT NULL,
+        total_transactions BIGINT NOT NULL,
+        number_of_mm_transactions BIGINT NOT NULL,
+        block_time BIGINT NOT NULL,
+        cu_consumed BIGINT NOT NULL,
+        cu_consumed_by_openbook BIGINT NOT NULL,
+        percentage_filled_by_openbook REAL NOT NULL
+    );
+    ALTER TABLE block_data
+        ADD COLUMN IF NOT EXISTS transactions_by_market TEXT NOT NULL DEFAULT '{}'";
+    const INSERT: &'static str = "INSERT INTO block_data (
+        block_hash, block_slot, block_leader, total_transactions, number_of_mm_transactions,
+        block_time, cu_consumed, cu_consumed_by_openbook, percentage_filled_by_openbook,
+        transactions_by_market
+    ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)";
+
+    fn params(&self) -> SqlParams {
+        vec![
+            Box::new(self.block_hash.clone()),
+            Box::new(self.block_slot as i64),
+            Box::new(self.block_leader.clone()),
+            Box::new(self.total_transactions as i64),
+            Box::new(self.number_of_mm_transactions as i64),
+            Box::new(self.block_time as i64),
+            Box::new(self.cu_consumed as i64),
+            Box::new(self.cu_consumed_by_openbook as i64),
+            Box::new(self.percentage_filled_by_openbook),
+            Box::new(self.transactions_by_market.clone()),
+        ]
+    }
+}
+
+async fn connect<T: PostgresRecord>(postgres_url: &str) -> anyhow::Result<Client> {
+    let (client, connection) = tokio_postgres::connect(postgres_url, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("postgres connection error {}", e);
+        }
+    });
+    client.batch_execute(T::CREATE_TABLE).await?;
+    Ok(client)
+}
+
+// inserts the whole batch in a single database transaction
+async fn insert_batch<T: PostgresRecord>(client: &mut Client, batch: &[T]) -> anyhow::Result<()> {
+    let transaction = client.transaction().await?;
+    let statement = transaction.prepare(T::INSERT).await?;
+    for record in batch {
+        let params = record.params();
+        let params: Vec<&(dyn ToSql + Sync)> = params
+            .iter()
+            .map(|x| x.as_ref() as &(dyn ToSql + Sync))
+            .collect();
+        transaction.execute(&statement, &params).await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+// records drained from the channel are kept until they are inserted, reconnecting on failure
+fn spawn_postgres_writer<T: PostgresRecord>(
+    postgres_url: String,
+    records: Receiver<T>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut records = records;
+        let mut client: Option<Client> = None;
+        let mut batch: Vec<T> = vec![];
+        let mut flush_tick = tokio::time::interval(FLUSH_INTERVAL);
+        let mut channel_closed = false;
+        while !channel_closed || !batch.is_empty() {
+            if !channel_closed && batch.len() < MAX_BATCH_SIZE {
+                tokio::select! {
+                    record = records.recv() => {
+                        match record {
+                            Ok(record) => batch.push(record),
+                            Err(RecvError::Lagged(n)) => warn!("postgres writer lagged, {n} records lost"),
+                            Err(RecvError::Closed) => channel_closed = true,
+                        }
+                        continue;
+                    }
+                    _ = flush_tick.tick() => {}
+                }
+            }
+            if batch.is_empty() {
+                continue;
+            }
+
+            if client.is_none() {
+                match connect::<T>(&postgres_url).await {
+                    Ok(new_client) => {
+                        info!("connected to postgres");
+                        client = Some(new_client);
+                    }
+                    Err(e) => {
+                        error!("cannot connect to postgres, retrying {}", e);
+                        tokio::time::sleep(RECONNECT_INTERVAL).await;
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(connected) = client.as_mut() {
+                match insert_batch(connected, &batch).await {
+                    Ok(()) => batch.clear(),
+                    Err(e) => {
+                        error!("error inserting into postgres, reconnecting {}", e);
+                        client = None;
+                        tokio::time::sleep(RECONNECT_INTERVAL).await;
+                    }
+                }
+            }
+        }
+    })
+}
+
+pub fn initialize_postgres_writers(
+    postgres_url: String,
+    tx_data: Receiver<TransactionConfirmRecord>,
+    block_data: Option<Receiver<BlockData>>,
+) -> Vec<JoinHandle<()>> {
+    let mut tasks = vec![spawn_postgres_writer(postgres_url.clone(), tx_data)];
+    if let Some(block_data) = block_data {
+        tasks.push(spawn_postgres_writer(postgres_url, block_data));
+    }
+    tasks
+}
@@ -0,0 +1,224 @@
+use crate::crank::MarketsHandle;
+use log::{debug, warn};
+use solana_client::{nonblocking::rpc_client::RpcClient, rpc_response::RpcPrioritizationFee};
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+const PRIORITY_FEE_PERCENTILE: usize = 75;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+// getRecentPrioritizationFees rejects more accounts than this
+const MAX_PRIORITIZATION_FEE_ACCOUNTS: usize = 128;
+// heap depths older than this do not count in the backlog growth rate
+const BACKLOG_GROWTH_WINDOW: Duration = Duration::from_secs(30);
+// below this span of depths the growth rate is too noisy to pay for
+const MIN_BACKLOG_GROWTH_SPAN: Duration = Duration::from_secs(1);
+/// Adaptive fee curve of the command line, in events per second and micro lamports
+pub const DEFAULT_ADAPTIVE_FEE_CURVE: &str = "1:5000,10:50000,50:200000";
+
+/// Point of the adaptive fee curve written as "<events per second>:<micro lamports>"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeeCurvePoint {
+    growth_rate: f64,
+    fee: u64,
+}
+
+impl FromStr for FeeCurvePoint {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (growth_rate, fee) = value.split_once(':').ok_or_else(|| {
+            format!("invalid fee curve point {value:?}, expected <events per second>:<fee>")
+        })?;
+        let growth_rate = growth_rate
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| format!("invalid growth rate in {value:?}: {e}"))?;
+        if !growth_rate.is_finite() || growth_rate < 0.0 {
+            return Err(format!("invalid growth rate in {value:?}"));
+        }
+        let fee = fee
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid fee in {value:?}: {e}"))?;
+        Ok(Self { growth_rate, fee })
+    }
+}
+
+// event heap depths of each market over the growth window, oldest first
+type HeapDepths = HashMap<Pubkey, VecDeque<(Instant, usize)>>;
+
+/// Compute unit price of each market scaled with how fast its event heap fills up
+#[derive(Clone, Debug)]
+pub struct AdaptiveFee {
+    // sorted by growth rate
+    curve: Arc<Vec<FeeCurvePoint>>,
+    floor: u64,
+    ceiling: Option<u64>,
+    depths: Arc<Mutex<HeapDepths>>,
+}
+
+impl AdaptiveFee {
+    pub fn new(mut curve: Vec<FeeCurvePoint>, floor: u64, ceiling: Option<u64>) -> Self {
+        curve.sort_by(|a, b| a.growth_rate.total_cmp(&b.growth_rate));
+        Self {
+            curve: Arc::new(curve),
+            floor,
+            ceiling,
+            depths: Arc::default(),
+        }
+    }
+
+    /// records the depth of the event heap of a market on every update
+    pub fn record_depth(&self, market: Pubkey, depth: usize) {
+        let now = Instant::now();
+        let mut depths = self.depths.lock().unwrap();
+        let market_depths = depths.entry(market).or_default();
+        market_depths.push_back((now, depth));
+        while matches!(market_depths.front(), Some((at, _)) if now.duration_since(*at) > BACKLOG_GROWTH_WINDOW)
+        {
+            market_depths.pop_front();
+        }
+    }
+
+    /// events per second added to the heap of the market over the growth window, 0 while
+    /// it shrinks
+    fn growth_rate(&self, market: &Pubkey) -> f64 {
+        let depths = self.depths.lock().unwrap();
+        let Some(market_depths) = depths.get(market) else {
+            return 0.0;
+        };
+        let (Some((first_at, first)), Some((last_at, last))) =
+            (market_depths.front(), market_depths.back())
+        else {
+            return 0.0;
+        };
+        let span = last_at.duration_since(*first_at);
+        if span < MIN_BACKLOG_GROWTH_SPAN || last <= first {
+            return 0.0;
+        }
+        (last - first) as f64 / span.as_secs_f64()
+    }
+
+    /// fee of the curve at the given growth rate, interpolated between the points, 0 before
+    /// the first point and the last fee after the last point
+    fn curve_fee(&self, growth_rate: f64) -> u64 {
+        let Some(index) = self.curve.iter().position(|x| x.growth_rate > growth_rate) else {
+            return self.curve.last().map_or(0, |x| x.fee);
+        };
+        if index == 0 {
+            return 0;
+        }
+        let (low, high) = (self.curve[index - 1], self.curve[index]);
+        let ratio = (growth_rate - low.growth_rate) / (high.growth_rate - low.growth_rate);
+        (low.fee as f64 + (high.fee as f64 - low.fee as f64) * ratio) as u64
+    }
+
+    /// compute unit price of a transaction cranking the given markets, the fastest growing
+    /// market sets it. Never below `base_fee` and the floor, nor above the ceiling
+    pub fn fee(&self, markets: &[Pubkey], base_fee: u64) -> u64 {
+        let fee = markets
+            .iter()
+            .map(|x| self.curve_fee(self.growth_rate(x)))
+            .max()
+            .unwrap_or_default()
+            .max(base_fee)
+            .max(self.floor);
+        self.ceiling.map_or(fee, |x| fee.min(x))
+    }
+}
+
+/// Value at the given percentile, fees must be sorted
+fn percentile(sorted_fees: &[u64], percentile: usize) -> Option<u64> {
+    if sorted_fees.is_empty() {
+        return None;
+    }
+    Some(sorted_fees[(sorted_fees.len() - 1) * percentile / 100])
+}
+
+/// Fee of each slot to land a transaction locking the accounts of every chunk, the highest
+/// fee of the slot across the chunks
+fn max_fee_per_slot(chunks: &[Vec<RpcPrioritizationFee>]) -> Vec<u64> {
+    let mut fees = HashMap::new();
+    for fee in chunks.iter().flatten() {
+        let slot_fee = fees.entry(fee.slot).or_insert(0);
+        *slot_fee = fee.prioritization_fee.max(*slot_fee);
+    }
+    fees.into_values().collect()
+}
+
+/// Fees paid in the recent slots for the accounts, requested in chunks of at most
+/// MAX_PRIORITIZATION_FEE_ACCOUNTS accounts
+async fn recent_prioritization_fees(
+    rpc_client: &RpcClient,
+    accounts: &[Pubkey],
+) -> anyhow::Result<Vec<u64>> {
+    let mut chunks = vec![];
+    for chunk in accounts.chunks(MAX_PRIORITIZATION_FEE_ACCOUNTS) {
+        chunks.push(rpc_client.get_recent_prioritization_fees(chunk).await?);
+    }
+    Ok(max_fee_per_slot(&chunks))
+}
+
+/// Periodically sets the priority fee to a percentile of the recent prioritization fees
+/// paid for the cranked accounts, never going below `floor` nor above `ceiling`
+pub fn start_priority_fee_service(
+    rpc_client: Arc<RpcClient>,
+    markets_handle: MarketsHandle,
+    priority_fee: Arc<AtomicU64>,
+    floor: u64,
+    ceiling: Option<u64>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let accounts = markets_handle.cranked_accounts();
+            match recent_prioritization_fees(&rpc_client, &accounts).await {
+                Ok(mut fees) => {
+                    fees.sort_unstable();
+                    let fee = percentile(&fees, PRIORITY_FEE_PERCENTILE)
+                        .unwrap_or_default()
+                        .max(floor);
+                    // a spike of the recent fees must not drain the fee payer
+                    let fee = ceiling.map_or(fee, |x| fee.min(x));
+                    debug!("priority fee updated to {}", fee);
+                    priority_fee.store(fee, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    warn!("failed to get recent prioritization fees {}", e);
+                }
+            }
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fee(slot: u64, prioritization_fee: u64) -> RpcPrioritizationFee {
+        RpcPrioritizationFee {
+            slot,
+            prioritization_fee,
+        }
+    }
+
+    #[test]
+    fn takes_highest_fee_of_each_slot_across_chunks() {
+        let chunks = vec![
+            vec![fee(1, 100), fee(2, 0), fee(3, 50)],
+            vec![fee(1, 20), fee(2, 300)],
+        ];
+        let mut fees = max_fee_per_slot(&chunks);
+        fees.sort_unstable();
+        assert_eq!(fees, vec![50, 100, 300]);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::{rebroadcast::TransactionResender, states::TransactionSendRecord, stats::CrankStats};
+use async_trait::async_trait;
+use log::{debug, warn};
+use solana_sdk::transaction::VersionedTransaction;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time::Instant,
+};
+
+/// Token bucket of `max_tps` transactions per second, shared by the rate limiter and the
+/// rebroadcasts so that resent transactions count against the same rate
+#[derive(Clone)]
+pub struct TpsBudget {
+    max_tps: f64,
+    // available tokens and when they were last refilled
+    tokens: Arc<Mutex<(f64, Instant)>>,
+}
+
+impl TpsBudget {
+    pub fn new(max_tps: u32) -> Self {
+        let max_tps = max_tps as f64;
+        // allows bursts of up to one second of transactions
+        Self {
+            max_tps,
+            tokens: Arc::new(Mutex::new((max_tps, Instant::now()))),
+        }
+    }
+
+    /// takes up to `wanted` tokens, returns how many were taken
+    pub fn take(&self, wanted: usize) -> usize {
+        let mut tokens = self.tokens.lock().unwrap();
+        let now = Instant::now();
+        let (available, last_refill) = *tokens;
+        let available = (available + now.duration_since(last_refill).as_secs_f64() * self.max_tps)
+            .min(self.max_tps);
+        let taken = (available.floor() as usize).min(wanted);
+        *tokens = (available - taken as f64, now);
+        taken
+    }
+
+    // time until the next token is available
+    fn next_token_in(&self) -> Duration {
+        let (available, last_refill) = *self.tokens.lock().unwrap();
+        let refilled = available + last_refill.elapsed().as_secs_f64() * self.max_tps;
+        Duration::from_secs_f64((1.0 - refilled).max(0.0) / self.max_tps)
+    }
+}
+
+/// Forwards transactions to the send bridge within the `budget` of transactions per second,
+/// transactions over the rate are queued and the oldest are dropped once more than
+/// `max_queue` are waiting
+pub fn start_rate_limiter(
+    tx_rx: UnboundedReceiver<(VersionedTransaction, TransactionSendRecord)>,
+    tx_sx: UnboundedSender<(VersionedTransaction, TransactionSendRecord)>,
+    budget: TpsBudget,
+    max_queue: usize,
+    stats: CrankStats,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tx_rx = tx_rx;
+        let mut queue = VecDeque::new();
+        loop {
+            let allowed = budget.take(queue.len());
+            for tx in queue.drain(..allowed) {
+                if tx_sx.send(tx).is_err() {
+                    warn!("transaction bridge channel closed");
+                    return;
+                }
+            }
+
+            let next = if queue.is_empty() {
+                tx_rx.recv().await
+            } else {
+                // wake up when the next token is available
+                match tokio::time::timeout(budget.next_token_in(), tx_rx.recv()).await {
+                    Ok(next) => next,
+                    Err(_) => continue,
+                }
+            };
+            let Some(next) = next else {
+                break;
+            };
+            queue.push_back(next);
+            if queue.len() > max_queue {
+                queue.pop_front();
+                stats.inc_rate_limited_drops();
+            }
+        }
+    })
+}
+
+/// Resends only as many transactions as the budget allows, the others wait for the next
+/// rebroadcast
+pub struct RateLimitedResender {
+    pub resender: Arc<dyn TransactionResender>,
+    pub budget: TpsBudget,
+}
+
+#[async_trait]
+impl TransactionResender for RateLimitedResender {
+    async fn resend_transactions(&self, mut transactions: Vec<VersionedTransaction>) {
+        let allowed = self.budget.take(transactions.len());
+        if allowed < transactions.len() {
+            debug!(
+                "rate limited, {} rebroadcasts postponed",
+                transactions.len() - allowed
+            );
+            transactions.truncate(allowed);
+        }
+        if !transactions.is_empty() {
+            self.resender.resend_transactions(transactions).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingResender(AtomicUsize);
+
+    #[async_trait]
+    impl TransactionResender for CountingResender {
+        async fn resend_transactions(&self, transactions: Vec<VersionedTransaction>) {
+            self.0.fetch_add(transactions.len(), Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn rebroadcasts_share_budget_with_first_sends() {
+        let budget = TpsBudget::new(5);
+        assert_eq!(budget.take(3), 3);
+
+        let counter = Arc::new(CountingResender::default());
+        let resender = RateLimitedResender {
+            resender: counter.clone(),
+            budget: budget.clone(),
+        };
+        resender
+            .resend_transactions(vec![VersionedTransaction::default(); 4])
+            .await;
+        assert_eq!(counter.0.load(Ordering::Relaxed), 2);
+        assert_eq!(budget.take(1), 0);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::states::{TransactionConfirmRecord, TransactionSendRecord};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use log::{debug, warn};
+use solana_sdk::{
+    clock::MAX_PROCESSING_AGE, signature::Signature, transaction::VersionedTransaction,
+};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{UnboundedReceiver, UnboundedSender},
+    },
+    task::JoinHandle,
+    time::Instant,
+};
+
+// stop resending a bit before the blockhash expires
+const BLOCKHASH_EXPIRY_MARGIN_SLOTS: u64 = 10;
+
+/// Sends already recorded transactions once more, without counting them as new sends
+#[async_trait]
+pub trait TransactionResender: Send + Sync {
+    async fn resend_transactions(&self, transactions: Vec<VersionedTransaction>);
+}
+
+/// Resends over every path a transaction was first sent over
+pub struct FanOutResender(pub Vec<Arc<dyn TransactionResender>>);
+
+#[async_trait]
+impl TransactionResender for FanOutResender {
+    async fn resend_transactions(&self, transactions: Vec<VersionedTransaction>) {
+        futures::future::join_all(
+            self.0
+                .iter()
+                .map(|resender| resender.resend_transactions(transactions.clone())),
+        )
+        .await;
+    }
+}
+
+struct PendingTransaction {
+    transaction: VersionedTransaction,
+    sent_slot: u64,
+    last_sent: Instant,
+}
+
+/// Forwards transactions from the crank to the send bridge and resends the ones which are
+/// still unconfirmed after `rebroadcast_after`, as long as their blockhash is valid
+pub fn start_rebroadcast_service(
+    tx_rx: UnboundedReceiver<(VersionedTransaction, TransactionSendRecord)>,
+    tx_sx: UnboundedSender<(VersionedTransaction, TransactionSendRecord)>,
+    tx_confirm_records: broadcast::Receiver<TransactionConfirmRecord>,
+    resender: Arc<dyn TransactionResender>,
+    current_slot: Arc<AtomicU64>,
+    rebroadcast_after: Duration,
+) -> Vec<JoinHandle<()>> {
+    let pending = Arc::new(DashMap::<Signature, PendingTransaction>::new());
+
+    let forward_jh = {
+        let pending = pending.clone();
+        tokio::spawn(async move {
+            let mut tx_rx = tx_rx;
+            while let Some((transaction, record)) = tx_rx.recv().await {
+                pending.insert(
+                    record.signature,
+                    PendingTransaction {
+                        transaction: transaction.clone(),
+                        sent_slot: record.sent_slot,
+                        last_sent: Instant::now(),
+                    },
+                );
+                if tx_sx.send((transaction, record)).is_err() {
+                    warn!("transaction bridge channel closed");
+                    break;
+                }
+            }
+        })
+    };
+
+    // confirmed or timed out transactions are not resent anymore
+    let confirmed_jh = {
+        let pending = pending.clone();
+        tokio::spawn(async move {
+            let mut tx_confirm_records = tx_confirm_records;
+            loop {
+                match tx_confirm_records.recv().await {
+                    Ok(record) => {
+                        if let Ok(signature) = Signature::from_str(&record.signature) {
+                            pending.remove(&signature);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    };
+
+    let rebroadcast_jh = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let slot = current_slot.load(Ordering::Relaxed);
+            let mut to_resend = vec![];
+            pending.retain(|_, tx| {
+                let blockhash_valid = slot.saturating_sub(tx.sent_slot)
+                    + BLOCKHASH_EXPIRY_MARGIN_SLOTS
+                    < MAX_PROCESSING_AGE as u64;
+                if blockhash_valid && tx.last_sent.elapsed() >= rebroadcast_after {
+                    to_resend.push(tx.transaction.clone());
+                    tx.last_sent = Instant::now();
+                }
+                blockhash_valid
+            });
+            if !to_resend.is_empty() {
+                debug!("rebroadcasting {} transactions", to_resend.len());
+                resender.resend_transactions(to_resend).await;
+            }
+        }
+    });
+
+    vec![forward_jh, confirmed_jh, rebroadcast_jh]
+}
@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    bs58,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    signer::Signer,
+};
+use std::str::FromStr;
+
+#[derive(Serialize)]
+struct SignRequest {
+    pubkey: String,
+    /// base58 serialized message
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct SignResponse {
+    /// base58 signature
+    signature: String,
+}
+
+/// Signs messages with a key kept by an http sign service, which answers a POST of
+/// `{"pubkey": "<base58>", "message": "<base58 serialized message>"}` with
+/// `{"signature": "<base58>"}`
+#[derive(Clone)]
+pub struct RemoteSigner {
+    http_client: reqwest::Client,
+    url: String,
+    pubkey: Pubkey,
+}
+
+impl RemoteSigner {
+    pub fn new(url: &str, pubkey: Pubkey) -> Self {
+        Self {
+            http_client: reqwest::Client::new(),
+            url: url.to_string(),
+            pubkey,
+        }
+    }
+
+    pub fn pubkey(&self) -> Pubkey {
+        self.pubkey
+    }
+
+    pub async fn sign_message(&self, message: &[u8]) -> anyhow::Result<Signature> {
+        let response: SignResponse = self
+            .http_client
+            .post(&self.url)
+            .json(&SignRequest {
+                pubkey: self.pubkey.to_string(),
+                message: bs58::encode(message).into_string(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let signature = Signature::from_str(&response.signature)?;
+        // a misconfigured service would otherwise only show up as failed transactions
+        anyhow::ensure!(
+            signature.verify(self.pubkey.as_ref(), message),
+            "remote signer returned an invalid signature for {}",
+            self.pubkey
+        );
+        Ok(signature)
+    }
+}
+
+/// Key of the crank authority, held in memory or by a remote signer
+pub enum CrankAuthority {
+    Keypair(Keypair),
+    Remote(RemoteSigner),
+}
+
+impl CrankAuthority {
+    pub fn pubkey(&self) -> Pubkey {
+        match self {
+            CrankAuthority::Keypair(keypair) => keypair.pubkey(),
+            CrankAuthority::Remote(remote_signer) => remote_signer.pubkey(),
+        }
+    }
+
+    pub fn keypair(&self) -> Option<&Keypair> {
+        match self {
+            CrankAuthority::Keypair(keypair) => Some(keypair),
+            CrankAuthority::Remote(_) => None,
+        }
+    }
+
+    pub fn remote_signer(&self) -> Option<&RemoteSigner> {
+        match self {
+            CrankAuthority::Keypair(_) => None,
+            CrankAuthority::Remote(remote_signer) => Some(remote_signer),
+        }
+    }
+}
+
+impl Clone for CrankAuthority {
+    fn clone(&self) -> Self {
+        match self {
+            CrankAuthority::Keypair(keypair) => CrankAuthority::Keypair(keypair.insecure_clone()),
+            CrankAuthority::Remote(remote_signer) => CrankAuthority::Remote(remote_signer.clone()),
+        }
+    }
+}
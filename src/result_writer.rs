@@ -1,41 +1,382 @@
-use crate::states::{BlockData, TransactionConfirmRecord};
+use crate::states::{BlockData, HeapDepthRecord, TransactionConfirmRecord};
+use async_compression::futures::write::GzipEncoder;
 use async_std::fs::File;
-use tokio::{sync::broadcast::Receiver, task::JoinHandle};
+use futures::{io::AsyncWrite, AsyncWriteExt};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver},
+    task::JoinHandle,
+    time::Instant,
+};
+
+#[derive(Debug, Clone)]
+pub struct ResultWriterConfig {
+    /// interval at which result files are flushed, and checked for rotation
+    pub flush_interval: Duration,
+    /// rotate a file once it is larger than this many bytes
+    pub rotate_max_bytes: Option<u64>,
+    /// rotate a file once it was opened for this long
+    pub rotate_max_age: Option<Duration>,
+    /// start every file with a csv header row
+    pub csv_header: bool,
+    /// written in the run_tag column of the records which have one
+    pub run_tag: String,
+}
+
+/// Totals of a result file, written next to it as `<file>.summary.json` when the crank
+/// shuts down cleanly
+#[derive(Debug, Default, Serialize)]
+pub struct ResultSummary {
+    pub run_tag: String,
+    pub started_at: String,
+    pub ended_at: String,
+    pub duration_secs: u64,
+    pub records_written: u64,
+    pub confirmed: u64,
+    pub timed_out: u64,
+    pub confirmed_percent: f64,
+    pub timeout_percent: f64,
+}
+
+/// Record saved as a csv row
+pub trait ResultRecord: Serialize + Clone + Send + 'static {
+    fn set_run_tag(&mut self, _run_tag: &str) {}
+
+    /// counts the outcome of the record in the summary of its file
+    fn summarize(&self, _summary: &mut ResultSummary) {}
+}
+
+impl ResultRecord for TransactionConfirmRecord {
+    fn set_run_tag(&mut self, run_tag: &str) {
+        self.run_tag = Some(run_tag.to_string());
+    }
+
+    fn summarize(&self, summary: &mut ResultSummary) {
+        if self.confirmed_at.is_some() && !self.reorged {
+            summary.confirmed += 1;
+        }
+        if self.timed_out {
+            summary.timed_out += 1;
+        }
+    }
+}
+
+impl ResultRecord for BlockData {
+    fn set_run_tag(&mut self, run_tag: &str) {
+        self.run_tag = Some(run_tag.to_string());
+    }
+}
+
+impl ResultRecord for HeapDepthRecord {}
+
+type ResultWriter = csv_async::AsyncSerializer<Box<dyn AsyncWrite + Unpin + Send>>;
+
+/// Save file path standing for stdout
+pub const STDOUT_PATH: &str = "-";
+
+// paths ending with .gz are compressed on the fly
+async fn create_writer(path: &str, csv_header: bool) -> std::io::Result<ResultWriter> {
+    let inner: Box<dyn AsyncWrite + Unpin + Send> = if path == STDOUT_PATH {
+        Box::new(async_std::io::stdout())
+    } else if path.ends_with(".gz") {
+        Box::new(GzipEncoder::new(File::create(path).await?))
+    } else {
+        Box::new(File::create(path).await?)
+    };
+    Ok(csv_async::AsyncWriterBuilder::new()
+        .has_headers(csv_header)
+        .create_serializer(inner))
+}
+
+/// Renames the file to its rotated path and opens a new one in its place, the current writer
+/// is kept on any error. The file is renamed while still open so that the current writer
+/// keeps writing to the rotated file until it is replaced.
+async fn rotate_writer(
+    writer: &mut ResultWriter,
+    save_file: &str,
+    csv_header: bool,
+) -> std::io::Result<()> {
+    let rotated = rotated_path(save_file);
+    async_std::fs::rename(save_file, &rotated).await?;
+    let new_writer = match create_writer(save_file, csv_header).await {
+        Ok(new_writer) => new_writer,
+        Err(e) => {
+            error!(
+                "error creating {save_file} : {e}, writing to {rotated} until the next rotation"
+            );
+            return Ok(());
+        }
+    };
+    // a fresh serializer writes the csv header again, if enabled
+    close_writer(std::mem::replace(writer, new_writer)).await;
+    info!("rotated {save_file} to {rotated}");
+    Ok(())
+}
+
+// flushes the csv buffer and closes the underlying stream so that gzip trailers are written
+async fn close_writer(writer: ResultWriter) {
+    match writer.into_inner().await {
+        Ok(mut inner) => {
+            if let Err(e) = inner.close().await {
+                error!("error closing result file {}", e);
+            }
+        }
+        Err(_) => error!("error flushing result file"),
+    }
+}
+
+// tx.csv.gz is rotated to tx-20230101T000000.csv.gz
+fn rotated_path(path: &str) -> String {
+    let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S");
+    let file_name_start = path.rfind('/').map(|x| x + 1).unwrap_or(0);
+    match path[file_name_start..].find('.') {
+        Some(extension_start) => {
+            let (stem, extension) = path.split_at(file_name_start + extension_start);
+            format!("{stem}-{timestamp}{extension}")
+        }
+        None => format!("{path}-{timestamp}"),
+    }
+}
+
+async fn should_rotate(path: &str, opened_at: Instant, config: &ResultWriterConfig) -> bool {
+    if path == STDOUT_PATH {
+        return false;
+    }
+    if config
+        .rotate_max_age
+        .is_some_and(|max_age| opened_at.elapsed() >= max_age)
+    {
+        return true;
+    }
+    match config.rotate_max_bytes {
+        Some(max_bytes) => async_std::fs::metadata(Path::new(path))
+            .await
+            .is_ok_and(|metadata| metadata.len() >= max_bytes),
+        None => false,
+    }
+}
+
+// the totals cover all the rotated files of the run
+async fn write_summary(save_file: &str, mut summary: ResultSummary, started_at: Instant) {
+    summary.ended_at = chrono::Utc::now().to_rfc3339();
+    summary.duration_secs = started_at.elapsed().as_secs();
+    if summary.records_written > 0 {
+        let records = summary.records_written as f64;
+        summary.confirmed_percent = summary.confirmed as f64 * 100.0 / records;
+        summary.timeout_percent = summary.timed_out as f64 * 100.0 / records;
+    }
+    let path = format!("{save_file}.summary.json");
+    match async_std::fs::write(&path, serde_json::to_string_pretty(&summary).unwrap()).await {
+        Ok(()) => info!("wrote the summary of {save_file} to {path}"),
+        Err(e) => error!("error writing {path} : {e}"),
+    }
+}
+
+// writes records until the channel closes, flushing periodically so that an
+// ungraceful exit loses at most one interval worth of data
+fn spawn_writer<T: ResultRecord>(
+    save_file: String,
+    records: Receiver<T>,
+    config: ResultWriterConfig,
+    with_summary: bool,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut writer = match create_writer(&save_file, config.csv_header).await {
+            Ok(writer) => writer,
+            Err(e) => {
+                error!("cannot create result file {save_file} : {e}");
+                return;
+            }
+        };
+        let started_at = Instant::now();
+        let mut summary = ResultSummary {
+            run_tag: config.run_tag.clone(),
+            started_at: chrono::Utc::now().to_rfc3339(),
+            ..Default::default()
+        };
+        let mut opened_at = started_at;
+        let mut records = records;
+        let mut flush_tick = tokio::time::interval(config.flush_interval);
+        loop {
+            tokio::select! {
+                record = records.recv() => {
+                    match record {
+                        Ok(mut record) => {
+                            record.set_run_tag(&config.run_tag);
+                            record.summarize(&mut summary);
+                            summary.records_written += 1;
+                            if let Err(e) = writer.serialize(record).await {
+                                error!("error writing to {save_file} : {e}");
+                            }
+                        }
+                        Err(RecvError::Lagged(n)) => warn!("result writer lagged, {n} records lost"),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                _ = flush_tick.tick() => {
+                    if let Err(e) = writer.flush().await {
+                        error!("error flushing result file {}", e);
+                    }
+                    if should_rotate(&save_file, opened_at, &config).await {
+                        if let Err(e) = rotate_writer(&mut writer, &save_file, config.csv_header).await {
+                            error!("error rotating {save_file} : {e}, appending to it meanwhile");
+                        }
+                        // a rotation by age is not retried on every flush
+                        opened_at = Instant::now();
+                    }
+                }
+            }
+        }
+        // the channel only closes once the crank services are stopped
+        close_writer(writer).await;
+        if with_summary && save_file != STDOUT_PATH {
+            write_summary(&save_file, summary, started_at).await;
+        }
+    })
+}
+
+/// Last transaction confirmations kept in memory, served by the health server
+#[derive(Clone)]
+pub struct RecentTransactions {
+    capacity: usize,
+    records: Arc<Mutex<VecDeque<TransactionConfirmRecord>>>,
+}
+
+impl RecentTransactions {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// json array of the records, oldest first
+    pub fn to_json(&self) -> String {
+        let records = self.records.lock().unwrap();
+        serde_json::to_string(&*records).unwrap_or_default()
+    }
+
+    pub fn start_recording(&self, records: Receiver<TransactionConfirmRecord>) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut records = records;
+            loop {
+                match records.recv().await {
+                    Ok(record) => {
+                        let mut recent = this.records.lock().unwrap();
+                        if recent.len() == this.capacity {
+                            recent.pop_front();
+                        }
+                        recent.push_back(record);
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+}
 
 pub fn initialize_result_writers(
     transaction_save_file: Option<String>,
     block_data_save_file: Option<String>,
+    heap_depth_save_file: Option<String>,
     tx_data: Receiver<TransactionConfirmRecord>,
     block_data: Receiver<BlockData>,
+    heap_depth_data: Receiver<HeapDepthRecord>,
+    config: ResultWriterConfig,
 ) -> Vec<JoinHandle<()>> {
     let mut tasks = vec![];
 
     if let Some(transaction_save_file) = transaction_save_file {
-        let tx_data_jh = tokio::spawn(async move {
-            let mut writer = csv_async::AsyncSerializer::from_writer(
-                File::create(transaction_save_file).await.unwrap(),
-            );
-            let mut tx_data = tx_data;
-            while let Ok(record) = tx_data.recv().await {
-                writer.serialize(record).await.unwrap();
-            }
-            writer.flush().await.unwrap();
-        });
-        tasks.push(tx_data_jh);
+        tasks.push(spawn_writer(
+            transaction_save_file,
+            tx_data,
+            config.clone(),
+            true,
+        ));
     }
 
     if let Some(block_data_save_file) = block_data_save_file {
-        let block_data_jh = tokio::spawn(async move {
-            let mut writer = csv_async::AsyncSerializer::from_writer(
-                File::create(block_data_save_file).await.unwrap(),
-            );
-            let mut block_data = block_data;
-            while let Ok(record) = block_data.recv().await {
-                writer.serialize(record).await.unwrap();
-            }
-            writer.flush().await.unwrap();
-        });
-        tasks.push(block_data_jh);
+        tasks.push(spawn_writer(
+            block_data_save_file,
+            block_data,
+            config.clone(),
+            false,
+        ));
+    }
+
+    if let Some(heap_depth_save_file) = heap_depth_save_file {
+        tasks.push(spawn_writer(
+            heap_depth_save_file,
+            heap_depth_data,
+            config,
+            false,
+        ));
     }
     tasks
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{name}-{}.csv", std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn keeps_writer_when_rotation_fails() {
+        let path = save_file("unrotated");
+        let mut writer = create_writer(&path, false).await.unwrap();
+        writer.serialize(("first", 1)).await.unwrap();
+        writer.flush().await.unwrap();
+        // the file cannot be renamed once it is gone
+        std::fs::remove_file(&path).unwrap();
+        assert!(rotate_writer(&mut writer, &path, false).await.is_err());
+        writer.serialize(("second", 2)).await.unwrap();
+        close_writer(writer).await;
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn rotates_without_losing_records() {
+        let path = save_file("rotated");
+        let mut writer = create_writer(&path, false).await.unwrap();
+        writer.serialize(("first", 1)).await.unwrap();
+        rotate_writer(&mut writer, &path, false).await.unwrap();
+        writer.serialize(("second", 2)).await.unwrap();
+        close_writer(writer).await;
+
+        let rotated = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .find(|x| {
+                let name = x.file_name().unwrap().to_string_lossy();
+                name.starts_with(&format!("rotated-{}-", std::process::id()))
+            })
+            .unwrap();
+        let rotated_records = std::fs::read_to_string(&rotated).unwrap();
+        let records = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rotated_records, "first,1\n");
+        assert_eq!(records, "second,2\n");
+    }
+
+    #[tokio::test]
+    async fn fails_to_create_writer_in_missing_directory() {
+        let path = std::env::temp_dir()
+            .join("missing-directory")
+            .join("tx.csv");
+        assert!(create_writer(&path.to_string_lossy(), false).await.is_err());
+    }
+}
@@ -1,14 +1,45 @@
+use crate::rebroadcast::TransactionResender;
 use crate::states::TransactionSendRecord;
 use crate::stats::CrankStats;
-use log::{error, warn};
-use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcSendTransactionConfig};
-use std::sync::Arc;
+use async_trait::async_trait;
+use log::{debug, error, warn};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    nonblocking::rpc_client::RpcClient,
+    rpc_config::RpcSendTransactionConfig,
+    rpc_request::{RpcError, RpcResponseErrorData},
+};
+use solana_sdk::{signature::Signature, transaction::TransactionError};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
 use tokio::sync::mpsc::UnboundedSender;
 
+/// Transactions the rpc rejected as it did not know their blockhash, they are signed again
+/// with a fresh blockhash once they timed out
+#[derive(Clone, Debug, Default)]
+pub struct BlockhashNotFoundSends(Arc<Mutex<HashSet<Signature>>>);
+
+impl BlockhashNotFoundSends {
+    fn insert(&self, signature: Signature) {
+        self.0.lock().unwrap().insert(signature);
+    }
+
+    /// whether the transaction was rejected, it is forgotten afterwards
+    pub fn take(&self, signature: &Signature) -> bool {
+        self.0.lock().unwrap().remove(signature)
+    }
+}
+
 #[derive(Clone)]
 pub struct RpcManager {
     rpc_client: Arc<RpcClient>,
     tx_send_record: UnboundedSender<TransactionSendRecord>,
+    // the rpc simulates transactions before forwarding them, send errors then carry the
+    // simulation error and logs
+    preflight: bool,
+    blockhash_not_found_sends: Option<BlockhashNotFoundSends>,
     stats: CrankStats,
 }
 
@@ -16,18 +47,22 @@ impl RpcManager {
     pub fn new(
         rpc_client: Arc<RpcClient>,
         tx_send_record: UnboundedSender<TransactionSendRecord>,
+        preflight: bool,
+        blockhash_not_found_sends: Option<BlockhashNotFoundSends>,
         stats: CrankStats,
     ) -> Self {
         Self {
             rpc_client,
             tx_send_record,
+            preflight,
+            blockhash_not_found_sends,
             stats,
         }
     }
 
     pub async fn send_transaction(
         &self,
-        transaction: &solana_sdk::transaction::Transaction,
+        transaction: &solana_sdk::transaction::VersionedTransaction,
         transaction_sent_record: TransactionSendRecord,
     ) -> bool {
         self.stats.inc_send();
@@ -35,14 +70,22 @@ impl RpcManager {
         let tx_sent_record = self.tx_send_record.clone();
         let sent = tx_sent_record.send(transaction_sent_record);
         if sent.is_err() {
+            self.stats.inc_dropped();
             warn!(
                 "sending error on channel : {}",
                 sent.err().unwrap().to_string()
             );
         }
 
+        self.send_over_rpc(transaction).await
+    }
+
+    async fn send_over_rpc(
+        &self,
+        transaction: &solana_sdk::transaction::VersionedTransaction,
+    ) -> bool {
         let config = RpcSendTransactionConfig {
-            skip_preflight: true,
+            skip_preflight: !self.preflight,
             ..RpcSendTransactionConfig::default()
         };
 
@@ -52,7 +95,44 @@ impl RpcManager {
             .await;
         if let Err(e) = &res {
             error!("error sending txs over rpc {}", e);
+            if let ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                data: RpcResponseErrorData::SendTransactionPreflightFailure(simulation),
+                ..
+            }) = e.kind()
+            {
+                debug!("simulation logs {:?}", simulation.logs);
+            }
+            if let Some(blockhash_not_found_sends) = &self.blockhash_not_found_sends {
+                if e.get_transaction_error() == Some(TransactionError::BlockhashNotFound) {
+                    blockhash_not_found_sends.insert(transaction.signatures[0]);
+                }
+            }
+            self.stats.inc_send_error(&send_error(e)).await;
         }
         res.is_ok()
     }
 }
+
+/// The transaction error when the rpc simulated the transaction, the rpc error otherwise
+fn send_error(error: &ClientError) -> String {
+    if let Some(transaction_error) = error.get_transaction_error() {
+        return transaction_error.to_string();
+    }
+    match error.kind() {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { message, .. }) => message.clone(),
+        ClientErrorKind::Io(_) | ClientErrorKind::Reqwest(_) => "rpc unreachable".to_string(),
+        kind => kind.to_string(),
+    }
+}
+
+#[async_trait]
+impl TransactionResender for RpcManager {
+    async fn resend_transactions(
+        &self,
+        transactions: Vec<solana_sdk::transaction::VersionedTransaction>,
+    ) {
+        for transaction in &transactions {
+            self.send_over_rpc(transaction).await;
+        }
+    }
+}
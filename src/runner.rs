@@ -0,0 +1,1613 @@
+use crate::{
+    admin::start_admin_socket,
+    blockhash_guard::start_blockhash_guard,
+    circuit_breaker::{
+        start_backpressure, start_circuit_breaker, start_market_breaker, CircuitBreakerConfig,
+    },
+    confirmation_strategy::{confirmations_by_blocks, ConfirmationConfig},
+    crank::{self, MarketsHandle},
+    health::{start_health_server, HealthStatus},
+    helpers::{
+        create_dual_transaction_bridge, create_jito_transaction_bridge,
+        create_rpc_transaction_bridge, create_tpu_transaction_bridge, fetch_lookup_table,
+        fetch_nonce, redact_url, start_blockhash_polling_service,
+        start_lookup_table_polling_service, start_nonce_polling_service,
+        start_slot_subscription_service, SendMode,
+    },
+    jito_manager::JitoManager,
+    leader_schedule::{start_leader_schedule_service, LeaderSchedule},
+    market_limiter::start_market_limiter,
+    markets::{self, MarketConfig, MarketData},
+    openbook_v2_sink::EventTypeFilter,
+    postgres_sink::initialize_postgres_writers,
+    priority_fees::{
+        start_priority_fee_service, AdaptiveFee, FeeCurvePoint, DEFAULT_ADAPTIVE_FEE_CURVE,
+    },
+    rate_limiter::{start_rate_limiter, RateLimitedResender, TpsBudget},
+    rebroadcast::{start_rebroadcast_service, FanOutResender, TransactionResender},
+    remote_signer::{CrankAuthority, RemoteSigner},
+    result_writer::{
+        initialize_result_writers, RecentTransactions, ResultWriterConfig, STDOUT_PATH,
+    },
+    rpc_manager,
+    stats::CrankStats,
+    statsd::StatsdClient,
+    tpu_manager,
+};
+use anyhow::Context;
+use itertools::Itertools;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    nonblocking::rpc_client::RpcClient,
+};
+use solana_sdk::{
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    pubkey::Pubkey,
+    signature::Keypair,
+    signer::Signer,
+};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc::unbounded_channel, Notify, RwLock},
+    task::JoinHandle,
+};
+
+/// Tuning knobs of the crank, the defaults match the ones of the command line
+#[derive(Debug, Clone)]
+pub struct CrankConfig {
+    pub rpc_url: String,
+    pub ws_url: String,
+    /// RPC fetching the blocks confirming transactions, the main RPC when unset
+    pub confirmation_rpc_url: Option<String>,
+    /// commitment of the markets, blockhash and slot fetches
+    pub commitment: CommitmentLevel,
+    /// commitment of the blocks confirming transactions, confirmed or finalized
+    pub confirmation_commitment: CommitmentLevel,
+    /// check that confirmed transactions get finalized and report those dropped by a reorg
+    pub recheck_finalized: bool,
+    /// number of blocks fetched at the same time when confirming transactions
+    pub max_concurrent_block_fetches: usize,
+    /// evict the oldest transactions waiting for a confirmation above this many, they are
+    /// counted as evicted rather than timed out
+    pub max_pending_transactions: Option<usize>,
+    /// stop cranking while this many sent transactions are neither confirmed nor timed out
+    pub unresolved_high_water_mark: Option<u64>,
+    /// crank again once the unresolved transactions drained to this many, half of the high
+    /// water mark when unset
+    pub unresolved_low_water_mark: Option<u64>,
+    /// warn when confirmations lag more slots behind the current slot
+    pub confirmation_lag_warning_slots: u64,
+    /// wait up to twice the 99th percentile of the recent confirmation latencies when it is
+    /// above the confirmation timeout, at most 4 times longer
+    pub adaptive_timeout: bool,
+    /// sign the transactions which timed out after their blockhash expired or was not found
+    /// again with the latest blockhash and resend them once
+    pub retry_expired_blockhash: bool,
+    /// do not request block rewards, the slot leader of confirmed transactions is then empty
+    pub no_leader_tracking: bool,
+    /// only confirm with blocks at least this many slots behind the current slot so that a
+    /// reorg cannot change the outcome, disabled when 0
+    pub confirmation_depth_slots: u64,
+    /// tpu fanout
+    pub fanout_size: u64,
+    /// immediate resends of a failed TPU batch before it counts as an error
+    pub tpu_send_retries: usize,
+    /// QUIC connections kept to each leader by a TPU client
+    pub tpu_connection_pool_size: usize,
+    /// interval at which the TPU clients are rebuilt, never when 0
+    pub tpu_reset_interval_secs: u64,
+    /// transactions sent together over TPU, a failed send fails the whole batch
+    pub tpu_batch_size: usize,
+    /// `-` writes to stdout, allowed for a single result file and without stats reports
+    pub transaction_save_file: Option<String>,
+    pub block_data_save_file: Option<String>,
+    /// interval in seconds at which result files are flushed to disk
+    pub result_flush_interval_secs: u64,
+    /// rotate result files larger than this many bytes
+    pub result_rotate_max_bytes: Option<u64>,
+    /// rotate result files opened for this many seconds
+    pub result_rotate_max_age_secs: Option<u64>,
+    /// write result files without the csv header row
+    pub no_csv_header: bool,
+    /// written in the run_tag column of the transaction and block files, defaults to the
+    /// start time and process id
+    pub run_tag: Option<String>,
+    /// append a json line with the stats to this file on every report
+    pub stats_json_file: Option<String>,
+    /// StatsD agent the stats are pushed to on every report, as host:port
+    pub statsd_addr: Option<String>,
+    /// overwrite this csv file with the count of every error on every report and at the
+    /// end of the run
+    pub error_histogram_file: Option<String>,
+    /// seconds between two stats reports, stdout reports are disabled when 0
+    pub report_interval_secs: u64,
+    /// seconds after start during which timeouts do not count against the success rates
+    /// and reports are marked as warming up
+    pub stats_warmup_secs: u64,
+    /// serve /healthz, /readyz and the effective configuration at /config on this port
+    pub health_port: Option<u16>,
+    /// also serve the last this many transaction confirmations at /recent-transactions on
+    /// the health port, 0 disables it
+    pub recent_transactions_size: usize,
+    /// accept commands adding and removing markets on this unix socket
+    pub admin_socket: Option<String>,
+    /// /healthz fails and transactions are not sent when the blockhash was not updated for
+    /// this many seconds
+    pub health_max_blockhash_age_secs: u64,
+    /// save event heap depth of every market on each heap update
+    pub heap_depth_save_file: Option<String>,
+    /// also save transaction confirmations and block data to postgres
+    pub postgres_url: Option<String>,
+    /// interval at which the send bridge drains pending transactions
+    pub transaction_retry_in_ms: u64,
+    /// send the transactions of at most this many distinct markets per bridge interval
+    pub max_concurrent_markets: Option<usize>,
+    /// fetch blocks which failed to download once more in the next confirmation pass
+    pub retry_failed_blocks: bool,
+    /// start fetching blocks for confirmations this many slots before the current slot
+    pub confirmation_lookback_slots: u64,
+    /// seconds after which an unconfirmed transaction is marked as timed out
+    pub confirmation_timeout_secs: u64,
+    /// seconds between two passes looking for timed out transactions
+    pub cleaner_interval_secs: u64,
+    /// seconds between two fetches of the new blocks confirming transactions
+    pub confirmation_refresh_secs: u64,
+    /// move every confirmation refresh randomly by up to this percentage of the refresh
+    /// interval so that cranks sharing an RPC do not fetch their blocks together
+    pub confirmation_refresh_jitter_percent: u64,
+    /// look up transactions still unconfirmed after half the confirmation timeout with
+    /// getSignatureStatuses
+    pub signature_status_fallback: bool,
+    /// save block data only for blocks containing at least one crank transaction
+    pub block_data_only_ours: bool,
+    /// write block data neither to a file nor to postgres
+    pub no_block_data_output: bool,
+    /// snapshot unconfirmed transactions to this file and confirm them again after a
+    /// restart, combine with confirmation_lookback_slots to fetch the blocks missed meanwhile
+    pub pending_transactions_file: Option<String>,
+    /// batch consume events of markets updated within this many milliseconds into one
+    /// transaction, disabled when 0
+    pub market_batch_window_ms: u64,
+    /// resend transactions still unconfirmed after this many seconds, disabled when 0
+    pub rebroadcast_after_secs: u64,
+    /// send at most this many transactions per second
+    pub max_tps: Option<u32>,
+    /// transactions waiting for the rate limiter, the oldest are dropped above it
+    pub max_tps_queue: usize,
+    /// pause sending when the ratio of successful transactions drops below this value
+    pub circuit_breaker_min_success_rate: Option<f64>,
+    /// window in seconds over which the circuit breaker success rate is computed
+    pub circuit_breaker_window_secs: u64,
+    /// minimum number of confirmation outcomes in the window to pause sending
+    pub circuit_breaker_min_txs: usize,
+    /// seconds to pause sending before probing again
+    pub circuit_breaker_cooldown_secs: u64,
+    /// stop cranking a market once this many of its transactions failed in a row
+    pub market_max_consecutive_failures: Option<usize>,
+    /// crank a market stopped after its failures again after this many seconds, never
+    /// when unset
+    pub market_reprobe_secs: Option<u64>,
+    /// warn when a market event heap holds at least this many events
+    pub backlog_alert_threshold: Option<usize>,
+    /// log an error when the seq_num of a market with a backlog did not move for this many
+    /// event heap updates, disabled when 0
+    pub stuck_heap_updates: usize,
+    /// compute unit price in micro lamports, used as a floor with dynamic_priority_fee
+    pub priority_fee: u64,
+    /// set the compute unit price from recent prioritization fees of the cranked accounts
+    pub dynamic_priority_fee: bool,
+    /// lowest compute unit price set with dynamic_priority_fee, defaults to priority_fee
+    pub priority_fee_min: Option<u64>,
+    /// highest compute unit price ever paid, also caps priority_fee
+    pub priority_fee_max: Option<u64>,
+    /// raise the compute unit price of markets whose event heap grows quickly following
+    /// adaptive_fee_curve, within priority_fee_min and priority_fee_max
+    pub adaptive_fee_by_backlog: bool,
+    /// fees by backlog growth rate in events per second, interpolated between the points
+    pub adaptive_fee_curve: Vec<FeeCurvePoint>,
+    /// stream event heaps from this Yellowstone gRPC endpoint instead of the websocket,
+    /// requires the grpc feature. Markets with their own endpoints keep their websocket
+    pub grpc_url: Option<String>,
+    /// x-token sent to the gRPC endpoint
+    pub grpc_x_token: Option<String>,
+    pub program_id: Pubkey,
+    /// markets to crank, or allowlist of the discovered markets
+    pub markets: Vec<Pubkey>,
+    /// address lookup table holding frequently used event accounts
+    pub lookup_table: Option<Pubkey>,
+    /// sign over the blockhash stored in this durable nonce account, advancing it in every
+    /// transaction, instead of over the recent blockhash
+    pub nonce_account: Option<Pubkey>,
+    /// json file with per market configuration, re-read by `Crank::reload_markets`
+    pub markets_config_file: Option<String>,
+    /// text file with one market pubkey per line added to `markets`, re-read by
+    /// `Crank::reload_markets`
+    pub markets_file: Option<String>,
+    /// discover all markets of the program using getProgramAccounts
+    pub discover_markets: bool,
+    /// markets never cranked when discovering markets
+    pub exclude_markets: Vec<Pubkey>,
+    /// send transactions as bundles to this jito block engine instead of over TPU or RPC
+    pub jito_block_engine_url: Option<String>,
+    /// lamports tipped to jito in every transaction sent as a bundle
+    pub jito_tip_lamports: Option<u64>,
+    /// send over TPU, RPC or both, defaults to TPU when a TPU identity is given and to RPC
+    /// otherwise
+    pub send_mode: Option<SendMode>,
+    /// only consume events of these types
+    pub event_types: EventTypeFilter,
+    /// consume at most this many events per instruction, below the number of events whose
+    /// accounts fit in the transaction
+    pub consume_limit: Option<usize>,
+    /// simulate the first transaction of every market after start and drop it when the
+    /// simulation fails, later transactions of validated markets are sent right away
+    pub validate_first_send: bool,
+    /// the rpc simulates the transactions sent over rpc and rejects the failing ones
+    pub rpc_preflight: bool,
+    /// read the event heaps of the markets of every successful transaction again once it
+    /// is confirmed and count the transactions which consumed no event
+    pub verify_consumption: bool,
+    /// experimental, hold transactions until a leader which already included some of ours
+    /// is scheduled within the next slots. Fewer transactions are wasted but cranking can
+    /// be delayed by up to 10s
+    pub leader_aware_send: bool,
+    /// crank on fills only once the fills of a heap are worth this much, in price lots
+    /// times base lots, markets with a backlog are cranked regardless
+    pub min_fill_value: Option<u64>,
+    /// milliseconds before a market is cranked again after its last crank, fills of markets
+    /// cranking on any fill skip the wait, disabled when 0
+    pub min_crank_interval_ms: u64,
+    /// consume events instructions waiting for the transaction sender, when it is full the
+    /// oldest instruction of the same market is dropped as stale
+    pub instruction_queue_size: usize,
+    /// heaps holding fill or out events of these accounts are cranked right away, ignoring
+    /// the backlog rule, the minimum fill value and the crank interval
+    pub priority_owners: Vec<Pubkey>,
+    /// crank all markets a single time with `Crank::run_once` instead of following updates
+    pub once: bool,
+    /// follow only this market and print its events and the crank decision of every update
+    /// instead of cranking
+    pub debug_market: Option<Pubkey>,
+}
+
+impl Default for CrankConfig {
+    fn default() -> Self {
+        Self {
+            rpc_url: String::from("http://127.0.0.1:8899"),
+            confirmation_rpc_url: None,
+            ws_url: String::from("ws://127.0.0.1:8900"),
+            commitment: CommitmentLevel::Finalized,
+            confirmation_commitment: CommitmentLevel::Confirmed,
+            recheck_finalized: false,
+            max_concurrent_block_fetches: 8,
+            max_pending_transactions: None,
+            unresolved_high_water_mark: None,
+            unresolved_low_water_mark: None,
+            confirmation_lag_warning_slots: 150,
+            confirmation_depth_slots: 0,
+            no_leader_tracking: false,
+            adaptive_timeout: false,
+            retry_expired_blockhash: false,
+            fanout_size: 16,
+            tpu_send_retries: 0,
+            tpu_connection_pool_size: 4,
+            tpu_reset_interval_secs: 600,
+            tpu_batch_size: 16,
+            transaction_save_file: None,
+            block_data_save_file: None,
+            result_flush_interval_secs: 5,
+            result_rotate_max_bytes: None,
+            result_rotate_max_age_secs: None,
+            no_csv_header: false,
+            run_tag: None,
+            stats_json_file: None,
+            statsd_addr: None,
+            error_histogram_file: None,
+            report_interval_secs: 60,
+            stats_warmup_secs: 0,
+            health_port: None,
+            recent_transactions_size: 100,
+            admin_socket: None,
+            health_max_blockhash_age_secs: 60,
+            heap_depth_save_file: None,
+            postgres_url: None,
+            transaction_retry_in_ms: 10,
+            max_concurrent_markets: None,
+            retry_failed_blocks: false,
+            confirmation_lookback_slots: 0,
+            confirmation_timeout_secs: 120,
+            cleaner_interval_secs: 10,
+            confirmation_refresh_secs: 10,
+            confirmation_refresh_jitter_percent: 0,
+            signature_status_fallback: false,
+            block_data_only_ours: false,
+            no_block_data_output: false,
+            pending_transactions_file: None,
+            market_batch_window_ms: 0,
+            rebroadcast_after_secs: 0,
+            max_tps: None,
+            max_tps_queue: 1000,
+            circuit_breaker_min_success_rate: None,
+            circuit_breaker_window_secs: 60,
+            circuit_breaker_min_txs: 20,
+            circuit_breaker_cooldown_secs: 30,
+            market_max_consecutive_failures: None,
+            market_reprobe_secs: None,
+            backlog_alert_threshold: None,
+            stuck_heap_updates: 20,
+            priority_fee: 1000,
+            dynamic_priority_fee: false,
+            priority_fee_min: None,
+            priority_fee_max: None,
+            adaptive_fee_by_backlog: false,
+            adaptive_fee_curve: DEFAULT_ADAPTIVE_FEE_CURVE
+                .split(',')
+                .map(|x| x.parse().unwrap())
+                .collect(),
+            grpc_url: None,
+            grpc_x_token: None,
+            program_id: openbook_v2::ID,
+            markets: vec![],
+            lookup_table: None,
+            nonce_account: None,
+            markets_config_file: None,
+            markets_file: None,
+            discover_markets: false,
+            exclude_markets: vec![],
+            jito_block_engine_url: None,
+            jito_tip_lamports: None,
+            send_mode: None,
+            event_types: EventTypeFilter::All,
+            consume_limit: None,
+            validate_first_send: false,
+            rpc_preflight: false,
+            verify_consumption: false,
+            leader_aware_send: false,
+            min_fill_value: None,
+            min_crank_interval_ms: 0,
+            instruction_queue_size: 1000,
+            priority_owners: vec![],
+            once: false,
+            debug_market: None,
+        }
+    }
+}
+
+impl CrankConfig {
+    /// copy of the config with the credentials of the urls hidden
+    fn redacted(&self) -> Self {
+        Self {
+            rpc_url: redact_url(&self.rpc_url),
+            ws_url: redact_url(&self.ws_url),
+            confirmation_rpc_url: self.confirmation_rpc_url.as_deref().map(redact_url),
+            postgres_url: self.postgres_url.as_deref().map(redact_url),
+            jito_block_engine_url: self.jito_block_engine_url.as_deref().map(redact_url),
+            grpc_url: self.grpc_url.as_deref().map(redact_url),
+            grpc_x_token: self.grpc_x_token.as_ref().map(|_| "***".to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct CrankBuilder {
+    config: CrankConfig,
+    crank_authority: Option<CrankAuthority>,
+    fee_payer: Option<Keypair>,
+    nonce_authority: Option<Keypair>,
+    tpu_identities: Vec<Keypair>,
+}
+
+impl CrankBuilder {
+    /// replaces all the tuning knobs, call it before the other setters
+    pub fn config(mut self, config: CrankConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn rpc_url(mut self, rpc_url: impl Into<String>) -> Self {
+        self.config.rpc_url = rpc_url.into();
+        self
+    }
+
+    pub fn ws_url(mut self, ws_url: impl Into<String>) -> Self {
+        self.config.ws_url = ws_url.into();
+        self
+    }
+
+    pub fn markets(mut self, markets: Vec<Pubkey>) -> Self {
+        self.config.markets = markets;
+        self
+    }
+
+    pub fn crank_authority(mut self, crank_authority: Keypair) -> Self {
+        self.crank_authority = Some(CrankAuthority::Keypair(crank_authority));
+        self
+    }
+
+    /// signs for the crank authority with a remote signer instead of a local keypair, a fee
+    /// payer is then required
+    pub fn remote_crank_authority(mut self, remote_signer: RemoteSigner) -> Self {
+        self.crank_authority = Some(CrankAuthority::Remote(remote_signer));
+        self
+    }
+
+    /// keypair paying the transaction fees, defaults to the crank authority when it is a
+    /// local keypair
+    pub fn fee_payer(mut self, fee_payer: Keypair) -> Self {
+        self.fee_payer = Some(fee_payer);
+        self
+    }
+
+    /// authority of the durable nonce account, defaults to the fee payer
+    pub fn nonce_authority(mut self, nonce_authority: Keypair) -> Self {
+        self.nonce_authority = Some(nonce_authority);
+        self
+    }
+
+    /// identity of the TPU connections, transactions are sent over TPU instead of over RPC
+    /// unless another send mode is configured. Call it several times to rotate batches
+    /// among identities
+    pub fn tpu_identity(mut self, tpu_identity: Keypair) -> Self {
+        self.tpu_identities.push(tpu_identity);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Crank> {
+        let crank_authority = self
+            .crank_authority
+            .context("a crank authority is required")?;
+        if self.config.markets.is_empty()
+            && self.config.markets_file.is_none()
+            && !self.config.discover_markets
+        {
+            anyhow::bail!("no markets to crank");
+        }
+        if !matches!(
+            self.config.confirmation_commitment,
+            CommitmentLevel::Confirmed | CommitmentLevel::Finalized
+        ) {
+            anyhow::bail!("the confirmation commitment must be confirmed or finalized");
+        }
+        let nb_stdout_files = [
+            &self.config.transaction_save_file,
+            &self.config.block_data_save_file,
+            &self.config.heap_depth_save_file,
+        ]
+        .iter()
+        .filter(|x| x.as_deref() == Some(STDOUT_PATH))
+        .count();
+        if nb_stdout_files > 1 {
+            anyhow::bail!(
+                "only one of the transaction, block data and heap depth files can be written to stdout"
+            );
+        }
+        // the reports would be interleaved with the csv lines
+        if nb_stdout_files > 0 && self.config.report_interval_secs > 0 {
+            anyhow::bail!("a result file written to stdout requires --report-interval-secs 0");
+        }
+        // the flush ticker panics on a zero period
+        if self.config.result_flush_interval_secs == 0 {
+            anyhow::bail!("the result flush interval must be at least 1 second");
+        }
+        // the send bridge would spin without sleeping
+        if self.config.transaction_retry_in_ms == 0 {
+            anyhow::bail!("the send bridge interval must be at least 1 millisecond");
+        }
+        if self.config.unresolved_high_water_mark == Some(0) {
+            anyhow::bail!("the unresolved transactions high water mark must be at least 1");
+        }
+        if let (Some(high), Some(low)) = (
+            self.config.unresolved_high_water_mark,
+            self.config.unresolved_low_water_mark,
+        ) {
+            if low >= high {
+                anyhow::bail!(
+                    "the unresolved transactions low water mark must be below the high one"
+                );
+            }
+        }
+        if self.config.max_pending_transactions == Some(0) {
+            anyhow::bail!("at least one transaction must be able to wait for a confirmation");
+        }
+        if self.config.max_tps == Some(0) {
+            anyhow::bail!("the max tps must be at least 1 transaction per second");
+        }
+        // every transaction would be dropped as soon as it is queued
+        if self.config.max_tps_queue == 0 {
+            anyhow::bail!("at least one transaction must be able to wait for the max tps");
+        }
+        // every transaction would time out as soon as it is sent
+        if self.config.confirmation_timeout_secs == 0 {
+            anyhow::bail!("the confirmation timeout must be at least 1 second");
+        }
+        // slots last about 400ms
+        if self.config.confirmation_depth_slots > 0
+            && self.config.confirmation_depth_slots * 2 / 5 >= self.config.confirmation_timeout_secs
+        {
+            anyhow::bail!("transactions would time out before reaching the confirmation depth");
+        }
+        if self.config.debug_market.is_some() && self.config.once {
+            anyhow::bail!("a debugged market is followed, it cannot be cranked once");
+        }
+        if self.config.no_block_data_output && self.config.block_data_save_file.is_some() {
+            anyhow::bail!("block data cannot be saved with no_block_data_output");
+        }
+        if self.config.retry_expired_blockhash && self.config.nonce_account.is_some() {
+            anyhow::bail!("transactions signed over a durable nonce do not expire");
+        }
+        if self.config.market_max_consecutive_failures == Some(0) {
+            anyhow::bail!("a market cannot be disabled before any failure");
+        }
+        if self.config.max_concurrent_markets == Some(0) {
+            anyhow::bail!("at least one market must be able to send transactions");
+        }
+        // the confirmation cleaner and the signature status fallback would spin without
+        // sleeping and the finalized recheck ticker panics on a zero period
+        if self.config.cleaner_interval_secs == 0 {
+            anyhow::bail!("the confirmation cleaner interval must be at least 1 second");
+        }
+        if self.config.confirmation_refresh_secs == 0 {
+            anyhow::bail!("confirmations are refreshed at most every second");
+        }
+        if self.config.confirmation_refresh_jitter_percent > 100 {
+            anyhow::bail!("the confirmation refresh jitter is at most 100%");
+        }
+        if self.config.tpu_batch_size == 0 {
+            anyhow::bail!("tpu batches hold at least one transaction");
+        }
+        if self.config.tpu_connection_pool_size == 0 {
+            anyhow::bail!("at least one tpu connection per leader is required");
+        }
+        if self.config.max_concurrent_block_fetches == 0 {
+            anyhow::bail!("at least one block must be fetched at a time");
+        }
+        if let (Some(min), Some(max)) = (self.config.priority_fee_min, self.config.priority_fee_max)
+        {
+            if min > max {
+                anyhow::bail!("the minimum priority fee is above the maximum");
+            }
+        }
+        if self.config.adaptive_fee_by_backlog && self.config.adaptive_fee_curve.is_empty() {
+            anyhow::bail!("the adaptive fee requires at least one point in its curve");
+        }
+        if self.config.instruction_queue_size == 0 {
+            anyhow::bail!("the instruction queue must hold at least one instruction");
+        }
+        if self.config.consume_limit == Some(0) {
+            anyhow::bail!("the consume limit must be at least 1");
+        }
+        if matches!(self.config.send_mode, Some(SendMode::Tpu | SendMode::Both))
+            && self.tpu_identities.is_empty()
+        {
+            anyhow::bail!("sending over TPU requires a TPU identity");
+        }
+        #[cfg(not(feature = "grpc"))]
+        if self.config.grpc_url.is_some() {
+            anyhow::bail!("streaming over gRPC requires building with the grpc feature");
+        }
+        if self.config.leader_aware_send && self.tpu_identities.is_empty() {
+            anyhow::bail!("leader aware sending requires sending over TPU");
+        }
+        if self.config.leader_aware_send && self.config.no_leader_tracking {
+            anyhow::bail!("leader aware sending requires tracking the slot leaders");
+        }
+        let fee_payer = match (self.fee_payer, &crank_authority) {
+            (Some(fee_payer), _) => fee_payer,
+            (None, CrankAuthority::Keypair(keypair)) => keypair.insecure_clone(),
+            (None, CrankAuthority::Remote(_)) => {
+                anyhow::bail!("a fee payer is required with a remote crank authority")
+            }
+        };
+        let nonce_authority = match self.nonce_authority {
+            Some(nonce_authority) => nonce_authority,
+            None => fee_payer.insecure_clone(),
+        };
+        let rpc_client = Arc::new(RpcClient::new_with_commitment(
+            self.config.rpc_url.clone(),
+            CommitmentConfig {
+                commitment: self.config.commitment,
+            },
+        ));
+        Ok(Crank {
+            config: self.config,
+            crank_authority,
+            fee_payer,
+            nonce_authority,
+            tpu_identities: self.tpu_identities,
+            rpc_client,
+            markets_handle: Mutex::new(None),
+            stats: Mutex::new(None),
+            tasks: Mutex::new(vec![]),
+            result_writers: Mutex::new(vec![]),
+            shutdown: Notify::new(),
+        })
+    }
+}
+
+/// The whole crank: market tracking, transaction sending, confirmations and reporting
+pub struct Crank {
+    config: CrankConfig,
+    crank_authority: CrankAuthority,
+    fee_payer: Keypair,
+    nonce_authority: Keypair,
+    tpu_identities: Vec<Keypair>,
+    rpc_client: Arc<RpcClient>,
+    markets_handle: Mutex<Option<MarketsHandle>>,
+    stats: Mutex<Option<CrankStats>>,
+    // services with a name to tell which one stopped
+    tasks: Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+    // not aborted on shutdown, they finish once the services sending records are stopped
+    result_writers: Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+    shutdown: Notify,
+}
+
+impl Crank {
+    pub fn builder() -> CrankBuilder {
+        CrankBuilder::default()
+    }
+
+    /// Fetches the markets and spawns all the crank services
+    pub async fn start(&self) -> anyhow::Result<()> {
+        self.start_services().await?;
+        Ok(())
+    }
+
+    /// Consumes the events buffered in every event heap, waits until the sent transactions
+    /// are confirmed or timed out, prints the report and stops the services
+    pub async fn run_once(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.config.once, "run_once requires the once config");
+        let stats = self.start_services().await?;
+        let markets_handle = self
+            .markets_handle
+            .lock()
+            .unwrap()
+            .clone()
+            .context("the crank is not started")?;
+
+        let nb_queued = markets_handle.crank_once(&self.rpc_client).await?;
+        log::info!("queued consume events for {nb_queued} event heaps");
+
+        if nb_queued > 0 {
+            // leave time for the batch window and the send bridge before counting
+            let grace =
+                Duration::from_millis(self.config.market_batch_window_ms) + Duration::from_secs(2);
+            tokio::time::sleep(grace).await;
+            // the adaptive timeout waits up to 4 times longer
+            let timeout_secs = if self.config.adaptive_timeout {
+                self.config.confirmation_timeout_secs * 4
+            } else {
+                self.config.confirmation_timeout_secs
+            };
+            let deadline = tokio::time::Instant::now()
+                + Duration::from_secs(timeout_secs + self.config.cleaner_interval_secs);
+            while markets_handle.pending_instructions() > 0 || stats.nb_unresolved_txs() > 0 {
+                if tokio::time::Instant::now() >= deadline {
+                    log::warn!(
+                        "{} transactions still unconfirmed, exiting",
+                        stats.nb_unresolved_txs()
+                    );
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+
+        self.shutdown();
+        stats.report_final().await;
+        self.close_result_writers().await;
+        Ok(())
+    }
+
+    async fn start_services(&self) -> anyhow::Result<CrankStats> {
+        let config = &self.config;
+        let rpc_client = self.rpc_client.clone();
+
+        let health = HealthStatus::new();
+        let recent_transactions = (config.health_port.is_some()
+            && config.recent_transactions_size > 0)
+            .then(|| RecentTransactions::new(config.recent_transactions_size));
+        let health_task = config.health_port.map(|port| {
+            start_health_server(
+                port,
+                health.clone(),
+                Duration::from_secs(config.health_max_blockhash_age_secs),
+                recent_transactions.clone(),
+            )
+        });
+
+        let markets = self.resolve_markets_with_retry().await?;
+        health.set_markets_fetched();
+
+        let (lookup_table, lookup_table_polling_task) = match config.lookup_table {
+            Some(lookup_table) => {
+                let lookup_table = Arc::new(RwLock::new(
+                    fetch_lookup_table(&rpc_client, lookup_table).await?,
+                ));
+                let task =
+                    start_lookup_table_polling_service(rpc_client.clone(), lookup_table.clone());
+                (Some(lookup_table), Some(task))
+            }
+            None => (None, None),
+        };
+        log::info!("cranking {} markets", markets.len());
+
+        // fetched once here so that a wrong nonce account fails the start
+        let (durable_nonce, nonce_polling_task) = match config.nonce_account {
+            Some(account) => {
+                let nonce = fetch_nonce(&rpc_client, &account)
+                    .await
+                    .context("cannot fetch the nonce account")?;
+                let value = Arc::new(RwLock::new(Some(nonce)));
+                let task = start_nonce_polling_service(rpc_client.clone(), account, value.clone());
+                let durable_nonce = crank::DurableNonce {
+                    account,
+                    authority: Arc::new(self.nonce_authority.insecure_clone()),
+                    value,
+                };
+                (Some(durable_nonce), Some(task))
+            }
+            None => (None, None),
+        };
+
+        // create a task that updates blockhash after every interval
+        let recent_blockhash = retry_with_backoff("fetch the latest blockhash", || async {
+            rpc_client.get_latest_blockhash().await.map_err(rpc_error)
+        })
+        .await?;
+        let last_slot = retry_with_backoff("fetch the current slot", || async {
+            rpc_client.get_slot().await.map_err(rpc_error)
+        })
+        .await?;
+        let blockhash_rw = Arc::new(RwLock::new(recent_blockhash));
+        let current_slot = Arc::new(AtomicU64::new(last_slot));
+        let slot_subscribed = Arc::new(AtomicBool::new(false));
+        let slot_subscription_task = start_slot_subscription_service(
+            config.ws_url.clone(),
+            current_slot.clone(),
+            slot_subscribed.clone(),
+        );
+        let bh_polling_task = start_blockhash_polling_service(
+            blockhash_rw.clone(),
+            current_slot.clone(),
+            slot_subscribed,
+            rpc_client.clone(),
+            health.clone(),
+        );
+
+        let crank_stats = CrankStats::new(
+            config.stats_json_file.clone(),
+            config.error_histogram_file.clone(),
+            health.clone(),
+            Duration::from_secs(config.stats_warmup_secs),
+            config
+                .statsd_addr
+                .as_deref()
+                .map(StatsdClient::new)
+                .transpose()?,
+        );
+        let (tx_sx, tx_rx) = unbounded_channel();
+        let (tx_send_record_sx, tx_send_record_rx) = unbounded_channel();
+        let (tx_confirmation_sx, tx_confirmation_rx) = tokio::sync::broadcast::channel(8192);
+        let (blocks_confirmation_sx, blocks_confirmation_rx) =
+            tokio::sync::broadcast::channel(8192);
+        let (heap_depth_sx, heap_depth_rx) = tokio::sync::broadcast::channel(8192);
+
+        let send_mode = config
+            .send_mode
+            .unwrap_or(if self.tpu_identities.is_empty() {
+                SendMode::Rpc
+            } else {
+                SendMode::Tpu
+            });
+
+        let effective_config = self.effective_config(&markets, send_mode);
+        log::info!("effective configuration: {effective_config}");
+        health.set_effective_config(effective_config);
+
+        let bridge_interval = Duration::from_millis(config.transaction_retry_in_ms);
+        log::info!(
+            "send bridge drains transactions every {}ms",
+            config.transaction_retry_in_ms
+        );
+
+        // the expired blockhash retry also resends transactions the rpc rejected
+        let blockhash_not_found_sends = config
+            .retry_expired_blockhash
+            .then(rpc_manager::BlockhashNotFoundSends::default);
+
+        // start transaction send bridge either as jito bundles, over TPU, over RPC or both
+        let mut tpu_reset_task = None;
+        let (transaction_send_bridge_task, resender): (_, Arc<dyn TransactionResender>) =
+            if let Some(block_engine_url) = &config.jito_block_engine_url {
+                let jito_manager = Arc::new(JitoManager::new(
+                    block_engine_url,
+                    tx_send_record_sx,
+                    crank_stats.clone(),
+                ));
+                (
+                    create_jito_transaction_bridge(tx_rx, jito_manager.clone(), bridge_interval),
+                    jito_manager,
+                )
+            } else if send_mode != SendMode::Rpc {
+                let tpu_manager = Arc::new(
+                    tpu_manager::TpuManager::new(
+                        rpc_client.clone(),
+                        config.ws_url.clone(),
+                        config.fanout_size,
+                        self.tpu_identities
+                            .iter()
+                            .map(|x| x.insecure_clone())
+                            .collect(),
+                        config.tpu_send_retries,
+                        config.tpu_connection_pool_size,
+                        tx_send_record_sx.clone(),
+                        crank_stats.clone(),
+                    )
+                    .await?,
+                );
+                log::info!(
+                    "sending transactions over TPU in batches of up to {}",
+                    config.tpu_batch_size
+                );
+                if config.tpu_reset_interval_secs > 0 {
+                    tpu_reset_task = Some(tpu_manager.force_reset_after_every(
+                        Duration::from_secs(config.tpu_reset_interval_secs),
+                    ));
+                }
+                if send_mode == SendMode::Both {
+                    // the rpc manager only sends, send records come from the tpu manager
+                    let rpc_manager = Arc::new(rpc_manager::RpcManager::new(
+                        rpc_client.clone(),
+                        tx_send_record_sx,
+                        config.rpc_preflight,
+                        blockhash_not_found_sends.clone(),
+                        crank_stats.clone(),
+                    ));
+                    (
+                        create_dual_transaction_bridge(
+                            tx_rx,
+                            tpu_manager.clone(),
+                            rpc_manager.clone(),
+                            config.tpu_batch_size,
+                            bridge_interval,
+                        ),
+                        Arc::new(FanOutResender(vec![tpu_manager, rpc_manager])),
+                    )
+                } else {
+                    (
+                        create_tpu_transaction_bridge(
+                            tx_rx,
+                            tpu_manager.clone(),
+                            config.tpu_batch_size,
+                            bridge_interval,
+                        ),
+                        tpu_manager,
+                    )
+                }
+            } else {
+                let rpc_manager = Arc::new(rpc_manager::RpcManager::new(
+                    rpc_client.clone(),
+                    tx_send_record_sx,
+                    config.rpc_preflight,
+                    blockhash_not_found_sends.clone(),
+                    crank_stats.clone(),
+                ));
+                (
+                    create_rpc_transaction_bridge(tx_rx, rpc_manager.clone(), bridge_interval),
+                    rpc_manager,
+                )
+            };
+
+        // rebroadcasts take from the same budget as the transactions sent for the first time
+        let tps_budget = config.max_tps.map(TpsBudget::new);
+        let resender: Arc<dyn TransactionResender> = match &tps_budget {
+            Some(budget) => Arc::new(RateLimitedResender {
+                resender,
+                budget: budget.clone(),
+            }),
+            None => resender,
+        };
+
+        // resend unconfirmed transactions by going through the rebroadcast service, right
+        // before the bridge so that the transactions dropped on their way are not tracked
+        let (tx_sx, rebroadcast_services) = if config.rebroadcast_after_secs > 0 {
+            let (rebroadcast_tx_sx, rebroadcast_tx_rx) = unbounded_channel();
+            let services = start_rebroadcast_service(
+                rebroadcast_tx_rx,
+                tx_sx,
+                tx_confirmation_sx.subscribe(),
+                resender,
+                current_slot.clone(),
+                Duration::from_secs(config.rebroadcast_after_secs),
+            );
+            (rebroadcast_tx_sx, services)
+        } else {
+            (tx_sx, vec![])
+        };
+
+        // limit the rate at which transactions reach the bridge
+        let (tx_sx, rate_limiter_task) = match tps_budget {
+            Some(budget) => {
+                let (limited_tx_sx, limited_tx_rx) = unbounded_channel();
+                let task = start_rate_limiter(
+                    limited_tx_rx,
+                    tx_sx,
+                    budget,
+                    config.max_tps_queue,
+                    crank_stats.clone(),
+                );
+                (limited_tx_sx, Some(task))
+            }
+            None => (tx_sx, None),
+        };
+
+        // spread the markets sending at the same time over several bridge intervals
+        let (tx_sx, market_limiter_task) = match config.max_concurrent_markets {
+            Some(max_markets) => {
+                let (limited_tx_sx, limited_tx_rx) = unbounded_channel();
+                let task = start_market_limiter(limited_tx_rx, tx_sx, max_markets, bridge_interval);
+                (limited_tx_sx, Some(task))
+            }
+            None => (tx_sx, None),
+        };
+
+        // do not send transactions signed with a blockhash which may have expired
+        let (crank_tx_sx, blockhash_guard_task) = {
+            let (guarded_tx_sx, guarded_tx_rx) = unbounded_channel();
+            let task = start_blockhash_guard(
+                guarded_tx_rx,
+                tx_sx,
+                health.clone(),
+                Duration::from_secs(config.health_max_blockhash_age_secs),
+                crank_stats.clone(),
+            );
+            (guarded_tx_sx, task)
+        };
+
+        // pause sending while most transactions fail
+        let (crank_tx_sx, circuit_breaker_services) = match config.circuit_breaker_min_success_rate
+        {
+            Some(min_success_rate) => {
+                let (breaker_tx_sx, breaker_tx_rx) = unbounded_channel();
+                let services = start_circuit_breaker(
+                    breaker_tx_rx,
+                    crank_tx_sx,
+                    tx_confirmation_sx.subscribe(),
+                    CircuitBreakerConfig {
+                        min_success_rate,
+                        window: Duration::from_secs(config.circuit_breaker_window_secs),
+                        min_txs: config.circuit_breaker_min_txs,
+                        cooldown: Duration::from_secs(config.circuit_breaker_cooldown_secs),
+                    },
+                );
+                (breaker_tx_sx, services)
+            }
+            None => (crank_tx_sx, vec![]),
+        };
+
+        let prioritization_fee = Arc::new(AtomicU64::new(
+            config
+                .priority_fee_max
+                .map_or(config.priority_fee, |x| config.priority_fee.min(x)),
+        ));
+        let leader_schedule = config.leader_aware_send.then(LeaderSchedule::default);
+
+        // start event queue crank
+        let (crank_tasks, markets_handle) = crank::start(
+            crank::KeeperConfig {
+                program_id: config.program_id,
+                rpc_url: config.rpc_url.to_string(),
+                websocket_url: config.ws_url.to_string(),
+                market_batch_window: (config.market_batch_window_ms > 0)
+                    .then(|| Duration::from_millis(config.market_batch_window_ms)),
+                prioritization_fee: prioritization_fee.clone(),
+                backlog_alert_threshold: config.backlog_alert_threshold,
+                stuck_heap_updates: config.stuck_heap_updates,
+                lookup_table,
+                jito_tip_lamports: config
+                    .jito_block_engine_url
+                    .is_some()
+                    .then_some(config.jito_tip_lamports)
+                    .flatten(),
+                event_types: config.event_types,
+                consume_limit: config.consume_limit,
+                validate_first_send: config.validate_first_send,
+                verify_consumption: config.verify_consumption,
+                leader_schedule: leader_schedule.clone(),
+                min_fill_value: config.min_fill_value,
+                min_crank_interval: (config.min_crank_interval_ms > 0)
+                    .then(|| Duration::from_millis(config.min_crank_interval_ms)),
+                instruction_queue_size: config.instruction_queue_size,
+                priority_owners: config.priority_owners.iter().copied().collect(),
+                grpc_source: config.grpc_url.clone().map(|url| crank::GrpcSourceConfig {
+                    url,
+                    x_token: config.grpc_x_token.clone(),
+                }),
+                adaptive_fee: config.adaptive_fee_by_backlog.then(|| {
+                    AdaptiveFee::new(
+                        config.adaptive_fee_curve.clone(),
+                        config.priority_fee_min.unwrap_or(config.priority_fee),
+                        config.priority_fee_max,
+                    )
+                }),
+                durable_nonce,
+                once: config.once,
+                retry_expired_blockhash: config.retry_expired_blockhash,
+                blockhash_not_found_sends,
+                debug_events: config.debug_market.is_some(),
+            },
+            blockhash_rw.clone(),
+            current_slot.clone(),
+            &markets,
+            &self.crank_authority,
+            &self.fee_payer,
+            crank_tx_sx,
+            crank_stats.clone(),
+            config
+                .heap_depth_save_file
+                .is_some()
+                .then_some(heap_depth_sx),
+            &tx_confirmation_sx,
+        );
+
+        let mut crank_services = named("crank", crank_tasks).collect_vec();
+
+        // stop wasting fees on markets whose transactions keep failing
+        if let Some(max_failures) = config.market_max_consecutive_failures {
+            crank_services.push((
+                "market breaker",
+                start_market_breaker(
+                    tx_confirmation_sx.subscribe(),
+                    markets_handle.clone(),
+                    max_failures,
+                    config.market_reprobe_secs.map(Duration::from_secs),
+                ),
+            ));
+        }
+
+        // the sink backs off while the confirmation side catches up
+        if let Some(high_water_mark) = config.unresolved_high_water_mark {
+            crank_services.push((
+                "backpressure",
+                start_backpressure(
+                    crank_stats.clone(),
+                    markets_handle.clone(),
+                    high_water_mark,
+                    config
+                        .unresolved_low_water_mark
+                        .unwrap_or(high_water_mark / 2),
+                ),
+            ));
+        }
+
+        // start confirmations by blocks
+        let stats_tasks = [
+            crank_stats.update_from_tx_status_stream(tx_confirmation_sx.subscribe()),
+            crank_stats.update_from_block_stream(blocks_confirmation_sx.subscribe()),
+        ];
+        // joined with the result writers on shutdown so that their last batches are inserted
+        let postgres_writers = match config.postgres_url.clone() {
+            Some(postgres_url) => initialize_postgres_writers(
+                postgres_url,
+                tx_confirmation_sx.subscribe(),
+                (!config.no_block_data_output).then(|| blocks_confirmation_sx.subscribe()),
+            ),
+            None => vec![],
+        };
+
+        if let Some(recent_transactions) = &recent_transactions {
+            crank_services.push((
+                "recent transactions",
+                recent_transactions.start_recording(tx_confirmation_sx.subscribe()),
+            ));
+        }
+
+        // block fetching is bandwidth heavy and may go to a cheaper node than the sends
+        let confirmation_rpc_client = match &config.confirmation_rpc_url {
+            Some(confirmation_rpc_url) => Arc::new(RpcClient::new_with_commitment(
+                confirmation_rpc_url.clone(),
+                CommitmentConfig {
+                    commitment: config.commitment,
+                },
+            )),
+            None => rpc_client.clone(),
+        };
+        let confirmation_services = confirmations_by_blocks(
+            confirmation_rpc_client,
+            tx_send_record_rx,
+            tx_confirmation_sx,
+            blocks_confirmation_sx,
+            current_slot.clone(),
+            ConfirmationConfig {
+                retry_failed_blocks: config.retry_failed_blocks,
+                lookback_slots: config.confirmation_lookback_slots,
+                timeout: Duration::from_secs(config.confirmation_timeout_secs),
+                cleaner_interval: Duration::from_secs(config.cleaner_interval_secs),
+                refresh_interval: Duration::from_secs(config.confirmation_refresh_secs),
+                refresh_jitter_percent: config.confirmation_refresh_jitter_percent,
+                signature_status_fallback: config.signature_status_fallback,
+                block_data_only_ours: config.block_data_only_ours,
+                commitment: config.confirmation_commitment,
+                recheck_finalized: config.recheck_finalized,
+                max_concurrent_block_fetches: config.max_concurrent_block_fetches,
+                pending_transactions_file: config.pending_transactions_file.clone(),
+                market_timeouts: markets
+                    .iter()
+                    .filter_map(|x| {
+                        x.config
+                            .confirmation_timeout_secs
+                            .map(|secs| (x.market_pk, Duration::from_secs(secs)))
+                    })
+                    .collect(),
+                max_pending_transactions: config.max_pending_transactions,
+                lag_warning_slots: config.confirmation_lag_warning_slots,
+                depth_slots: config.confirmation_depth_slots,
+                leader_tracking: !config.no_leader_tracking,
+                adaptive_timeout: config.adaptive_timeout,
+            },
+            health.clone(),
+            crank_stats.clone(),
+        );
+
+        // start writing results
+        let run_tag = config.run_tag.clone().unwrap_or_else(|| {
+            format!(
+                "{}-{}",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+                std::process::id()
+            )
+        });
+        log::info!("tagging results with run tag {run_tag}");
+        let result_writers = initialize_result_writers(
+            config.transaction_save_file.clone(),
+            config.block_data_save_file.clone(),
+            config.heap_depth_save_file.clone(),
+            tx_confirmation_rx,
+            blocks_confirmation_rx,
+            heap_depth_rx,
+            ResultWriterConfig {
+                flush_interval: Duration::from_secs(config.result_flush_interval_secs),
+                rotate_max_bytes: config.result_rotate_max_bytes,
+                rotate_max_age: config.result_rotate_max_age_secs.map(Duration::from_secs),
+                csv_header: !config.no_csv_header,
+                run_tag,
+            },
+        );
+        *self.result_writers.lock().unwrap() = named("result writer", result_writers)
+            .chain(named("postgres writer", postgres_writers))
+            .collect();
+
+        // task which updates stats
+        // the json and error histogram files are still written and statsd still receives the
+        // stats every 60s when stdout reports are disabled
+        let print_reports = config.report_interval_secs > 0;
+        let write_reports = config.stats_json_file.is_some()
+            || config.error_histogram_file.is_some()
+            || config.statsd_addr.is_some();
+        let reporting_thread = (print_reports || write_reports).then(|| {
+            let mut stats = crank_stats.clone();
+            let report_interval = Duration::from_secs(if print_reports {
+                config.report_interval_secs
+            } else {
+                60
+            });
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(report_interval).await;
+                    stats.report(print_reports).await;
+                }
+            })
+        });
+
+        if let Some(admin_socket) = config.admin_socket.clone() {
+            crank_services.push((
+                "admin socket",
+                start_admin_socket(
+                    admin_socket,
+                    rpc_client.clone(),
+                    markets_handle.clone(),
+                    crank_stats.clone(),
+                ),
+            ));
+        }
+
+        if let Some(leader_schedule) = leader_schedule {
+            crank_services.push((
+                "leader schedule",
+                start_leader_schedule_service(
+                    rpc_client.clone(),
+                    current_slot.clone(),
+                    leader_schedule,
+                ),
+            ));
+        }
+
+        if config.dynamic_priority_fee {
+            crank_services.push((
+                "priority fees",
+                start_priority_fee_service(
+                    rpc_client.clone(),
+                    markets_handle.clone(),
+                    prioritization_fee,
+                    config.priority_fee_min.unwrap_or(config.priority_fee),
+                    config.priority_fee_max,
+                ),
+            ));
+        }
+
+        crank_services.extend(named("confirmation", confirmation_services));
+        crank_services.extend(named("rebroadcast", rebroadcast_services));
+        crank_services.extend(named("circuit breaker", circuit_breaker_services));
+        crank_services.push(("blockhash polling", bh_polling_task));
+        crank_services.push(("slot subscription", slot_subscription_task));
+        crank_services.push(("blockhash guard", blockhash_guard_task));
+        crank_services.push(("transaction send bridge", transaction_send_bridge_task));
+        crank_services.extend(named("tpu reset", tpu_reset_task));
+        crank_services.extend(named("stats", stats_tasks));
+        crank_services.extend(named("reporting", reporting_thread));
+        crank_services.extend(named("health", health_task));
+        crank_services.extend(named("rate limiter", rate_limiter_task));
+        crank_services.extend(named("market limiter", market_limiter_task));
+        crank_services.extend(named("nonce polling", nonce_polling_task));
+        crank_services.extend(named("lookup table polling", lookup_table_polling_task));
+
+        *self.markets_handle.lock().unwrap() = Some(markets_handle);
+        *self.stats.lock().unwrap() = Some(crank_stats.clone());
+        crank_stats.set_services(crank_services.len());
+        self.tasks.lock().unwrap().append(&mut crank_services);
+        Ok(crank_stats)
+    }
+
+    /// Resolves the markets again and replaces the cranked ones, transactions already sent
+    /// for removed markets are still confirmed as the confirmation service does not depend
+    /// on markets. Fails when the markets only come from the command line as nothing can
+    /// change then
+    pub async fn reload_markets(&self) -> anyhow::Result<()> {
+        let config = &self.config;
+        anyhow::ensure!(
+            config.markets_file.is_some()
+                || config.markets_config_file.is_some()
+                || (config.discover_markets && config.debug_market.is_none()),
+            "nothing to reload without --markets-file, --config or --discover-markets"
+        );
+        let markets = self.resolve_markets().await?;
+        let markets_handle = self.markets_handle.lock().unwrap().clone();
+        markets_handle
+            .context("the crank is not started")?
+            .update(&markets)
+    }
+
+    /// Pauses cranking when it runs and resumes it when it is paused, returns whether it is
+    /// now paused. Confirmations and stats keep running meanwhile
+    pub fn toggle_paused(&self) -> anyhow::Result<bool> {
+        let markets_handle = self
+            .markets_handle
+            .lock()
+            .unwrap()
+            .clone()
+            .context("the crank is not started")?;
+        let paused = !markets_handle.is_paused();
+        markets_handle.set_paused(paused);
+        Ok(paused)
+    }
+
+    /// Waits until one of the services stops or `shutdown` is called, then stops all
+    /// the other services. Fails when a service stopped on its own so that the process
+    /// exits with an error and gets restarted by its supervisor.
+    pub async fn wait(&self) -> anyhow::Result<()> {
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        if tasks.is_empty() {
+            return Ok(());
+        }
+        let result = tokio::select! {
+            (result, index, _) = futures::future::select_all(tasks.iter_mut().map(|(_, x)| x)) => {
+                let reason = match result {
+                    Ok(()) => "returned".to_string(),
+                    Err(e) if e.is_panic() => "panicked".to_string(),
+                    Err(e) => e.to_string(),
+                };
+                let name = tasks[index].0;
+                log::error!("service {name} (#{index}) stopped ({reason}), stopping the crank");
+                if let Some(stats) = self.stats.lock().unwrap().as_ref() {
+                    stats.inc_stopped_services();
+                }
+                Err(anyhow::anyhow!("service {name} stopped ({reason})"))
+            }
+            _ = self.shutdown.notified() => Ok(()),
+        };
+        for (_, task) in tasks {
+            task.abort();
+        }
+        result
+    }
+
+    /// Prints the totals since the crank started and closes the result files, does nothing
+    /// before it is started
+    pub async fn report_final(&self) {
+        let stats = self.stats.lock().unwrap().clone();
+        if let Some(stats) = stats {
+            stats.report_final().await;
+        }
+        self.close_result_writers().await;
+    }
+
+    // waits for the result files to be flushed and their summaries written, after shutdown
+    async fn close_result_writers(&self) {
+        // the sink outlives the stopped services, its heap depth sender would keep the heap
+        // depth writer waiting
+        if let Some(markets_handle) = self.markets_handle.lock().unwrap().take() {
+            markets_handle.close_heap_depth_records();
+        }
+        let result_writers = std::mem::take(&mut *self.result_writers.lock().unwrap());
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        for (name, writer) in result_writers {
+            if tokio::time::timeout_at(deadline, writer).await.is_err() {
+                log::warn!("{name} not closed after 10s, its last records may be lost");
+            }
+        }
+    }
+
+    /// Stops all the services
+    pub fn shutdown(&self) {
+        for (_, task) in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        self.shutdown.notify_one();
+    }
+
+    /// Configuration the crank runs with, defaults, resolved markets, market config files
+    /// and send mode included, without any secret
+    fn effective_config(&self, markets: &[MarketData], send_mode: SendMode) -> String {
+        let config = CrankConfig {
+            send_mode: Some(send_mode),
+            markets: markets.iter().map(|x| x.market_pk).collect(),
+            ..self.config.redacted()
+        };
+        let market_configs = markets
+            .iter()
+            .map(|x| {
+                let market_config = MarketConfig {
+                    rpc_url: x.config.rpc_url.as_deref().map(redact_url),
+                    ws_url: x.config.ws_url.as_deref().map(redact_url),
+                    ..x.config.clone()
+                };
+                format!("{}: {market_config:?}", x.market_pk)
+            })
+            .join(", ");
+        format!(
+            "{config:?}, crank authority: {}, fee payer: {}, tpu identities: [{}], market configs: [{market_configs}]",
+            self.crank_authority.pubkey(),
+            self.fee_payer.pubkey(),
+            self.tpu_identities.iter().map(|x| x.pubkey()).join(", "),
+        )
+    }
+
+    async fn resolve_markets_with_retry(&self) -> anyhow::Result<Vec<MarketData>> {
+        retry_with_backoff("fetch markets", || async {
+            let markets = self.resolve_markets().await?;
+            // markets which could not be loaded are logged by the fetch
+            anyhow::ensure!(!markets.is_empty(), "no market could be loaded");
+            Ok(markets)
+        })
+        .await
+    }
+
+    async fn resolve_markets(&self) -> anyhow::Result<Vec<MarketData>> {
+        let config = &self.config;
+        // markets listed several times are only loaded once
+        let mut market_pks = config.markets.clone();
+        if let Some(markets_file) = &config.markets_file {
+            market_pks.extend(markets::load_markets_file(markets_file).await?);
+        }
+        let discover = config.discover_markets && config.debug_market.is_none();
+        if let Some(debug_market) = config.debug_market {
+            market_pks = vec![debug_market];
+        }
+        let mut markets = markets::load_markets(
+            &self.rpc_client,
+            &config.program_id,
+            &market_pks,
+            discover,
+            &config.exclude_markets,
+        )
+        .await?;
+        if let Some(markets_config_file) = &config.markets_config_file {
+            markets::MarketsConfig::load(markets_config_file)
+                .await?
+                .apply(&mut markets);
+        }
+        Ok(markets)
+    }
+}
+
+fn named(
+    name: &'static str,
+    tasks: impl IntoIterator<Item = JoinHandle<()>>,
+) -> impl Iterator<Item = (&'static str, JoinHandle<()>)> {
+    tasks.into_iter().map(move |x| (name, x))
+}
+
+// an rpc hiccup at startup should not make a supervisor crash loop the crank
+async fn retry_with_backoff<T, F, Fut>(what: &str, mut f: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    const ATTEMPTS: u32 = 5;
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < ATTEMPTS => {
+                log::warn!("cannot {what}, attempt {attempt}/{ATTEMPTS}: {e:#}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.context(format!("cannot {what} after {ATTEMPTS} attempts"))),
+        }
+    }
+}
+
+/// Tells connection failures apart from errors returned by the rpc node, a node answering
+/// with an http error status is reachable
+fn rpc_error(error: ClientError) -> anyhow::Error {
+    let unreachable = match error.kind() {
+        ClientErrorKind::Io(_) => true,
+        ClientErrorKind::Reqwest(e) => e.status().is_none(),
+        _ => false,
+    };
+    let context = if unreachable {
+        "RPC unreachable"
+    } else {
+        "RPC reachable but errored"
+    };
+    anyhow::Error::new(error).context(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crank(config: CrankConfig) -> anyhow::Result<Crank> {
+        Crank::builder()
+            .config(config)
+            .crank_authority(Keypair::new())
+            .build()
+    }
+
+    #[tokio::test]
+    async fn fails_reload_without_markets_source() {
+        let crank = crank(CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            ..CrankConfig::default()
+        })
+        .unwrap();
+        let error = crank.reload_markets().await.unwrap_err();
+        assert!(error.to_string().starts_with("nothing to reload"));
+    }
+
+    #[test]
+    fn rejects_stdout_results_with_stats_reports() {
+        let config = CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            transaction_save_file: Some(STDOUT_PATH.to_string()),
+            ..CrankConfig::default()
+        };
+        assert!(crank(config.clone()).is_err());
+        crank(CrankConfig {
+            report_interval_secs: 0,
+            ..config
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_zero_result_flush_interval() {
+        let error = crank(CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            result_flush_interval_secs: 0,
+            ..CrankConfig::default()
+        })
+        .err()
+        .unwrap();
+        assert!(error.to_string().starts_with("the result flush interval"));
+    }
+
+    #[test]
+    fn rejects_zero_send_bridge_interval() {
+        let error = crank(CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            transaction_retry_in_ms: 0,
+            ..CrankConfig::default()
+        })
+        .err()
+        .unwrap();
+        assert!(error.to_string().starts_with("the send bridge interval"));
+    }
+
+    #[test]
+    fn rejects_zero_cleaner_interval() {
+        let error = crank(CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            cleaner_interval_secs: 0,
+            ..CrankConfig::default()
+        })
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("the confirmation cleaner interval"));
+    }
+
+    #[test]
+    fn rejects_zero_max_tps() {
+        let error = crank(CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            max_tps: Some(0),
+            ..CrankConfig::default()
+        })
+        .err()
+        .unwrap();
+        assert!(error.to_string().starts_with("the max tps must be"));
+    }
+
+    #[test]
+    fn rejects_zero_max_tps_queue() {
+        let error = crank(CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            max_tps_queue: 0,
+            ..CrankConfig::default()
+        })
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("at least one transaction must be able to wait for the max tps"));
+    }
+
+    #[test]
+    fn rejects_zero_confirmation_timeout() {
+        let error = crank(CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            confirmation_timeout_secs: 0,
+            ..CrankConfig::default()
+        })
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("the confirmation timeout must be at least 1 second"));
+    }
+
+    #[test]
+    fn rejects_confirmation_depth_reached_after_timeout() {
+        let error = crank(CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            confirmation_depth_slots: 25,
+            confirmation_timeout_secs: 10,
+            ..CrankConfig::default()
+        })
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("transactions would time out before reaching"));
+        assert!(crank(CrankConfig {
+            markets: vec![Pubkey::new_unique()],
+            confirmation_depth_slots: 24,
+            confirmation_timeout_secs: 10,
+            ..CrankConfig::default()
+        })
+        .is_ok());
+    }
+}
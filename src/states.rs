@@ -1,16 +1,60 @@
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use solana_program::{pubkey::Pubkey, slot_history::Slot};
 use solana_sdk::signature::Signature;
+use std::time::{Duration, Instant};
 
-#[derive(Clone, Serialize)]
+/// Fill and out events a consume events instruction was built for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventCounts {
+    pub fill: u64,
+    pub out: u64,
+}
+
+impl EventCounts {
+    pub fn of(events: &[ConsumedEvent]) -> Self {
+        let fill = events.iter().filter(|x| x.fill).count() as u64;
+        Self {
+            fill,
+            out: events.len() as u64 - fill,
+        }
+    }
+}
+
+/// An event a consume events instruction was built for, oldest first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConsumedEvent {
+    pub fill: bool,
+    /// event accounts of the instruction needed to consume this event and the older ones
+    pub nb_event_accounts: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TransactionSendRecord {
     pub signature: Signature,
     pub sent_at: DateTime<Utc>,
     pub sent_slot: Slot,
     pub user: Option<Pubkey>,
-    pub market: Option<Pubkey>,
+    /// markets cranked by the transaction, several when consume events are batched
+    pub markets: Vec<Pubkey>,
     pub priority_fees: u64,
+    /// when the oldest consume events instruction of the transaction was built
+    #[serde(skip)]
+    pub built_at: Option<Instant>,
+    /// events of each consume events instruction, counted once the transaction succeeds
+    #[serde(skip)]
+    pub events: Vec<EventCounts>,
+}
+
+impl TransactionSendRecord {
+    pub fn markets_string(&self) -> Option<String> {
+        if self.markets.is_empty() {
+            None
+        } else {
+            Some(self.markets.iter().map(|x| x.to_string()).join(";"))
+        }
+    }
 }
 
 #[derive(Clone, Serialize)]
@@ -27,8 +71,23 @@ pub struct TransactionConfirmRecord {
     pub market: Option<String>,
     pub block_hash: Option<String>,
     pub slot_processed: Option<Slot>,
+    /// slots between sending and processing, 0 if the send slot was ahead
+    pub slot_latency: Option<u64>,
     pub timed_out: bool,
     pub priority_fees: u64,
+    /// emitted again after a confirmation when the transaction never got finalized
+    pub reorged: bool,
+    /// tag of the crank run, set by the result writer
+    pub run_tag: Option<String>,
+    /// time between sending and processing the confirming block
+    #[serde(skip)]
+    pub confirmation_latency: Option<Duration>,
+    /// OpenBook error the transaction failed with, its name when the logs are known
+    #[serde(skip)]
+    pub program_error: Option<String>,
+    /// events of each consume events instruction of the transaction
+    #[serde(skip)]
+    pub events: Vec<EventCounts>,
 }
 
 #[derive(Clone, Serialize)]
@@ -40,5 +99,21 @@ pub struct BlockData {
     pub number_of_mm_transactions: u64,
     pub block_time: u64,
     pub cu_consumed: u64,
+    pub cu_consumed_by_openbook: u64,
     pub percentage_filled_by_openbook: f32,
+    /// json map of our transactions in the block by market, a batched transaction counts
+    /// for each of its markets
+    pub transactions_by_market: String,
+    /// tag of the crank run, set by the result writer
+    pub run_tag: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct HeapDepthRecord {
+    pub timestamp: String,
+    pub slot: Slot,
+    pub market: String,
+    pub event_heap: String,
+    pub depth: u64,
+    pub seq_num: u64,
 }
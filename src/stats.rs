@@ -1,46 +1,251 @@
-use crate::states::TransactionConfirmRecord;
+use crate::{
+    confirmation_strategy::{describe_error, EVICTED_ERROR},
+    health::HealthStatus,
+    states::{BlockData, EventCounts, TransactionConfirmRecord},
+    statsd::{MetricKind, StatsdClient},
+};
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
+use log::error;
+use serde::Serialize;
+use solana_sdk::pubkey::Pubkey;
 use std::{
-    collections::HashMap,
-    sync::Mutex,
+    collections::{HashMap, HashSet, VecDeque},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
-    time::Instant,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
 };
+use tokio::io::AsyncWriteExt;
 use tokio::{sync::RwLock, task::JoinHandle};
 
+// number of recent errors kept for each market
+const RECENT_ERRORS_PER_MARKET: usize = 5;
+
+/// Strips the instruction index from transaction errors so that the same error of
+/// different instructions is counted once
+pub fn normalize_error(error: &str) -> String {
+    static INSTRUCTION_INDEX: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    INSTRUCTION_INDEX
+        .get_or_init(|| regex::Regex::new(r"Error processing Instruction \d+: ").unwrap())
+        .replace_all(error, "")
+        .to_string()
+}
+
 // Non atomic version of counters
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, Serialize)]
 struct NACounters {
     num_confirmed_txs: u64,
     num_error_txs: u64,
     num_timeout_txs: u64,
+    num_evicted: u64,
+    paused: u64,
+    backpressure: u64,
+    num_unresolved_txs: u64,
+    num_stale_instructions: u64,
+    num_blockhash_retries: u64,
+    num_no_effect: u64,
+    num_lagged_records: u64,
+    confirmation_backlog: u64,
+    block_backlog: u64,
+    num_pending_instructions: u64,
+    num_stopped_services: u64,
+    num_services: u64,
+    confirmation_lag_slots: u64,
+    num_pending_transactions: u64,
+    num_reorged: u64,
+    num_warmup_timeouts: u64,
     num_successful: u64,
     num_sent: u64,
+    num_fill_events: u64,
+    num_out_events: u64,
+    num_consume_instructions: u64,
+    max_events_per_instruction: u64,
+    num_backlog_alerts: u64,
+    num_instructions_0_events: u64,
+    num_instructions_1_10_events: u64,
+    num_instructions_11_25_events: u64,
+    num_instructions_26_50_events: u64,
+    num_instructions_over_50_events: u64,
+    num_rate_limited_drops: u64,
+    num_markets_with_backlog: u64,
+    num_markets: u64,
+    num_dropped: u64,
+    num_stale_blockhash_drops: u64,
+    num_cu_consumed: u64,
+    num_blocks_with_txs: u64,
+    total_slot_latency: u64,
+    num_slot_latencies: u64,
+    total_build_latency_us: u64,
+    num_build_latencies: u64,
+    total_send_latency_us: u64,
+    num_send_latencies: u64,
+    total_confirm_latency_us: u64,
+    num_confirm_latencies: u64,
 
     // errors section
     errors: HashMap<String, u64>,
+
+    // errors returned when sending, before confirmation
+    send_errors: HashMap<String, u64>,
+
+    // failed transactions by OpenBook error
+    program_errors: HashMap<String, u64>,
+
+    // landed transactions by slot leader
+    landed_by_leader: HashMap<String, u64>,
 }
 
+// timeouts are reported long after the transactions were sent, so whether they count
+// against the success rates depends on when they were sent
+fn sent_during_warmup(sent_at: &str, started_at: DateTime<Utc>, warmup: Duration) -> bool {
+    let Ok(sent_at) = sent_at.parse::<DateTime<Utc>>() else {
+        return false;
+    };
+    match (sent_at - started_at).to_std() {
+        Ok(elapsed) => elapsed < warmup,
+        // sent before the start by the clock
+        Err(_) => true,
+    }
+}
+
+fn diff_map(
+    current: &HashMap<String, u64>,
+    previous: &HashMap<String, u64>,
+) -> HashMap<String, u64> {
+    current
+        .iter()
+        .map(|(key, count)| {
+            (
+                key.clone(),
+                *count - previous.get(key).copied().unwrap_or(0),
+            )
+        })
+        .collect()
+}
+
+// counters holding a current value or a maximum, the others only grow
+const GAUGES: &[&str] = &[
+    "paused",
+    "backpressure",
+    "num_unresolved_txs",
+    "num_pending_instructions",
+    "confirmation_backlog",
+    "block_backlog",
+    "num_services",
+    "confirmation_lag_slots",
+    "num_pending_transactions",
+    "max_events_per_instruction",
+    "num_markets_with_backlog",
+    "num_markets",
+];
+
 impl NACounters {
+    /// StatsD metrics of a diff, the increments since the previous report and the gauges
+    fn statsd_metrics(&self) -> Vec<(String, f64, MetricKind)> {
+        let serde_json::Value::Object(fields) = serde_json::to_value(self).unwrap_or_default()
+        else {
+            return vec![];
+        };
+        fields
+            .into_iter()
+            .filter_map(|(name, value)| {
+                // the maps are left to the json and error histogram files
+                let value = value.as_u64()? as f64;
+                let kind = if GAUGES.contains(&name.as_str()) {
+                    MetricKind::Gauge
+                } else {
+                    MetricKind::Counter
+                };
+                Some((name, value, kind))
+            })
+            .collect()
+    }
+
+    /// consume events instructions by number of events, tells whether transactions are
+    /// mostly full or mostly tiny
+    fn events_histogram(&self) -> String {
+        format!(
+            "0: {}, 1-10: {}, 11-25: {}, 26-50: {}, over 50: {}",
+            self.num_instructions_0_events,
+            self.num_instructions_1_10_events,
+            self.num_instructions_11_25_events,
+            self.num_instructions_26_50_events,
+            self.num_instructions_over_50_events,
+        )
+    }
+
     pub fn diff(&self, other: &NACounters) -> NACounters {
-        let mut new_error_count = HashMap::new();
-        for (error, count) in &self.errors {
-            if let Some(v) = other.errors.get(error) {
-                new_error_count.insert(error.clone(), *count - *v);
-            } else {
-                new_error_count.insert(error.clone(), *count);
-            }
-        }
         NACounters {
             num_confirmed_txs: self.num_confirmed_txs - other.num_confirmed_txs,
             num_error_txs: self.num_error_txs - other.num_error_txs,
             num_timeout_txs: self.num_timeout_txs - other.num_timeout_txs,
+            num_evicted: self.num_evicted - other.num_evicted,
+            // a current value, it cannot be diffed
+            paused: self.paused,
+            // current values, they cannot be diffed
+            backpressure: self.backpressure,
+            num_unresolved_txs: self.num_unresolved_txs,
+            num_stale_instructions: self.num_stale_instructions - other.num_stale_instructions,
+            num_blockhash_retries: self.num_blockhash_retries - other.num_blockhash_retries,
+            num_no_effect: self.num_no_effect - other.num_no_effect,
+            num_lagged_records: self.num_lagged_records - other.num_lagged_records,
+            // current values, they cannot be diffed
+            confirmation_backlog: self.confirmation_backlog,
+            block_backlog: self.block_backlog,
+            num_pending_instructions: self.num_pending_instructions,
+            num_stopped_services: self.num_stopped_services - other.num_stopped_services,
+            // current values, they cannot be diffed
+            num_services: self.num_services,
+            // a current value, it cannot be diffed
+            confirmation_lag_slots: self.confirmation_lag_slots,
+            // a current value, it cannot be diffed
+            num_pending_transactions: self.num_pending_transactions,
+            num_reorged: self.num_reorged - other.num_reorged,
+            num_warmup_timeouts: self.num_warmup_timeouts - other.num_warmup_timeouts,
             num_successful: self.num_successful - other.num_successful,
             num_sent: self.num_sent - other.num_sent,
-            errors: new_error_count,
+            num_fill_events: self.num_fill_events - other.num_fill_events,
+            num_out_events: self.num_out_events - other.num_out_events,
+            num_consume_instructions: self.num_consume_instructions
+                - other.num_consume_instructions,
+            // a maximum since start, it cannot be diffed
+            max_events_per_instruction: self.max_events_per_instruction,
+            num_backlog_alerts: self.num_backlog_alerts - other.num_backlog_alerts,
+            num_instructions_0_events: self.num_instructions_0_events
+                - other.num_instructions_0_events,
+            num_instructions_1_10_events: self.num_instructions_1_10_events
+                - other.num_instructions_1_10_events,
+            num_instructions_11_25_events: self.num_instructions_11_25_events
+                - other.num_instructions_11_25_events,
+            num_instructions_26_50_events: self.num_instructions_26_50_events
+                - other.num_instructions_26_50_events,
+            num_instructions_over_50_events: self.num_instructions_over_50_events
+                - other.num_instructions_over_50_events,
+            num_rate_limited_drops: self.num_rate_limited_drops - other.num_rate_limited_drops,
+            // current values, they cannot be diffed
+            num_markets_with_backlog: self.num_markets_with_backlog,
+            num_markets: self.num_markets,
+            num_dropped: self.num_dropped - other.num_dropped,
+            num_stale_blockhash_drops: self.num_stale_blockhash_drops
+                - other.num_stale_blockhash_drops,
+            num_cu_consumed: self.num_cu_consumed - other.num_cu_consumed,
+            num_blocks_with_txs: self.num_blocks_with_txs - other.num_blocks_with_txs,
+            total_slot_latency: self.total_slot_latency - other.total_slot_latency,
+            num_slot_latencies: self.num_slot_latencies - other.num_slot_latencies,
+            total_build_latency_us: self.total_build_latency_us - other.total_build_latency_us,
+            num_build_latencies: self.num_build_latencies - other.num_build_latencies,
+            total_send_latency_us: self.total_send_latency_us - other.total_send_latency_us,
+            num_send_latencies: self.num_send_latencies - other.num_send_latencies,
+            total_confirm_latency_us: self.total_confirm_latency_us
+                - other.total_confirm_latency_us,
+            num_confirm_latencies: self.num_confirm_latencies - other.num_confirm_latencies,
+            errors: diff_map(&self.errors, &other.errors),
+            send_errors: diff_map(&self.send_errors, &other.send_errors),
+            program_errors: diff_map(&self.program_errors, &other.program_errors),
+            landed_by_leader: diff_map(&self.landed_by_leader, &other.landed_by_leader),
         }
     }
 }
@@ -50,39 +255,219 @@ struct Counters {
     num_confirmed_txs: Arc<AtomicU64>,
     num_error_txs: Arc<AtomicU64>,
     num_timeout_txs: Arc<AtomicU64>,
+    num_evicted: Arc<AtomicU64>,
+    paused: Arc<AtomicU64>,
+    backpressure: Arc<AtomicU64>,
+    num_stale_instructions: Arc<AtomicU64>,
+    num_blockhash_retries: Arc<AtomicU64>,
+    num_no_effect: Arc<AtomicU64>,
+    num_lagged_records: Arc<AtomicU64>,
+    // records sent to the stats receivers and not received yet
+    confirmation_backlog: Arc<AtomicU64>,
+    block_backlog: Arc<AtomicU64>,
+    num_pending_instructions: Arc<AtomicU64>,
+    num_stopped_services: Arc<AtomicU64>,
+    num_services: Arc<AtomicU64>,
+    confirmation_lag_slots: Arc<AtomicU64>,
+    num_pending_transactions: Arc<AtomicU64>,
+    num_reorged: Arc<AtomicU64>,
+    num_warmup_timeouts: Arc<AtomicU64>,
     num_successful: Arc<AtomicU64>,
     num_sent: Arc<AtomicU64>,
+    num_fill_events: Arc<AtomicU64>,
+    num_out_events: Arc<AtomicU64>,
+    num_consume_instructions: Arc<AtomicU64>,
+    max_events_per_instruction: Arc<AtomicU64>,
+    num_backlog_alerts: Arc<AtomicU64>,
+    num_instructions_0_events: Arc<AtomicU64>,
+    num_instructions_1_10_events: Arc<AtomicU64>,
+    num_instructions_11_25_events: Arc<AtomicU64>,
+    num_instructions_26_50_events: Arc<AtomicU64>,
+    num_instructions_over_50_events: Arc<AtomicU64>,
+    num_rate_limited_drops: Arc<AtomicU64>,
+    num_markets_with_backlog: Arc<AtomicU64>,
+    num_markets: Arc<AtomicU64>,
+    num_dropped: Arc<AtomicU64>,
+    num_stale_blockhash_drops: Arc<AtomicU64>,
+    num_cu_consumed: Arc<AtomicU64>,
+    num_blocks_with_txs: Arc<AtomicU64>,
+    total_slot_latency: Arc<AtomicU64>,
+    num_slot_latencies: Arc<AtomicU64>,
+    total_build_latency_us: Arc<AtomicU64>,
+    num_build_latencies: Arc<AtomicU64>,
+    total_send_latency_us: Arc<AtomicU64>,
+    num_send_latencies: Arc<AtomicU64>,
+    total_confirm_latency_us: Arc<AtomicU64>,
+    num_confirm_latencies: Arc<AtomicU64>,
 
     // Errors
     errors: Arc<RwLock<HashMap<String, u64>>>,
+
+    // Errors returned when sending
+    send_errors: Arc<RwLock<HashMap<String, u64>>>,
+
+    // Failed transactions by OpenBook error
+    program_errors: Arc<RwLock<HashMap<String, u64>>>,
+
+    // Landed transactions by slot leader
+    landed_by_leader: Arc<RwLock<HashMap<String, u64>>>,
+
+    // Last errors of each market
+    recent_errors_by_market: Arc<Mutex<HashMap<String, VecDeque<String>>>>,
 }
 
 impl Counters {
+    fn nb_unresolved_txs(&self) -> u64 {
+        let resolved = self.num_confirmed_txs.load(Ordering::Relaxed)
+            + self.num_timeout_txs.load(Ordering::Relaxed)
+            + self.num_warmup_timeouts.load(Ordering::Relaxed)
+            + self.num_evicted.load(Ordering::Relaxed);
+        self.num_sent
+            .load(Ordering::Relaxed)
+            .saturating_sub(resolved)
+    }
+
+    /// events of a consume events instruction of a successful transaction
+    fn add_consumed_events(&self, events: EventCounts) {
+        self.num_fill_events
+            .fetch_add(events.fill, Ordering::Relaxed);
+        self.num_out_events.fetch_add(events.out, Ordering::Relaxed);
+        self.num_consume_instructions
+            .fetch_add(1, Ordering::Relaxed);
+        self.max_events_per_instruction
+            .fetch_max(events.fill + events.out, Ordering::Relaxed);
+        let bucket = match events.fill + events.out {
+            0 => &self.num_instructions_0_events,
+            1..=10 => &self.num_instructions_1_10_events,
+            11..=25 => &self.num_instructions_11_25_events,
+            26..=50 => &self.num_instructions_26_50_events,
+            _ => &self.num_instructions_over_50_events,
+        };
+        bucket.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub async fn to_na_counters(&self) -> NACounters {
         NACounters {
             num_confirmed_txs: self.num_confirmed_txs.load(Ordering::Relaxed),
             num_error_txs: self.num_error_txs.load(Ordering::Relaxed),
             num_timeout_txs: self.num_timeout_txs.load(Ordering::Relaxed),
+            num_evicted: self.num_evicted.load(Ordering::Relaxed),
+            paused: self.paused.load(Ordering::Relaxed),
+            backpressure: self.backpressure.load(Ordering::Relaxed),
+            num_unresolved_txs: self.nb_unresolved_txs(),
+            num_stale_instructions: self.num_stale_instructions.load(Ordering::Relaxed),
+            num_blockhash_retries: self.num_blockhash_retries.load(Ordering::Relaxed),
+            num_no_effect: self.num_no_effect.load(Ordering::Relaxed),
+            num_lagged_records: self.num_lagged_records.load(Ordering::Relaxed),
+            confirmation_backlog: self.confirmation_backlog.load(Ordering::Relaxed),
+            block_backlog: self.block_backlog.load(Ordering::Relaxed),
+            num_pending_instructions: self.num_pending_instructions.load(Ordering::Relaxed),
+            num_stopped_services: self.num_stopped_services.load(Ordering::Relaxed),
+            num_services: self.num_services.load(Ordering::Relaxed),
+            confirmation_lag_slots: self.confirmation_lag_slots.load(Ordering::Relaxed),
+            num_pending_transactions: self.num_pending_transactions.load(Ordering::Relaxed),
+            num_reorged: self.num_reorged.load(Ordering::Relaxed),
+            num_warmup_timeouts: self.num_warmup_timeouts.load(Ordering::Relaxed),
             num_successful: self.num_successful.load(Ordering::Relaxed),
             num_sent: self.num_sent.load(Ordering::Relaxed),
+            num_fill_events: self.num_fill_events.load(Ordering::Relaxed),
+            num_out_events: self.num_out_events.load(Ordering::Relaxed),
+            num_consume_instructions: self.num_consume_instructions.load(Ordering::Relaxed),
+            max_events_per_instruction: self.max_events_per_instruction.load(Ordering::Relaxed),
+            num_backlog_alerts: self.num_backlog_alerts.load(Ordering::Relaxed),
+            num_instructions_0_events: self.num_instructions_0_events.load(Ordering::Relaxed),
+            num_instructions_1_10_events: self.num_instructions_1_10_events.load(Ordering::Relaxed),
+            num_instructions_11_25_events: self
+                .num_instructions_11_25_events
+                .load(Ordering::Relaxed),
+            num_instructions_26_50_events: self
+                .num_instructions_26_50_events
+                .load(Ordering::Relaxed),
+            num_instructions_over_50_events: self
+                .num_instructions_over_50_events
+                .load(Ordering::Relaxed),
+            num_rate_limited_drops: self.num_rate_limited_drops.load(Ordering::Relaxed),
+            num_markets_with_backlog: self.num_markets_with_backlog.load(Ordering::Relaxed),
+            num_markets: self.num_markets.load(Ordering::Relaxed),
+            num_dropped: self.num_dropped.load(Ordering::Relaxed),
+            num_stale_blockhash_drops: self.num_stale_blockhash_drops.load(Ordering::Relaxed),
+            num_cu_consumed: self.num_cu_consumed.load(Ordering::Relaxed),
+            num_blocks_with_txs: self.num_blocks_with_txs.load(Ordering::Relaxed),
+            total_slot_latency: self.total_slot_latency.load(Ordering::Relaxed),
+            num_slot_latencies: self.num_slot_latencies.load(Ordering::Relaxed),
+            total_build_latency_us: self.total_build_latency_us.load(Ordering::Relaxed),
+            num_build_latencies: self.num_build_latencies.load(Ordering::Relaxed),
+            total_send_latency_us: self.total_send_latency_us.load(Ordering::Relaxed),
+            num_send_latencies: self.num_send_latencies.load(Ordering::Relaxed),
+            total_confirm_latency_us: self.total_confirm_latency_us.load(Ordering::Relaxed),
+            num_confirm_latencies: self.num_confirm_latencies.load(Ordering::Relaxed),
             errors: self.errors.read().await.clone(),
+            send_errors: self.send_errors.read().await.clone(),
+            program_errors: self.program_errors.read().await.clone(),
+            landed_by_leader: self.landed_by_leader.read().await.clone(),
         }
     }
 }
 
+// one line of the stats json file
+#[derive(Serialize)]
+struct StatsSnapshot<'a> {
+    elapsed_secs: u64,
+    counters: &'a NACounters,
+    diff: &'a NACounters,
+    top_5_errors: Vec<(&'a String, &'a u64)>,
+    blockhash_age_secs: Option<u64>,
+    blockhash_refresh_failures: u64,
+    warming_up: bool,
+    // rates over the interval since the previous report
+    sent_tps: f64,
+    confirmed_tps: f64,
+}
+
+// one row of the error histogram file
+#[derive(Serialize)]
+struct ErrorCount<'a> {
+    error: &'a str,
+    count: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct CrankStats {
     counters: Counters,
     previous_counters: Arc<Mutex<NACounters>>,
+    previous_report: Arc<Mutex<Instant>>,
     instant: Instant,
+    // wall clock time of `instant`, the transactions sent before the warmup ends are told
+    // apart by their send time
+    started_at: DateTime<Utc>,
+    // timeouts are not counted against the success rates and reports are marked as
+    // warming up during this period after start
+    warmup: Duration,
+    json_file: Option<String>,
+    error_histogram_file: Option<String>,
+    health: HealthStatus,
+    statsd: Option<Arc<StatsdClient>>,
 }
 
 impl CrankStats {
-    pub fn new() -> Self {
+    pub fn new(
+        json_file: Option<String>,
+        error_histogram_file: Option<String>,
+        health: HealthStatus,
+        warmup: Duration,
+        statsd: Option<StatsdClient>,
+    ) -> Self {
         Self {
             counters: Counters::default(),
             instant: Instant::now(),
+            started_at: Utc::now(),
+            warmup,
             previous_counters: Arc::new(Mutex::new(NACounters::default())),
+            previous_report: Arc::new(Mutex::new(Instant::now())),
+            json_file,
+            error_histogram_file,
+            health,
+            statsd: statsd.map(Arc::new),
         }
     }
 
@@ -91,15 +476,73 @@ impl CrankStats {
         tx_confirm_record_reciever: tokio::sync::broadcast::Receiver<TransactionConfirmRecord>,
     ) -> JoinHandle<()> {
         let counters = self.counters.clone();
-        let regex = regex::Regex::new(r"Error processing Instruction \d+: ").unwrap();
+        let (started_at, warmup) = (self.started_at, self.warmup);
         tokio::spawn(async move {
             let mut tx_confirm_record_reciever = tx_confirm_record_reciever;
-            while let Ok(tx_data) = tx_confirm_record_reciever.recv().await {
-                if tx_data.confirmed_at.is_some() {
+            loop {
+                // a lagging receiver used to stop the stats for the rest of the run
+                let tx_data = match tx_confirm_record_reciever.recv().await {
+                    Ok(tx_data) => tx_data,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        counters.num_lagged_records.fetch_add(n, Ordering::Relaxed);
+                        continue;
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                counters
+                    .confirmation_backlog
+                    .store(tx_confirm_record_reciever.len() as u64, Ordering::Relaxed);
+                // the transaction was already counted when it was confirmed
+                if tx_data.reorged {
+                    counters.num_reorged.fetch_add(1, Ordering::Relaxed);
+                } else if tx_data.confirmed_at.is_some() {
                     counters.num_confirmed_txs.fetch_add(1, Ordering::Relaxed);
+                    if let Some(slot_latency) = tx_data.slot_latency {
+                        counters
+                            .total_slot_latency
+                            .fetch_add(slot_latency, Ordering::Relaxed);
+                        counters.num_slot_latencies.fetch_add(1, Ordering::Relaxed);
+                    }
+                    if let Some(confirmation_latency) = tx_data.confirmation_latency {
+                        counters
+                            .total_confirm_latency_us
+                            .fetch_add(confirmation_latency.as_micros() as u64, Ordering::Relaxed);
+                        counters
+                            .num_confirm_latencies
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    if let Some(slot_leader) = tx_data.slot_leader.filter(|x| !x.is_empty()) {
+                        *counters
+                            .landed_by_leader
+                            .write()
+                            .await
+                            .entry(slot_leader)
+                            .or_default() += 1;
+                    }
                     if let Some(error) = tx_data.error {
-                        let error = regex.replace_all(&error, "").to_string();
+                        let error = normalize_error(&error);
+                        {
+                            let mut recent_errors = counters
+                                .recent_errors_by_market
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner);
+                            for market in tx_data.market.iter().flat_map(|x| x.split(';')) {
+                                let errors = recent_errors.entry(market.to_string()).or_default();
+                                if errors.len() == RECENT_ERRORS_PER_MARKET {
+                                    errors.pop_front();
+                                }
+                                errors.push_back(error.clone());
+                            }
+                        }
                         counters.num_error_txs.fetch_add(1, Ordering::Relaxed);
+                        if let Some(program_error) = tx_data.program_error {
+                            *counters
+                                .program_errors
+                                .write()
+                                .await
+                                .entry(program_error)
+                                .or_default() += 1;
+                        }
                         let mut lock = counters.errors.write().await;
                         if let Some(value) = lock.get_mut(&error) {
                             *value += 1;
@@ -108,7 +551,14 @@ impl CrankStats {
                         }
                     } else {
                         counters.num_successful.fetch_add(1, Ordering::Relaxed);
+                        for events in tx_data.events {
+                            counters.add_consumed_events(events);
+                        }
                     }
+                } else if tx_data.error.as_deref() == Some(EVICTED_ERROR) {
+                    counters.num_evicted.fetch_add(1, Ordering::Relaxed);
+                } else if sent_during_warmup(&tx_data.sent_at, started_at, warmup) {
+                    counters.num_warmup_timeouts.fetch_add(1, Ordering::Relaxed);
                 } else {
                     counters.num_timeout_txs.fetch_add(1, Ordering::Relaxed);
                 }
@@ -116,60 +566,701 @@ impl CrankStats {
         })
     }
 
+    pub fn update_from_block_stream(
+        &self,
+        block_data_reciever: tokio::sync::broadcast::Receiver<BlockData>,
+    ) -> JoinHandle<()> {
+        let counters = self.counters.clone();
+        tokio::spawn(async move {
+            let mut block_data_reciever = block_data_reciever;
+            loop {
+                match block_data_reciever.recv().await {
+                    Ok(block_data) => {
+                        counters
+                            .block_backlog
+                            .store(block_data_reciever.len() as u64, Ordering::Relaxed);
+                        if block_data.number_of_mm_transactions == 0 {
+                            continue;
+                        }
+                        counters
+                            .num_cu_consumed
+                            .fetch_add(block_data.cu_consumed_by_openbook, Ordering::Relaxed);
+                        counters.num_blocks_with_txs.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        counters.num_lagged_records.fetch_add(n, Ordering::Relaxed);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    /// last errors of transactions cranking this market, oldest first
+    pub fn recent_errors(&self, market: &Pubkey) -> Vec<String> {
+        self.counters
+            .recent_errors_by_market
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&market.to_string())
+            .map(|errors| errors.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// sent transactions neither confirmed nor timed out yet
+    pub fn nb_unresolved_txs(&self) -> u64 {
+        self.counters.nb_unresolved_txs()
+    }
+
     pub fn inc_send(&self) {
         self.counters.num_sent.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub async fn report(&mut self) {
+    /// counts an error returned by the rpc when sending, normalized like confirmed errors
+    pub async fn inc_send_error(&self, error: &str) {
+        *self
+            .counters
+            .send_errors
+            .write()
+            .await
+            .entry(normalize_error(error))
+            .or_default() += 1;
+    }
+
+    pub fn inc_backlog_alerts(&self) {
+        self.counters
+            .num_backlog_alerts
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_rate_limited_drops(&self) {
+        self.counters
+            .num_rate_limited_drops
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// time from an event heap update to its consume events instruction
+    pub fn add_build_latency(&self, latency: Duration) {
+        self.counters
+            .total_build_latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.counters
+            .num_build_latencies
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// time from a consume events instruction to its transaction being sent
+    pub fn add_send_latency(&self, latency: Duration) {
+        self.counters
+            .total_send_latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.counters
+            .num_send_latencies
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// services spawned by the crank
+    pub fn set_services(&self, num_services: usize) {
+        self.counters
+            .num_services
+            .store(num_services as u64, Ordering::Relaxed);
+    }
+
+    /// a service stopped while the crank was running
+    pub fn inc_stopped_services(&self) {
+        self.counters
+            .num_stopped_services
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// whether cranking is paused, 1 when paused
+    pub fn set_paused(&self, paused: bool) {
+        self.counters.paused.store(paused as u64, Ordering::Relaxed);
+    }
+
+    /// cranking is held back until fewer transactions are unconfirmed
+    pub fn set_backpressure(&self, backpressure: bool) {
+        self.counters
+            .backpressure
+            .store(backpressure as u64, Ordering::Relaxed);
+    }
+
+    /// consume events instructions waiting to be sent
+    pub fn set_pending_instructions(&self, num_pending_instructions: usize) {
+        self.counters
+            .num_pending_instructions
+            .store(num_pending_instructions as u64, Ordering::Relaxed);
+    }
+
+    /// an instruction was dropped from the full instruction queue for a newer one
+    pub fn inc_stale_instructions(&self) {
+        self.counters
+            .num_stale_instructions
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a timed out transaction was signed again with a fresh blockhash and resent
+    pub fn inc_blockhash_retries(&self) {
+        self.counters
+            .num_blockhash_retries
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a successful transaction consumed no event of one of its markets
+    pub fn inc_no_effect(&self) {
+        self.counters.num_no_effect.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a transaction or its send record was lost because a channel is closed
+    pub fn inc_dropped(&self) {
+        self.counters.num_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// transactions waiting for a confirmation
+    pub fn set_pending_transactions(&self, num_pending_transactions: usize) {
+        self.counters
+            .num_pending_transactions
+            .store(num_pending_transactions as u64, Ordering::Relaxed);
+    }
+
+    /// leaders which included at least one of our transactions
+    pub async fn landed_leaders(&self) -> HashSet<String> {
+        self.counters
+            .landed_by_leader
+            .read()
+            .await
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// slots between the current slot and the next block to confirm transactions with
+    pub fn set_confirmation_lag(&self, confirmation_lag_slots: u64) {
+        self.counters
+            .confirmation_lag_slots
+            .store(confirmation_lag_slots, Ordering::Relaxed);
+    }
+
+    /// markets whose event heap currently holds a backlog, out of the cranked markets
+    pub fn set_markets_with_backlog(&self, num_markets_with_backlog: usize, num_markets: usize) {
+        self.counters
+            .num_markets_with_backlog
+            .store(num_markets_with_backlog as u64, Ordering::Relaxed);
+        self.counters
+            .num_markets
+            .store(num_markets as u64, Ordering::Relaxed);
+    }
+
+    pub fn inc_stale_blockhash_drops(&self) {
+        self.counters
+            .num_stale_blockhash_drops
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// one line with the main counters since start
+    pub async fn summary(&self) -> String {
+        let counters = self.counters.to_na_counters().await;
+        format!(
+            "elapsed={}s paused={} sent={} confirmed={} successful={} errors={} timeouts={} dropped={} fill_events={} out_events={}",
+            self.instant.elapsed().as_secs(),
+            counters.paused > 0,
+            counters.num_sent,
+            counters.num_confirmed_txs,
+            counters.num_successful,
+            counters.num_error_txs,
+            counters.num_timeout_txs,
+            counters.num_dropped,
+            counters.num_fill_events,
+            counters.num_out_events,
+        )
+    }
+
+    /// prints the stats on stdout when `print` is set and appends them to the json file
+    pub async fn report(&mut self, print: bool) {
+        macro_rules! report_line {
+            ($($arg:tt)*) => {
+                if print {
+                    println!($($arg)*);
+                }
+            };
+        }
+
         let time_diff = std::time::Instant::now() - self.instant;
         let counters = self.counters.to_na_counters().await;
+        let warming_up = time_diff < self.warmup;
+        // transactions timed out during the warmup do not count against the success rates
+        let num_counted = counters
+            .num_sent
+            .saturating_sub(counters.num_warmup_timeouts);
 
-        println!("\n\n openbook_v2 crank at {} secs", time_diff.as_secs());
-        println!("Recently sent transactions could not yet be confirmed and would be confirmed shortly.\n
+        if warming_up {
+            report_line!(
+                "\n\n openbook_v2 crank at {} secs, warming up for {} secs",
+                time_diff.as_secs(),
+                self.warmup.as_secs()
+            );
+        } else {
+            report_line!("\n\n openbook_v2 crank at {} secs", time_diff.as_secs());
+        }
+        report_line!("Recently sent transactions could not yet be confirmed and would be confirmed shortly.\n
         diff is wrt previous report");
 
         let diff = {
-            let mut prev_counter_lock = self.previous_counters.lock().unwrap();
+            // the counters stay consistent even if a report panicked while holding the lock,
+            // keep reporting rather than panicking on every report after it
+            let mut prev_counter_lock = self
+                .previous_counters
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
             let diff = counters.diff(&prev_counter_lock);
             *prev_counter_lock = counters.clone();
             diff
         };
+        let interval_secs = {
+            let mut previous_report = self
+                .previous_report
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let interval = previous_report.elapsed();
+            *previous_report = Instant::now();
+            interval.as_secs_f64()
+        };
+        let per_second = |x: u64| {
+            if interval_secs > 0.0 {
+                x as f64 / interval_secs
+            } else {
+                0.0
+            }
+        };
+        let sent_tps = per_second(diff.num_sent);
+        let confirmed_tps = per_second(diff.num_confirmed_txs);
 
-        println!(
+        report_line!(
             "Number of transactions Sent: {} (Diff: {})",
-            counters.num_sent, diff.num_sent,
+            counters.num_sent,
+            diff.num_sent,
         );
 
-        println!(
+        report_line!(
+            "Transactions per second over the last {:.0}s: sent {:.2}, confirmed {:.2}",
+            interval_secs,
+            sent_tps,
+            confirmed_tps,
+        );
+
+        report_line!(
+            "Fill events cranked: {} (Diff: {}), Out events cranked: {} (Diff: {})",
+            counters.num_fill_events,
+            diff.num_fill_events,
+            counters.num_out_events,
+            diff.num_out_events,
+        );
+
+        report_line!(
+            "Events per consume events instruction: avg {} (Diff: {}), max {}",
+            (counters.num_fill_events + counters.num_out_events)
+                .checked_div(counters.num_consume_instructions)
+                .unwrap_or(0),
+            (diff.num_fill_events + diff.num_out_events)
+                .checked_div(diff.num_consume_instructions)
+                .unwrap_or(0),
+            counters.max_events_per_instruction,
+        );
+        report_line!(
+            "Consume events instructions by events: {} (Diff: {})",
+            counters.events_histogram(),
+            diff.events_histogram(),
+        );
+
+        report_line!(
+            "Markets with backlog: {} / {}",
+            counters.num_markets_with_backlog,
+            counters.num_markets,
+        );
+
+        report_line!(
+            "Backlog alerts: {} (Diff: {})",
+            counters.num_backlog_alerts,
+            diff.num_backlog_alerts,
+        );
+
+        report_line!(
+            "Dropped by the rate limiter: {} (Diff: {})",
+            counters.num_rate_limited_drops,
+            diff.num_rate_limited_drops,
+        );
+
+        report_line!(
+            "Dropped on a closed channel: {} (Diff: {})",
+            counters.num_dropped,
+            diff.num_dropped,
+        );
+
+        if counters.paused > 0 {
+            report_line!("Cranking paused, transactions in flight are still confirmed");
+        }
+
+        // a growing backlog comes before records are lost by lagging receivers
+        report_line!(
+            "Records waiting for the stats: {} confirmations, {} blocks, pending instructions: {}, stale instructions dropped from the full queue: {} (Diff: {}), records lost by lagging receivers: {} (Diff: {})",
+            counters.confirmation_backlog,
+            counters.block_backlog,
+            counters.num_pending_instructions,
+            counters.num_stale_instructions,
+            diff.num_stale_instructions,
+            counters.num_lagged_records,
+            diff.num_lagged_records,
+        );
+
+        report_line!(
+            "Pending confirmation: {}, evicted before their timeout: {} (Diff: {}), sent but unresolved: {}{}",
+            counters.num_pending_transactions,
+            counters.num_evicted,
+            diff.num_evicted,
+            counters.num_unresolved_txs,
+            if counters.backpressure > 0 {
+                ", cranking held back"
+            } else {
+                ""
+            },
+        );
+
+        // where latency accumulates, averages over the interval
+        let avg_ms = |total_us: u64, nb: u64| total_us.checked_div(nb).unwrap_or(0) as f64 / 1000.0;
+        report_line!(
+            "Latency by stage: update to instruction {:.1}ms, instruction to send {:.1}ms, send to confirmation {:.1}ms",
+            avg_ms(diff.total_build_latency_us, diff.num_build_latencies),
+            avg_ms(diff.total_send_latency_us, diff.num_send_latencies),
+            avg_ms(diff.total_confirm_latency_us, diff.num_confirm_latencies),
+        );
+
+        report_line!(
+            "Confirmation lag: {} slots",
+            counters.confirmation_lag_slots,
+        );
+
+        report_line!(
+            "Confirmed then dropped by a reorg: {} (Diff: {})",
+            counters.num_reorged,
+            diff.num_reorged,
+        );
+
+        let blockhash_age_secs = self.health.blockhash_age().map(|x| x.as_secs());
+        let blockhash_refresh_failures = self.health.blockhash_refresh_failures();
+        report_line!(
+            "Blockhash age: {}s, failed refreshes in a row: {}, dropped because of a stale blockhash: {} (Diff: {})",
+            blockhash_age_secs.unwrap_or_default(),
+            blockhash_refresh_failures,
+            counters.num_stale_blockhash_drops,
+            diff.num_stale_blockhash_drops,
+        );
+
+        report_line!(
+            "CU consumed by openbook: {} (Diff: {}), avg per landed tx: {}, avg per block: {}",
+            counters.num_cu_consumed,
+            diff.num_cu_consumed,
+            counters
+                .num_cu_consumed
+                .checked_div(counters.num_confirmed_txs)
+                .unwrap_or(0),
+            counters
+                .num_cu_consumed
+                .checked_div(counters.num_blocks_with_txs)
+                .unwrap_or(0),
+        );
+
+        report_line!(
             "Transactions confirmed: {}%",
             (counters.num_confirmed_txs * 100)
-                .checked_div(counters.num_sent)
+                .checked_div(num_counted)
                 .unwrap_or(0)
         );
-        println!(
-            "Transactions successful: {}%",
+        report_line!(
+            "Transactions successful: {}%, without consuming events: {} (Diff: {})",
             (counters.num_successful * 100)
-                .checked_div(counters.num_sent)
-                .unwrap_or(0)
+                .checked_div(num_counted)
+                .unwrap_or(0),
+            counters.num_no_effect,
+            diff.num_no_effect,
         );
-        println!(
-            "Transactions timed out: {}%",
+        report_line!(
+            "Transactions timed out: {}%, not counted during the warmup: {}, resent after their blockhash expired: {} (Diff: {})",
             (counters.num_timeout_txs * 100)
-                .checked_div(counters.num_sent)
-                .unwrap_or(0)
+                .checked_div(num_counted)
+                .unwrap_or(0),
+            counters.num_warmup_timeouts,
+            counters.num_blockhash_retries,
+            diff.num_blockhash_retries,
         );
         let top_5_errors = counters
             .errors
             .iter()
             .sorted_by(|x, y| (*y.1).cmp(x.1))
             .take(5)
-            .enumerate()
             .collect_vec();
         let mut errors_to_print: String = String::new();
-        for (idx, (error, count)) in top_5_errors {
-            println!("Error #{idx}: {error} ({count})");
+        for (idx, (error, count)) in top_5_errors.iter().enumerate() {
+            report_line!("Error #{idx}: {} ({count})", describe_error(error));
             errors_to_print += format!("{error}({count}),").as_str();
         }
-        println!("\n");
+        for (error, count) in counters
+            .send_errors
+            .iter()
+            .sorted_by(|x, y| (*y.1).cmp(x.1))
+            .take(5)
+        {
+            report_line!(
+                "Send error: {error} ({count}, Diff: {})",
+                diff.send_errors[error]
+            );
+        }
+        // tells benign failures, like an already consumed heap, from misconfigured markets
+        if !counters.program_errors.is_empty() {
+            report_line!(
+                "OpenBook errors: {}",
+                counters
+                    .program_errors
+                    .iter()
+                    .sorted_by(|x, y| (*y.1).cmp(x.1))
+                    .map(|(error, count)| format!(
+                        "{error} ({count}, Diff: {})",
+                        diff.program_errors[error]
+                    ))
+                    .join(", ")
+            );
+        }
+
+        // leaders which never included any of our transactions do not show up here, compare
+        // with the timed out transactions to see if txs get dropped
+        let leaders_by_inclusion = counters
+            .landed_by_leader
+            .iter()
+            .sorted_by(|x, y| (*y.1).cmp(x.1).then_with(|| x.0.cmp(y.0)))
+            .collect_vec();
+        report_line!(
+            "Slot leaders which included our transactions: {}",
+            leaders_by_inclusion.len()
+        );
+        for (leader, count) in leaders_by_inclusion.iter().take(5) {
+            report_line!("Top leader: {leader} ({count})");
+        }
+        if leaders_by_inclusion.len() > 5 {
+            for (leader, count) in leaders_by_inclusion
+                .iter()
+                .rev()
+                .take((leaders_by_inclusion.len() - 5).min(5))
+            {
+                report_line!("Bottom leader: {leader} ({count})");
+            }
+        }
+        report_line!("\n");
+
+        if let Some(json_file) = &self.json_file {
+            let snapshot = StatsSnapshot {
+                elapsed_secs: time_diff.as_secs(),
+                counters: &counters,
+                diff: &diff,
+                top_5_errors,
+                blockhash_age_secs,
+                blockhash_refresh_failures,
+                warming_up,
+                sent_tps,
+                confirmed_tps,
+            };
+            if let Err(e) = Self::append_json(json_file, &snapshot).await {
+                error!("error writing stats to {} : {}", json_file, e);
+            }
+        }
+        if let Some(statsd) = &self.statsd {
+            let mut metrics = diff.statsd_metrics();
+            metrics.push(("sent_tps".to_string(), sent_tps, MetricKind::Gauge));
+            metrics.push((
+                "confirmed_tps".to_string(),
+                confirmed_tps,
+                MetricKind::Gauge,
+            ));
+            statsd.send(&metrics);
+        }
+        self.save_error_histogram(&counters.errors).await;
+    }
+
+    /// prints the totals since start with every error, for the end of a run
+    pub async fn report_final(&self) {
+        let counters = self.counters.to_na_counters().await;
+        let num_counted = counters
+            .num_sent
+            .saturating_sub(counters.num_warmup_timeouts);
+        let percent_of_sent = |x: u64| (x * 100).checked_div(num_counted).unwrap_or(0);
+
+        println!(
+            "\n\n openbook_v2 crank summary after {} secs",
+            self.instant.elapsed().as_secs()
+        );
+        println!("Transactions sent: {}", counters.num_sent);
+        if counters.num_stopped_services > 0 {
+            println!(
+                "WARNING: {} services stopped unexpectedly, see the logs",
+                counters.num_stopped_services
+            );
+        }
+        println!(
+            "Transactions confirmed: {}%, successful: {}%, timed out: {}%",
+            percent_of_sent(counters.num_confirmed_txs),
+            percent_of_sent(counters.num_successful),
+            percent_of_sent(counters.num_timeout_txs),
+        );
+        println!(
+            "Transactions confirmed then dropped by a reorg: {}",
+            counters.num_reorged
+        );
+        println!(
+            "Transactions resent after their blockhash expired: {}",
+            counters.num_blockhash_retries
+        );
+        println!(
+            "Transactions confirmed without consuming events: {}",
+            counters.num_no_effect
+        );
+        println!(
+            "Average confirmation latency: {} slots",
+            counters
+                .total_slot_latency
+                .checked_div(counters.num_slot_latencies)
+                .unwrap_or(0)
+        );
+        println!(
+            "Fill events cranked: {}, Out events cranked: {}",
+            counters.num_fill_events, counters.num_out_events
+        );
+        println!(
+            "Consume events instructions by events: {}",
+            counters.events_histogram()
+        );
+        println!("CU consumed by openbook: {}", counters.num_cu_consumed);
+        println!(
+            "Dropped by the rate limiter: {}, because of a stale blockhash: {}, on a closed channel: {}",
+            counters.num_rate_limited_drops, counters.num_stale_blockhash_drops, counters.num_dropped
+        );
+        for (error, count) in counters.errors.iter().sorted_by(|x, y| (*y.1).cmp(x.1)) {
+            println!("Error: {} ({count})", describe_error(error));
+        }
+        for (error, count) in counters
+            .send_errors
+            .iter()
+            .sorted_by(|x, y| (*y.1).cmp(x.1))
+        {
+            println!("Send error: {error} ({count})");
+        }
+        for (error, count) in counters
+            .program_errors
+            .iter()
+            .sorted_by(|x, y| (*y.1).cmp(x.1))
+        {
+            println!("OpenBook error: {error} ({count})");
+        }
+        self.save_error_histogram(&counters.errors).await;
+    }
+
+    async fn save_error_histogram(&self, errors: &HashMap<String, u64>) {
+        if let Some(path) = &self.error_histogram_file {
+            if let Err(e) = Self::write_error_histogram(path, errors).await {
+                error!("error writing the error histogram to {} : {}", path, e);
+            }
+        }
+    }
+
+    /// overwrites the file with every error since start, most frequent first
+    async fn write_error_histogram(
+        path: &str,
+        errors: &HashMap<String, u64>,
+    ) -> anyhow::Result<()> {
+        let file = async_std::fs::File::create(path).await?;
+        let mut serializer = csv_async::AsyncSerializer::from_writer(file);
+        for (error, count) in errors
+            .iter()
+            .sorted_by(|x, y| (*y.1).cmp(x.1).then_with(|| x.0.cmp(y.0)))
+        {
+            serializer
+                .serialize(ErrorCount {
+                    error,
+                    count: *count,
+                })
+                .await?;
+        }
+        serializer.flush().await?;
+        Ok(())
+    }
+
+    async fn append_json(json_file: &str, snapshot: &StatsSnapshot<'_>) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(snapshot)?;
+        line.push(b'\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(json_file)
+            .await?;
+        file.write_all(&line).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_timeouts_by_send_time() {
+        let started_at = Utc::now();
+        let warmup = Duration::from_secs(10);
+        let sent_at = |secs| (started_at + chrono::Duration::seconds(secs)).to_string();
+        assert!(sent_during_warmup(&sent_at(1), started_at, warmup));
+        assert!(!sent_during_warmup(&sent_at(11), started_at, warmup));
+        assert!(!sent_during_warmup("", started_at, warmup));
+    }
+
+    fn confirmed(error: Option<&str>, events: Vec<EventCounts>) -> TransactionConfirmRecord {
+        TransactionConfirmRecord {
+            signature: String::new(),
+            sent_slot: 1,
+            sent_at: Utc::now().to_string(),
+            confirmed_slot: Some(2),
+            confirmed_at: Some(Utc::now().to_string()),
+            successful: error.is_none(),
+            slot_leader: None,
+            error: error.map(str::to_string),
+            user: None,
+            market: None,
+            block_hash: None,
+            slot_processed: Some(2),
+            slot_latency: Some(1),
+            timed_out: false,
+            priority_fees: 0,
+            reorged: false,
+            run_tag: None,
+            confirmation_latency: None,
+            program_error: None,
+            events,
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_events_of_successful_transactions_only() {
+        let stats = CrankStats::new(None, None, HealthStatus::default(), Duration::ZERO, None);
+        let (sender, receiver) = tokio::sync::broadcast::channel(4);
+        let task = stats.update_from_tx_status_stream(receiver);
+        let events = vec![
+            EventCounts { fill: 2, out: 1 },
+            EventCounts { fill: 0, out: 12 },
+        ];
+        assert!(sender.send(confirmed(None, events.clone())).is_ok());
+        assert!(sender.send(confirmed(Some("failed"), events)).is_ok());
+        drop(sender);
+        task.await.unwrap();
+
+        let counters = stats.counters.to_na_counters().await;
+        assert_eq!(counters.num_fill_events, 2);
+        assert_eq!(counters.num_out_events, 13);
+        assert_eq!(counters.num_consume_instructions, 2);
+        assert_eq!(counters.max_events_per_instruction, 12);
     }
 }
@@ -0,0 +1,58 @@
+use log::warn;
+use std::net::UdpSocket;
+
+// stay below the usual MTU so that packets are not fragmented
+const MAX_PACKET_SIZE: usize = 1432;
+const PREFIX: &str = "openbook_crank";
+
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+/// Pushes metrics to a StatsD or DogStatsD agent over UDP, metrics which cannot be sent
+/// right away are dropped
+#[derive(Debug)]
+pub struct StatsdClient {
+    socket: UdpSocket,
+}
+
+impl StatsdClient {
+    pub fn new(addr: &str) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        // reports must not wait for an unreachable agent
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+
+    /// sends the metrics packed in as few packets as possible, counters are increments
+    /// since the previous send
+    pub fn send(&self, metrics: &[(String, f64, MetricKind)]) {
+        let mut packet = String::new();
+        for (name, value, kind) in metrics {
+            let kind = match kind {
+                MetricKind::Counter => "c",
+                MetricKind::Gauge => "g",
+            };
+            let line = format!("{PREFIX}.{name}:{value}|{kind}");
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+                self.send_packet(&packet);
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            self.send_packet(&packet);
+        }
+    }
+
+    fn send_packet(&self, packet: &str) {
+        if let Err(e) = self.socket.send(packet.as_bytes()) {
+            warn!("error sending metrics to statsd: {e}");
+        }
+    }
+}
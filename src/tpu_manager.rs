@@ -1,115 +1,128 @@
+use crate::rebroadcast::TransactionResender;
 use crate::states::TransactionSendRecord;
 use crate::stats::CrankStats;
+use async_trait::async_trait;
 use bincode::serialize;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use solana_client::nonblocking::rpc_client::RpcClient;
 use solana_client::{connection_cache::ConnectionCache, nonblocking::tpu_client::TpuClient};
 use solana_quic_client::{QuicConfig, QuicConnectionManager, QuicPool};
 use solana_sdk::signature::Keypair;
-use solana_sdk::transaction::Transaction;
+use solana_sdk::transaction::VersionedTransaction;
 use std::time::Duration;
 use std::{
     net::{IpAddr, Ipv4Addr},
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicU32, AtomicUsize, Ordering},
         Arc,
     },
 };
-use tokio::sync::{mpsc::UnboundedSender, RwLock};
+use tokio::{
+    sync::{mpsc::UnboundedSender, RwLock},
+    task::JoinHandle,
+};
 
 pub type QuicTpuClient = TpuClient<QuicPool, QuicConnectionManager, QuicConfig>;
 
+// pause between two attempts of the same batch
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(5);
+
 #[derive(Clone)]
 pub struct TpuManager {
     error_count: Arc<AtomicU32>,
     rpc_client: Arc<RpcClient>,
-    // why arc twice / one is so that we clone rwlock and other so that we can clone tpu client
-    tpu_client: Arc<RwLock<Arc<QuicTpuClient>>>,
+    // one client per identity, batches are sent through them in turn
+    tpu_clients: Arc<RwLock<Vec<Arc<QuicTpuClient>>>>,
+    next_client: Arc<AtomicUsize>,
     pub ws_addr: String,
     fanout_slots: u64,
-    identity: Arc<Keypair>,
+    identities: Arc<Vec<Keypair>>,
+    // immediate resends of a batch before it counts as an error
+    send_retries: usize,
+    // QUIC connections kept per leader by each client
+    connection_pool_size: usize,
     tx_send_record: UnboundedSender<TransactionSendRecord>,
     stats: CrankStats,
 }
 
 impl TpuManager {
+    /// Spreads QUIC connections over several identities when more than one is given, so
+    /// that leaders do not rate limit all of them at once
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         rpc_client: Arc<RpcClient>,
         ws_addr: String,
         fanout_slots: u64,
-        identity: Keypair,
+        identities: Vec<Keypair>,
+        send_retries: usize,
+        connection_pool_size: usize,
         tx_send_record: UnboundedSender<TransactionSendRecord>,
         stats: CrankStats,
-    ) -> Self {
-        let connection_cache = ConnectionCache::new_with_client_options(
-            "",
-            4,
-            None,
-            Some((&identity, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))),
-            None,
-        );
-
-        let quic_connection_cache =
-            if let ConnectionCache::Quic(connection_cache) = connection_cache {
-                Some(connection_cache)
-            } else {
-                None
-            };
-
-        let tpu_client = Arc::new(
-            TpuClient::new_with_connection_cache(
-                rpc_client.clone(),
-                &ws_addr,
-                solana_client::tpu_client::TpuClientConfig { fanout_slots },
-                quic_connection_cache.unwrap(),
-            )
-            .await
-            .unwrap(),
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(!identities.is_empty(), "a tpu identity is required");
+        anyhow::ensure!(connection_pool_size > 0, "the tpu connection pool is empty");
+        info!(
+            "creating {} tpu clients with fanout_slots={} connection_pool_size={}",
+            identities.len(),
+            fanout_slots,
+            connection_pool_size
         );
-
-        Self {
+        let this = Self {
             rpc_client,
-            tpu_client: Arc::new(RwLock::new(tpu_client)),
+            tpu_clients: Arc::new(RwLock::new(vec![])),
+            next_client: Default::default(),
             ws_addr,
             fanout_slots,
             error_count: Default::default(),
-            identity: Arc::new(identity),
+            identities: Arc::new(identities),
+            send_retries,
+            connection_pool_size,
             tx_send_record,
             stats,
-        }
+        };
+        this.reset_tpu_client().await?;
+        Ok(this)
     }
 
-    pub async fn reset_tpu_client(&self) -> anyhow::Result<()> {
-        let identity = Keypair::from_bytes(&self.identity.to_bytes()).unwrap();
+    // the only place building tpu clients, so that a fresh manager and a reset one behave
+    // the same
+    async fn build_tpu_client(&self, identity: &Keypair) -> anyhow::Result<Arc<QuicTpuClient>> {
         let connection_cache = ConnectionCache::new_with_client_options(
             "",
-            4,
+            self.connection_pool_size,
             None,
-            Some((&identity, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))),
+            Some((identity, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))),
             None,
         );
 
         let quic_connection_cache =
             if let ConnectionCache::Quic(connection_cache) = connection_cache {
-                Some(connection_cache)
+                connection_cache
             } else {
-                None
+                anyhow::bail!("connection cache is not using quic");
             };
 
-        let tpu_client = Arc::new(
+        Ok(Arc::new(
             TpuClient::new_with_connection_cache(
                 self.rpc_client.clone(),
                 &self.ws_addr,
                 solana_client::tpu_client::TpuClientConfig {
                     fanout_slots: self.fanout_slots,
                 },
-                quic_connection_cache.unwrap(),
+                quic_connection_cache,
             )
-            .await
-            .unwrap(),
-        );
+            .await?,
+        ))
+    }
+
+    pub async fn reset_tpu_client(&self) -> anyhow::Result<()> {
+        // on failure the previous clients are kept and the reset is retried later
+        let mut tpu_clients = vec![];
+        for identity in self.identities.iter() {
+            tpu_clients.push(self.build_tpu_client(identity).await?);
+        }
         self.error_count.store(0, Ordering::Relaxed);
-        *self.tpu_client.write().await = tpu_client;
+        *self.tpu_clients.write().await = tpu_clients;
         Ok(())
     }
 
@@ -124,30 +137,34 @@ impl TpuManager {
         Ok(())
     }
 
-    pub fn force_reset_after_every(&self, duration: Duration) {
+    /// Rebuilds the clients every `duration`, until the process exits
+    pub fn force_reset_after_every(&self, duration: Duration) -> JoinHandle<()> {
         let this = self.clone();
         tokio::spawn(async move {
-            tokio::time::sleep(duration).await;
-            if let Err(e) = this.reset_tpu_client().await {
-                error!("timely restart of tpu client failed {}", e);
+            loop {
+                tokio::time::sleep(duration).await;
+                if let Err(e) = this.reset_tpu_client().await {
+                    error!("timely restart of tpu client failed {}", e);
+                }
             }
-        });
+        })
     }
 
     async fn get_tpu_client(&self) -> Arc<QuicTpuClient> {
-        self.tpu_client.read().await.clone()
+        let tpu_clients = self.tpu_clients.read().await;
+        let next = self.next_client.fetch_add(1, Ordering::Relaxed);
+        tpu_clients[next % tpu_clients.len()].clone()
     }
 
     pub async fn send_transaction_batch(
         &self,
-        batch: &Vec<(Transaction, TransactionSendRecord)>,
+        batch: &Vec<(VersionedTransaction, TransactionSendRecord)>,
     ) -> bool {
-        let tpu_client = self.get_tpu_client().await;
-
         for (_tx, record) in batch {
             let tx_sent_record = self.tx_send_record.clone();
             let sent = tx_sent_record.send(record.clone());
             if sent.is_err() {
+                self.stats.inc_dropped();
                 warn!(
                     "sending error on channel : {}",
                     sent.err().unwrap().to_string()
@@ -159,22 +176,74 @@ impl TpuManager {
             self.stats.inc_send()
         }
 
-        if tpu_client
-            .try_send_wire_transaction_batch(
-                batch
-                    .iter()
-                    .map(|(tx, _)| serialize(tx).expect("serialization should succeed"))
-                    .collect(),
-            )
-            .await
-            .is_err()
-        {
-            if let Err(e) = self.reset().await {
-                error!("error while reseting tpu client {}", e);
+        self.send_wire_batch(batch.iter().map(|(tx, _)| tx)).await
+    }
+
+    async fn send_wire_batch<'a>(
+        &self,
+        batch: impl Iterator<Item = &'a VersionedTransaction>,
+    ) -> bool {
+        let wire_transactions: Vec<_> = batch
+            .map(|tx| serialize(tx).expect("serialization should succeed"))
+            .collect();
+        // quic stream errors are often transient, send records were emitted once already
+        for attempt in 0..=self.send_retries {
+            if attempt > 0 {
+                tokio::time::sleep(SEND_RETRY_DELAY).await;
             }
-            false
-        } else {
-            true
+            let tpu_client = self.get_tpu_client().await;
+            match tpu_client
+                .try_send_wire_transaction_batch(wire_transactions.clone())
+                .await
+            {
+                Ok(()) => return true,
+                Err(e) => debug!("tpu batch send attempt {} failed {}", attempt + 1, e),
+            }
+        }
+        if let Err(e) = self.reset().await {
+            error!("error while reseting tpu client {}", e);
         }
+        false
+    }
+}
+
+#[async_trait]
+impl TransactionResender for TpuManager {
+    async fn resend_transactions(&self, transactions: Vec<VersionedTransaction>) {
+        self.send_wire_batch(transactions.iter()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::HealthStatus;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const LOCAL_RPC_URL: &str = "http://127.0.0.1:8899";
+    const LOCAL_WS_URL: &str = "ws://127.0.0.1:8900";
+
+    #[tokio::test]
+    #[ignore = "needs a local validator"]
+    async fn builds_tpu_client_against_local_validator() {
+        let rpc_client = Arc::new(RpcClient::new(LOCAL_RPC_URL.to_string()));
+        let (tx_send_record, _rx_send_record) = unbounded_channel();
+        let stats = CrankStats::new(None, None, HealthStatus::default(), Duration::ZERO, None);
+        let manager = TpuManager::new(
+            rpc_client,
+            LOCAL_WS_URL.to_string(),
+            12,
+            vec![Keypair::new(), Keypair::new()],
+            0,
+            4,
+            tx_send_record,
+            stats,
+        )
+        .await
+        .unwrap();
+        assert_eq!(manager.tpu_clients.read().await.len(), 2);
+
+        let identity = Keypair::new();
+        manager.build_tpu_client(&identity).await.unwrap();
     }
 }